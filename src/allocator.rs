@@ -0,0 +1,420 @@
+// The heap the VM's heap instructions work on. Different allocators can be plugged in through the Allocator
+// trait, so their fragmentation can be compared on the same program. The machine config picks one:
+//   first-fit  yoloheap::Heap, boundary tags and immediate coalescing.
+//   buddy      Power of two blocks split in halves, see buddy.rs.
+//   slab       Fixed size slots, see slab.rs.
+//
+// Every allocator keeps its blocks, bookkeeping included, in the bytes returned by memory().
+// That is what gets traced, snapshotted and hashed, so they don't have to know which allocator it is.
+// The little that is kept outside of it, like the peak, is saved and restored through state() and set_state().
+use std::fmt;
+
+use crate::buddy::Buddy;
+use crate::slab::Slab;
+use crate::yoloheap::{BlockInfo, HeaderWidth, Heap};
+use crate::yoloheap::constants::MAX_BLOCK_SIZE;
+
+// Size of the heap for every allocator. Pointers live in 8 bit registers, so nothing above 255 can be reached.
+pub const FIRST_FIT_SIZE: usize = MAX_BLOCK_SIZE as usize;
+pub const BUDDY_SIZE: usize = 256;
+pub const SLAB_SIZE: usize = 256;
+pub const DEFAULT_SLOT_SIZE: usize = 16;
+const MAP_WIDTH: usize = 32;  // Bytes per row of the heap map.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AllocatorKind {
+    #[default]
+    FirstFit,
+    Buddy,
+    Slab(usize),  // Slot size.
+}
+
+impl AllocatorKind {
+    // The kind and the slot size, for snapshots.
+    pub fn to_bytes(self) -> [u8; 2] {
+        match self {
+            AllocatorKind::FirstFit   => [0, 0],
+            AllocatorKind::Buddy      => [1, 0],
+            AllocatorKind::Slab(slot) => [2, slot as u8],
+        }
+    }
+
+    pub fn from_bytes(bytes: [u8; 2]) -> Option<Self> {
+        match bytes {
+            [0, _]    => Some(AllocatorKind::FirstFit),
+            [1, _]    => Some(AllocatorKind::Buddy),
+            [2, slot] => Some(AllocatorKind::Slab(slot as usize)),
+            _         => None,
+        }
+    }
+}
+
+impl fmt::Display for AllocatorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AllocatorKind::FirstFit   => write!(f, "first-fit"),
+            AllocatorKind::Buddy      => write!(f, "buddy"),
+            AllocatorKind::Slab(slot) => write!(f, "slab ({} byte slots)", slot),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct AllocStats {
+    pub size:             usize,  // Bytes the allocator manages.
+    pub allocated_blocks: usize,
+    pub free_blocks:      usize,
+    pub allocated_bytes:  usize,  // Bytes in allocated blocks, headers included.
+    pub free_bytes:       usize,
+    pub largest_free:     usize,  // The largest block that could still be handed out.
+    pub peak_bytes:       usize,  // The most allocated_bytes there have been at once.
+}
+
+impl AllocStats {
+    pub fn from_blocks(blocks: &[BlockInfo], size: usize, peak_bytes: usize) -> Self {
+        let mut stats = AllocStats { size, peak_bytes, ..AllocStats::default() };
+        for b in blocks {
+            if b.allocated {
+                stats.allocated_blocks += 1;
+                stats.allocated_bytes += b.size;
+            } else {
+                stats.free_blocks += 1;
+                stats.free_bytes += b.size;
+                stats.largest_free = stats.largest_free.max(b.size);
+            }
+        }
+        stats
+    }
+
+    // External fragmentation in percent: how much of the free space is not in the largest free block.
+    pub fn fragmentation(&self) -> usize {
+        if self.free_bytes == 0 {
+            return 0;
+        }
+        100 - self.largest_free * 100 / self.free_bytes
+    }
+}
+
+impl fmt::Display for AllocStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} bytes: {} allocated blocks ({} bytes), {} free blocks ({} bytes), largest free {}, fragmentation {}%, peak {} bytes",
+            self.size, self.allocated_blocks, self.allocated_bytes, self.free_blocks, self.free_bytes,
+            self.largest_free, self.fragmentation(), self.peak_bytes)
+    }
+}
+
+// Bytes in allocated blocks, for keeping track of the peak.
+pub fn allocated_bytes(blocks: &[BlockInfo]) -> usize {
+    blocks.iter().filter(|b| b.allocated).map(|b| b.size).sum()
+}
+
+pub trait Allocator {
+    fn kind(&self) -> AllocatorKind;
+    // Returns the pointer to at least 'size' bytes, None if there is no room.
+    fn allocate(&mut self, size: usize) -> Option<usize>;
+    // 'ptr' has to be a pointer allocate returned that was not freed yet.
+    fn free(&mut self, ptr: usize) -> Result<(), String>;
+    // Both take an address anywhere in an allocated block, the bytes must not go past the end of it.
+    fn write_bytes(&mut self, addr: usize, data: &[u8]) -> Result<(), String>;
+    fn read_bytes(&self, addr: usize, len: usize) -> Result<Vec<u8>, String>;
+    // Every block from the bottom of the heap up.
+    fn blocks(&self) -> Vec<BlockInfo>;
+    // Bytes of bookkeeping in front of each pointer, and at the end of each block.
+    fn header_size(&self) -> usize;
+    fn footer_size(&self) -> usize {
+        0
+    }
+    // The most bytes there have been in allocated blocks at once.
+    fn peak(&self) -> usize;
+    fn set_peak(&mut self, peak: usize);
+    // The pointer allocate returned for 'block'.
+    fn pointer_of(&self, block: &BlockInfo) -> usize {
+        block.offset + self.header_size()
+    }
+    fn memory(&self) -> &[u8];
+    fn memory_mut(&mut self) -> &mut [u8];
+
+    // Everything that is wrong with the bookkeeping, one line each. Allocators that can't check themselves say nothing.
+    fn check(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    // Called with the pc of every instruction before it runs, for the sanitizer to say where blocks came from.
+    fn set_pc(&mut self, _pc: u8) {}
+
+    // Blocks that were never freed, one line each, for allocators that keep track of them.
+    fn leaks(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    // Whether this is the sanitizer, whose pointers are off the blocks by its red zone.
+    fn is_sanitized(&self) -> bool {
+        false
+    }
+
+    // Resizes the block 'ptr' points to, keeping its contents. None if there is no room, the old block is kept then.
+    // Always moves the block, allocators that can resize in place do that instead.
+    fn realloc(&mut self, ptr: usize, size: usize) -> Result<Option<usize>, String> {
+        let header = self.header_size();
+        let old = self.blocks()
+            .into_iter()
+            .find(|b| b.allocated && b.offset + header == ptr)
+            .ok_or_else(|| format!("Error: {} is not an allocated block.", ptr))?;
+        let data = self.read_bytes(ptr, (old.size - header).min(size))?;
+        let new_ptr = match self.allocate(size) {
+            Some(p) => p,
+            None    => return Ok(None),
+        };
+        self.write_bytes(new_ptr, &data)?;
+        self.free(ptr)?;
+        Ok(Some(new_ptr))
+    }
+
+    fn stats(&self) -> AllocStats {
+        AllocStats::from_blocks(&self.blocks(), self.memory().len(), self.peak())
+    }
+
+    // Everything the allocator keeps outside of memory(), for the trace and snapshots. Usually only the peak.
+    fn state(&self) -> Vec<u8> {
+        (self.peak() as u16).to_le_bytes().to_vec()
+    }
+
+    fn set_state(&mut self, state: &[u8]) -> Result<(), String> {
+        match state {
+            [lo, hi] => {
+                self.set_peak(u16::from_le_bytes([*lo, *hi]) as usize);
+                Ok(())
+            }
+            _ => Err(String::from("Error: Invalid allocator state.")),
+        }
+    }
+}
+
+// Draws the heap one character per byte, MAP_WIDTH bytes a row, followed by the blocks it is made of.
+// Runs of blocks of the same size and state are listed once, the slab allocator has a lot of them.
+pub fn render_map(heap: &dyn Allocator) -> String {
+    let blocks = heap.blocks();
+    let mut map = vec!['?'; heap.memory().len()];
+    for b in &blocks {
+        for (j, c) in map[b.offset..b.offset + b.size].iter_mut().enumerate() {
+            *c = if j < heap.header_size() || j >= b.size - heap.footer_size() {
+                ':'
+            } else if b.allocated {
+                '#'
+            } else {
+                '.'
+            };
+        }
+    }
+
+    let mut out = format!("{} heap, {}\n", heap.kind(), heap.stats());
+    for (row, chunk) in map.chunks(MAP_WIDTH).enumerate() {
+        out += &format!("{:#06x} {}\n", row * MAP_WIDTH, chunk.iter().collect::<String>());
+    }
+    let mut i = 0;
+    while i < blocks.len() {
+        let b = &blocks[i];
+        let run = blocks[i..].iter().take_while(|o| o.size == b.size && o.allocated == b.allocated).count();
+        out += &format!("{:#06x}..{:#06x} {} x {} bytes {}\n", b.offset, b.offset + run * b.size, run, b.size,
+            if b.allocated { "allocated" } else { "free" });
+        i += run;
+    }
+    out += "# allocated, . free, : header/footer, ? in no block\n";
+    out
+}
+
+// The allocated block whose payload, the bytes after its 'header', holds all of 'addr..addr + len'.
+pub fn block_around(blocks: &[BlockInfo], header: usize, addr: usize, len: usize) -> Result<&BlockInfo, String> {
+    blocks.iter()
+        .find(|b| b.allocated && addr >= b.offset + header && addr < b.offset + b.size)
+        .filter(|b| addr + len <= b.offset + b.size)
+        .ok_or_else(|| format!("Error: {}..{} is not inside an allocated block.", addr, addr + len))
+}
+
+pub fn create(kind: AllocatorKind, header: HeaderWidth) -> Box<dyn Allocator> {
+    match kind {
+        AllocatorKind::FirstFit   => Box::new(Heap::with_header(FIRST_FIT_SIZE, header)),
+        AllocatorKind::Buddy      => Box::new(Buddy::new(BUDDY_SIZE)),
+        AllocatorKind::Slab(slot) => Box::new(Slab::new(SLAB_SIZE, slot)),
+    }
+}
+
+impl Allocator for Heap {
+    fn kind(&self) -> AllocatorKind {
+        AllocatorKind::FirstFit
+    }
+
+    fn allocate(&mut self, size: usize) -> Option<usize> {
+        Heap::allocate(self, size)
+    }
+
+    fn free(&mut self, ptr: usize) -> Result<(), String> {
+        // Heap::free trusts the header in front of ptr, make sure it is one.
+        if !self.block_list().iter().any(|b| b.allocated && b.offset + self.header_size() == ptr) {
+            return Err(format!("Error: {} is not an allocated block.", ptr));
+        }
+        Heap::free(self, ptr).map_err(String::from)
+    }
+
+    fn realloc(&mut self, ptr: usize, size: usize) -> Result<Option<usize>, String> {
+        if !self.block_list().iter().any(|b| b.allocated && b.offset + self.header_size() == ptr) {
+            return Err(format!("Error: {} is not an allocated block.", ptr));
+        }
+        Heap::realloc(self, ptr, size).map_err(String::from)
+    }
+
+    fn write_bytes(&mut self, addr: usize, data: &[u8]) -> Result<(), String> {
+        // The footer is not part of the payload.
+        let footer = self.header_size();
+        block_around(&self.block_list(), self.header_size(), addr, data.len() + footer)?;
+        self.heap[addr..addr + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read_bytes(&self, addr: usize, len: usize) -> Result<Vec<u8>, String> {
+        block_around(&self.block_list(), self.header_size(), addr, len + self.header_size())?;
+        Ok(self.heap[addr..addr + len].to_vec())
+    }
+
+    fn blocks(&self) -> Vec<BlockInfo> {
+        self.block_list()
+    }
+
+    fn check(&self) -> Vec<String> {
+        Heap::check(self).iter().map(|v| v.to_string()).collect()
+    }
+
+    fn header_size(&self) -> usize {
+        Heap::header_size(self)
+    }
+
+    fn footer_size(&self) -> usize {
+        Heap::header_size(self)
+    }
+
+    fn peak(&self) -> usize {
+        self.peak
+    }
+
+    fn set_peak(&mut self, peak: usize) {
+        self.peak = peak;
+    }
+
+    fn stats(&self) -> AllocStats {
+        Heap::stats(self)
+    }
+
+    fn memory(&self) -> &[u8] {
+        &self.heap
+    }
+
+    fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.heap
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_source;
+    use crate::config::MachineConfig;
+    use crate::cpu::cpu_state::Machine;
+
+    // Allocates three 6 byte blocks, frees the middle one and asks for 12 bytes.
+    const PROGRAM: &str = "
+_START:
+    ADDI r1 6
+    ALC r2 r1
+    ALC r3 r1
+    ALC r4 r1
+    FREE r3
+    ADDI r5 12
+    ALC r6 r5
+    HLT
+";
+
+    fn run(allocator: &str) -> Machine {
+        let config = MachineConfig::parse(&format!("allocator {}\n", allocator)).unwrap();
+        let mut m = Machine::from_config(assemble_source(PROGRAM).mem, config).unwrap();
+        m.state.verbose = false;
+        while m.state.running {
+            m.step().unwrap();
+        }
+        m
+    }
+
+    #[test]
+    fn test_every_allocator_runs_the_heap_instructions() {
+        for name in ["first-fit", "buddy", "slab 16"] {
+            let m = run(name);
+            let r = m.state.registers;
+            assert!(r[2] != 0 && r[4] != 0 && r[6] != 0, "{}", name);
+            assert_eq!(m.state.heap.stats().allocated_blocks, 3, "{}", name);
+        }
+        assert_eq!(run("slab 8").state.registers[6], 0);
+    }
+
+    #[test]
+    fn test_ralc_keeps_contents() {
+        let src = "_START:\n ADDI r1 4\n ALC r2 r1\n ADDI r3 7\n WRH r2 r3\n ADDI r1 20\n RALC r2 r1\n HLT\n";
+        for name in ["first-fit", "buddy", "slab 32"] {
+            let config = MachineConfig::parse(&format!("allocator {}\n", name)).unwrap();
+            let mut m = Machine::from_config(assemble_source(src).mem, config).unwrap();
+            m.state.verbose = false;
+            while m.state.running {
+                m.step().unwrap();
+            }
+            let p = m.state.registers[2] as usize;
+            assert_eq!(m.state.heap.read_bytes(p, 20).unwrap()[0], 7, "{}", name);
+            assert_eq!(m.state.heap.stats().allocated_blocks, 1, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_stats_and_map() {
+        let mut m = run("first-fit");
+        let stats = m.state.heap.stats();
+        assert_eq!((stats.allocated_blocks, stats.allocated_bytes, stats.free_blocks), (3, 32, 2));
+        assert_eq!(stats.peak_bytes, 32);
+
+        let map = render_map(m.state.heap.as_ref());
+        let lines: Vec<&str> = map.lines().collect();
+        assert!(lines[0].starts_with("first-fit heap, 252 bytes: 3 allocated blocks (32 bytes)"), "{}", lines[0]);
+        assert_eq!(lines[1], "0x0000 :######::......::######::#######");
+        assert_eq!(lines[9], "0x0000..0x0008 1 x 8 bytes allocated");
+        assert_eq!(lines[10], "0x0008..0x0010 1 x 8 bytes free");
+
+        // Freeing doesn't lower the peak.
+        let p = m.state.registers[6] as usize;
+        m.state.heap.free(p).unwrap();
+        assert_eq!(m.state.heap.stats().allocated_bytes, 16);
+        assert_eq!(m.state.heap.stats().peak_bytes, 32);
+
+        // The bitmap of the slab is in no block.
+        let map = render_map(run("slab 8").state.heap.as_ref());
+        assert!(map.lines().nth(1).unwrap().starts_with("0x0000 ????????########........########"), "{}", map);
+    }
+
+    #[test]
+    fn test_fragmentation() {
+        let stats = AllocStats { free_bytes: 40, largest_free: 10, ..AllocStats::default() };
+        assert_eq!(stats.fragmentation(), 75);
+        assert_eq!(AllocStats::default().fragmentation(), 0);
+    }
+
+    #[test]
+    fn test_read_and_write_bounds() {
+        for mut a in [create(AllocatorKind::FirstFit, HeaderWidth::Narrow), create(AllocatorKind::Buddy, HeaderWidth::Narrow),
+                      create(AllocatorKind::Slab(8), HeaderWidth::Narrow)] {
+            let p = a.allocate(6).unwrap();
+            a.write_bytes(p + 1, &[1, 2, 3]).unwrap();
+            assert_eq!(a.read_bytes(p, 4).unwrap(), vec![0, 1, 2, 3]);
+            assert!(a.write_bytes(p, &[0; 64]).is_err());
+            assert!(a.read_bytes(p + 200, 1).is_err());
+            a.free(p).unwrap();
+            assert!(a.read_bytes(p, 1).is_err());
+            assert!(a.free(p).is_err());
+            assert_eq!(a.stats().allocated_blocks, 0);
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use std::fs;
-use crate::instruction_mapping::instruction_utils::{self, get_upcodes, map_register_to_value};
+use crate::instruction_mapping::instruction_utils::{self, get_funct, get_upcodes, map_register_to_value, HLT_WITH_STATUS};
 use crate::memory;
 use crate::parser;
 use crate::symtab::{SymTab, Function};
@@ -48,19 +48,27 @@ pub fn print_vec(v: &[String]) {
 }
 
 // Reads a programfile and puts the instructions in a vector.
-fn read_and_parse_programfile(file: &str) -> Vec<String> {
+fn read_and_parse_programfile(file: &str) -> (Vec<String>, Vec<usize>) {
     let fc = fs::read_to_string(file)
                 .expect("Could not open file :(");
     
+    parse_program_source(&fc)
+}
+
+// Puts the instructions of a program source in a vector.
+// The second vector holds the source line (starting at 1) of every instruction.
+fn parse_program_source(fc: &str) -> (Vec<String>, Vec<usize>) {
     let mut instructions = Vec::<String>::new();
+    let mut lines = Vec::<usize>::new();
 
-    for instr in fc.lines() {
+    for (line, instr) in fc.lines().enumerate() {
 
         let code = instr.split(';').next().unwrap_or("").trim();
 
         // Trim whitespace and check if the line is empty
         if !code.trim().is_empty() {
             instructions.push(String::from(code));
+            lines.push(line + 1);
         }
     }
 
@@ -69,7 +77,7 @@ fn read_and_parse_programfile(file: &str) -> Vec<String> {
         panic!("Error: Program has no entry point.");
     }
 
-    instructions
+    (instructions, lines)
 }
 
 // HELPER FUNCTION - DO NOT USE!
@@ -102,7 +110,7 @@ pub fn tokenize_instructions(insts: Vec<String>) -> Result<Vec<InstructionTokeni
 }
 
 // Takes a instruction token and returns a tuple of the first and second byte
-// as u8 values.
+// as u8 values. The fields are or'ed together, the parser has made sure each fits in its own bits.
 fn token_to_value(token: &InstructionTokenized, symtab: &mut SymTab) -> Result<(u8, u8), String> {
     let mut res = (0, 0);
     
@@ -112,7 +120,8 @@ fn token_to_value(token: &InstructionTokenized, symtab: &mut SymTab) -> Result<(
             if let Ok(n) = get_upcodes(name) {
                 match n {
                     instruction_utils::InstructionNameMap::Instruction(upcode) => {
-                        res.0 += upcode;
+                        res.0 |= upcode;
+                        res.1 |= get_funct(name);
                     }
                     _ => {
                         return Err(format!("Error: Name '{}' was not an instruction.", name));
@@ -128,14 +137,24 @@ fn token_to_value(token: &InstructionTokenized, symtab: &mut SymTab) -> Result<(
     // Handle the arguments
     match (&token.arg1, &token.arg2) {
         (Some(a1), Some(a2)) => { 
-            res.0 += map_register_to_value(a1);
-            res.1 += map_register_to_value(a2);
+            res.0 |= map_register_to_value(a1);
+            res.1 |= map_register_to_value(a2);
         },
         (Some(a1), None) => {
             match token.name.as_ref() {
+                Some(name) if name == "HLT" => {
+                    res.0 |= map_register_to_value(a1);
+                    res.1 |= HLT_WITH_STATUS;
+                }
+                Some(name) if name == "SYS" => {
+                    // The service number is split, high nibble in arg1 and low nibble next to the funct.
+                    let n = map_register_to_value(a1);
+                    res.0 |= n >> 4;
+                    res.1 |= n & 0xf;
+                }
                 Some(name) if name == "CALL" => { 
                     match symtab.symtab_lookup(a1) {
-                        Ok(f) => res.1 += f,
+                        Ok(f) => res.1 |= f,
                         Err(_) => {
                             symtab.print_symtab();
                             return Err(format!("Error: Symbol '{}' not found in symtab.", a1));
@@ -143,7 +162,7 @@ fn token_to_value(token: &InstructionTokenized, symtab: &mut SymTab) -> Result<(
                     }
                 }
                 _ => {
-                    res.0 += map_register_to_value(a1);
+                    res.0 |= map_register_to_value(a1);
                 }
             }
         },
@@ -204,13 +223,43 @@ fn write_tokens_to_mem(insts: Vec<InstructionTokenized>, symtab: &mut SymTab) ->
 }
 
 
-pub fn init_program_in_memory(file: &str) -> Vec<u8>{
+// An assembled program, the memory image together with the symtab it was built with.
+// lines[i] is the source line of the instruction at address 2 * i.
+pub struct Program {
+    pub mem:    Vec<u8>,
+    pub symtab: SymTab,
+    pub lines:  Vec<usize>,
+}
+
+impl Program {
+    // Returns the source line of the instruction at 'addr'.
+    pub fn line_of_address(&self, addr: u8) -> Option<usize> {
+        self.lines.get(addr as usize / 2).copied()
+    }
+
+    // Returns the address of the first instruction at or after 'line'.
+    // Breakpoints on labels, comments or empty lines end up on the next instruction.
+    pub fn address_of_line(&self, line: usize) -> Option<u8> {
+        self.lines
+            .iter()
+            .position(|l| *l >= line)
+            .map(|i| (i * 2) as u8)
+    }
+}
+
+fn assemble((parsed_prg, source_lines): (Vec<String>, Vec<usize>)) -> Program {
     let mut symtab = SymTab::new(); 
-    let parsed_prg = read_and_parse_programfile(file);
+
+    // Labels take no space in memory, so they get no line.
+    let lines = parsed_prg.iter()
+        .zip(source_lines)
+        .filter(|(code, _)| !code.split_whitespace().next().unwrap_or("").ends_with(':'))
+        .map(|(_, line)| line)
+        .collect();
     let tokens = tokenize_instructions(parsed_prg);
 
     //Checking if program is valid.
-    match tokens {
+    let mem = match tokens {
         Ok(t) => {
             match write_tokens_to_mem(t, &mut symtab) {
                 Ok(mem) => mem,
@@ -218,9 +267,18 @@ pub fn init_program_in_memory(file: &str) -> Vec<u8>{
             }
         },
         Err(e) => { panic!("{e}") }
-    }
-}
-
+    };
 
+    Program { mem, symtab, lines }
+}
 
+// Assembles a programfile and keeps the symtab, used by the debugger.
+pub fn assemble_program(file: &str) -> Program {
+    assemble(read_and_parse_programfile(file))
+}
 
+// Assembles a program given as source text instead of a file.
+#[allow(dead_code)]
+pub fn assemble_source(src: &str) -> Program {
+    assemble(parse_program_source(src))
+}
@@ -0,0 +1,239 @@
+// Audio device, five ports:
+//   +0 FREQ_LO   Low byte of the tone frequency in Hz.
+//   +1 FREQ_HI   High byte of the tone frequency.
+//   +2 VOLUME    Volume of the tone, 0 to 255.
+//   +3 DURATION  Writing starts a square wave tone lasting this many hundredths of a second.
+//   +4 SAMPLE    Writing queues an 8 bit unsigned PCM sample, they are played one after the other.
+//                Reading gives the number of queued samples not yet played, at most 255.
+//
+// Time is counted in instructions, the VM is taken to run one instruction per sample at SAMPLE_RATE.
+// Nothing is played while running, tones and samples are mixed into a WAV file when the run is over,
+// so the same program always gives the same file.
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use crate::bus::Device;
+
+pub const SAMPLE_RATE: u32 = 8000;
+const SAMPLES_PER_DURATION: u64 = SAMPLE_RATE as u64 / 100;
+const SILENCE: u8 = 128;
+
+const FREQ_LO_PORT: usize = 0;
+const FREQ_HI_PORT: usize = 1;
+const VOLUME_PORT: usize = 2;
+const DURATION_PORT: usize = 3;
+const SAMPLE_PORT: usize = 4;
+
+struct Tone {
+    start:  u64,  // Sample the tone starts at.
+    len:    u64,
+    freq:   u16,
+    volume: u8,
+}
+
+pub struct Audio {
+    path:     String,
+    freq:     u16,
+    volume:   u8,
+    duration: u8,
+    tones:    Vec<Tone>,
+    stream:   Vec<(u64, u8)>,  // Streamed samples and when they play.
+    next:     u64,             // When the next streamed sample plays.
+    now:      u64,             // Instructions executed, which is also the current sample.
+}
+
+// A mono 8 bit PCM WAV file holding 'samples'.
+pub fn to_wav(samples: &[u8]) -> Vec<u8> {
+    let len = samples.len() as u32;
+    let mut out = Vec::with_capacity(44 + samples.len());
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(36 + len).to_le_bytes());
+    out.extend_from_slice(b"WAVEfmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());          // Size of the fmt chunk.
+    out.extend_from_slice(&1u16.to_le_bytes());           // PCM.
+    out.extend_from_slice(&1u16.to_le_bytes());           // Channels.
+    out.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    out.extend_from_slice(&SAMPLE_RATE.to_le_bytes());    // Bytes per second.
+    out.extend_from_slice(&1u16.to_le_bytes());           // Bytes per frame.
+    out.extend_from_slice(&8u16.to_le_bytes());           // Bits per sample.
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&len.to_le_bytes());
+    out.extend_from_slice(samples);
+    out
+}
+
+impl Audio {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path:     path.to_string(),
+            freq:     0,
+            volume:   0,
+            duration: 0,
+            tones:    Vec::new(),
+            stream:   Vec::new(),
+            next:     0,
+            now:      0,
+        }
+    }
+
+    // Mixes the tones and the streamed samples, centred around SILENCE and clipped.
+    pub fn render(&self) -> Vec<u8> {
+        let tones_end = self.tones.iter().map(|t| t.start + t.len).max().unwrap_or(0);
+        let mut mix = vec![0i32; tones_end.max(self.next) as usize];
+        for t in &self.tones {
+            let amplitude = t.volume as i32 / 2;
+            for i in 0..t.len {
+                // Two half periods per cycle, high in the first one.
+                let half = i * t.freq as u64 * 2 / SAMPLE_RATE as u64;
+                mix[(t.start + i) as usize] += if half.is_multiple_of(2) { amplitude } else { -amplitude };
+            }
+        }
+        for (at, s) in &self.stream {
+            mix[*at as usize] += *s as i32 - SILENCE as i32;
+        }
+        mix.iter().map(|v| (v + SILENCE as i32).clamp(0, 255) as u8).collect()
+    }
+}
+
+impl Device for Audio {
+    fn name(&self) -> &'static str {
+        "audio"
+    }
+
+    fn size(&self) -> usize {
+        5
+    }
+
+    fn read(&mut self, offset: usize) -> u8 {
+        let [lo, hi] = self.freq.to_le_bytes();
+        match offset {
+            FREQ_LO_PORT  => lo,
+            FREQ_HI_PORT  => hi,
+            VOLUME_PORT   => self.volume,
+            DURATION_PORT => self.duration,
+            SAMPLE_PORT   => self.next.saturating_sub(self.now).min(255) as u8,
+            _             => 0,
+        }
+    }
+
+    fn write(&mut self, offset: usize, val: u8) {
+        match offset {
+            FREQ_LO_PORT => self.freq = self.freq & 0xFF00 | val as u16,
+            FREQ_HI_PORT => self.freq = self.freq & 0x00FF | (val as u16) << 8,
+            VOLUME_PORT  => self.volume = val,
+            DURATION_PORT => {
+                self.duration = val;
+                self.tones.push(Tone {
+                    start:  self.now,
+                    len:    val as u64 * SAMPLES_PER_DURATION,
+                    freq:   self.freq,
+                    volume: self.volume,
+                });
+            }
+            SAMPLE_PORT => {
+                // A sample written after the queue ran dry plays right away.
+                self.next = self.next.max(self.now);
+                self.stream.push((self.next, val));
+                self.next += 1;
+            }
+            _ => (),
+        }
+    }
+
+    fn tick(&mut self) -> bool {
+        self.now += 1;
+        false
+    }
+
+    fn finish(&mut self) {
+        let res = File::create(&self.path).and_then(|f| {
+            let mut w = BufWriter::new(f);
+            w.write_all(&to_wav(&self.render()))?;
+            w.flush()
+        });
+        if let Err(e) = res {
+            eprintln!("Error: Writing audio to {}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_source;
+    use crate::bus::Mapping;
+    use crate::config::MachineConfig;
+    use crate::cpu::cpu_state::Machine;
+
+    #[test]
+    fn test_tone() {
+        // 2000 Hz at 8000 samples per second is two samples high, two low.
+        let mut a = Audio::new("");
+        a.write(FREQ_LO_PORT, 0xD0);
+        a.write(FREQ_HI_PORT, 0x07);
+        a.write(VOLUME_PORT, 200);
+        a.write(DURATION_PORT, 1);
+        let out = a.render();
+        assert_eq!(out.len(), SAMPLES_PER_DURATION as usize);
+        assert_eq!(&out[..6], &[228, 228, 28, 28, 228, 228]);
+    }
+
+    #[test]
+    fn test_sample_queue() {
+        let mut a = Audio::new("");
+        a.tick();
+        a.write(SAMPLE_PORT, 10);
+        a.write(SAMPLE_PORT, 20);
+        a.write(SAMPLE_PORT, 30);
+        assert_eq!(a.read(SAMPLE_PORT), 3);
+        a.tick();
+        assert_eq!(a.read(SAMPLE_PORT), 2);
+        (0..5).for_each(|_| { a.tick(); });
+        a.write(SAMPLE_PORT, 40);
+        assert_eq!(a.render(), vec![128, 10, 20, 30, 128, 128, 128, 40]);
+    }
+
+    // Streams a sample, then starts a 10 ms tone of 1000 Hz at full volume.
+    const PROGRAM: &str = "
+_START:
+    ADDI r1 240
+    ADDI r2 200
+    ADDI r3 244
+    ST r3 r2
+    LDI r2 232
+    ST r1 r2
+    ADDI r1 1
+    LDI r2 3
+    ST r1 r2
+    ADDI r1 1
+    LDI r2 255
+    ST r1 r2
+    ADDI r1 1
+    LDI r2 1
+    ST r1 r2
+    HLT
+";
+
+    #[test]
+    fn test_wav_file() {
+        let path = std::env::temp_dir().join("vm8bit_audio_test.wav");
+        let path = path.to_str().unwrap();
+        let mut config = MachineConfig::default();
+        config.devices.push(Mapping::new(240, Box::new(Audio::new(path))));
+        let mut m = Machine::from_config(assemble_source(PROGRAM).mem, config).unwrap();
+        m.state.verbose = false;
+        while m.state.running {
+            m.step().unwrap();
+        }
+        m.finish();
+
+        let wav = std::fs::read(path).unwrap();
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(wav[24..28].try_into().unwrap()), SAMPLE_RATE);
+        let data = &wav[44..];
+        // The sample plays at 3, after the three instructions before the store. The tone starts at 14.
+        assert_eq!(data.len(), 14 + SAMPLES_PER_DURATION as usize);
+        assert_eq!(data[3], 200);
+        assert_eq!((data[2], data[4]), (SILENCE, SILENCE));
+        assert_eq!(&data[14..19], &[255, 255, 255, 255, 1]);
+    }
+}
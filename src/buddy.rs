@@ -0,0 +1,173 @@
+// Buddy allocator. The heap is a power of two bytes and every block is a power of two bytes, aligned to its size.
+// Allocating splits the smallest free block that fits in halves until it is just large enough, freeing merges
+// a block with its buddy, the other half it was split from, for as long as that one is free too.
+//
+// Each block starts with a 1 byte header: ALLOCATED_FLAG and the order, the block is 1 << order bytes.
+// Stale headers inside merged blocks are just payload, the heap is only ever walked from block to block.
+use crate::allocator::{allocated_bytes, block_around, Allocator, AllocatorKind};
+use crate::yoloheap::BlockInfo;
+
+const HEADER_SIZE: usize = 1;
+const MIN_ORDER: u8 = 2;
+const ALLOCATED_FLAG: u8 = 0x80;
+const ORDER_MASK: u8 = 0x1F;
+
+pub struct Buddy {
+    heap:      Vec<u8>,
+    max_order: u8,
+    peak:      usize,
+}
+
+// Smallest order with a block of at least 'size' bytes.
+fn order_for(size: usize) -> u8 {
+    (size.next_power_of_two().trailing_zeros() as u8).max(MIN_ORDER)
+}
+
+impl Buddy {
+    // Asserts that 'size' is a power of two the headers can describe.
+    pub fn new(size: usize) -> Self {
+        assert!(size.is_power_of_two() && (1 << MIN_ORDER..=1 << ORDER_MASK).contains(&size));
+        let max_order = size.trailing_zeros() as u8;
+        let mut heap = vec![0; size];
+        heap[0] = max_order;
+        Self { heap, max_order, peak: 0 }
+    }
+
+    fn order(&self, i: usize) -> u8 {
+        self.heap[i] & ORDER_MASK
+    }
+
+    fn is_allocated(&self, i: usize) -> bool {
+        self.heap[i] & ALLOCATED_FLAG != 0
+    }
+}
+
+impl Allocator for Buddy {
+    fn kind(&self) -> AllocatorKind {
+        AllocatorKind::Buddy
+    }
+
+    fn allocate(&mut self, size: usize) -> Option<usize> {
+        let order = order_for(size + HEADER_SIZE);
+
+        // The smallest free block that fits, the lowest one if there are several.
+        let best = self.blocks()
+            .into_iter()
+            .filter(|b| !b.allocated && b.size >= 1 << order)
+            .min_by_key(|b| b.size)?;
+
+        let i = best.offset;
+        let mut current = self.order(i);
+        while current > order {
+            current -= 1;
+            self.heap[i + (1 << current)] = current;
+        }
+        self.heap[i] = ALLOCATED_FLAG | order;
+        self.peak = self.peak.max(allocated_bytes(&self.blocks()));
+        Some(i + HEADER_SIZE)
+    }
+
+    fn free(&mut self, ptr: usize) -> Result<(), String> {
+        let mut i = ptr.wrapping_sub(HEADER_SIZE);
+        if !self.blocks().iter().any(|b| b.allocated && b.offset == i) {
+            return Err(format!("Error: {} is not an allocated block.", ptr));
+        }
+        let mut order = self.order(i);
+        while order < self.max_order {
+            let buddy = i ^ (1 << order);
+            if self.is_allocated(buddy) || self.order(buddy) != order {
+                break;
+            }
+            i = i.min(buddy);
+            order += 1;
+        }
+        self.heap[i] = order;
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, addr: usize, data: &[u8]) -> Result<(), String> {
+        block_around(&self.blocks(), HEADER_SIZE, addr, data.len())?;
+        self.heap[addr..addr + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read_bytes(&self, addr: usize, len: usize) -> Result<Vec<u8>, String> {
+        block_around(&self.blocks(), HEADER_SIZE, addr, len)?;
+        Ok(self.heap[addr..addr + len].to_vec())
+    }
+
+    // Stops at a corrupt header, one with an order that doesn't fit, so it never loops forever.
+    fn blocks(&self) -> Vec<BlockInfo> {
+        let mut blocks = Vec::new();
+        let mut i = 0;
+        while i < self.heap.len() {
+            let order = self.order(i);
+            if !(MIN_ORDER..=self.max_order).contains(&order) || i + (1 << order) > self.heap.len() {
+                break;
+            }
+            blocks.push(BlockInfo { offset: i, size: 1 << order, allocated: self.is_allocated(i) });
+            i += 1 << order;
+        }
+        blocks
+    }
+
+    fn header_size(&self) -> usize {
+        HEADER_SIZE
+    }
+
+    fn peak(&self) -> usize {
+        self.peak
+    }
+
+    fn set_peak(&mut self, peak: usize) {
+        self.peak = peak;
+    }
+
+    fn memory(&self) -> &[u8] {
+        &self.heap
+    }
+
+    fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.heap
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sizes(b: &Buddy) -> Vec<(usize, bool)> {
+        b.blocks().iter().map(|b| (b.size, b.allocated)).collect()
+    }
+
+    #[test]
+    fn test_split_and_merge() {
+        let mut b = Buddy::new(64);
+        let p1 = b.allocate(5).unwrap();
+        assert_eq!(p1, 1);
+        assert_eq!(sizes(&b), vec![(8, true), (8, false), (16, false), (32, false)]);
+
+        let p2 = b.allocate(12).unwrap();
+        assert_eq!(p2, 17);
+        let p3 = b.allocate(5).unwrap();
+        assert_eq!(p3, 9);
+        assert!(b.allocate(40).is_none());
+
+        b.free(p1).unwrap();
+        assert_eq!(sizes(&b), vec![(8, false), (8, true), (16, true), (32, false)]);
+        b.free(p3).unwrap();
+        b.free(p2).unwrap();
+        assert_eq!(sizes(&b), vec![(64, false)]);
+        assert_eq!(b.allocate(63), Some(1));
+    }
+
+    #[test]
+    fn test_free_checks_pointer() {
+        let mut b = Buddy::new(32);
+        let p = b.allocate(4).unwrap();
+        assert!(b.free(p + 1).is_err());
+        assert!(b.free(0).is_err());
+        b.free(p).unwrap();
+        assert!(b.free(p).is_err());
+    }
+}
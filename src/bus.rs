@@ -0,0 +1,301 @@
+// The memory bus. LD, ST and instruction fetch go through it, and it routes every address
+// to the device mapped there. RAM is a device like any other, it is just always mapped at 0.
+use crate::interrupts::NUM_IRQS;
+use crate::memory::ADDRESS_SPACE;
+use crate::protection::{self, Access, Protection, Region, DATA};
+
+// A transfer between a device and memory. The bus performs it through the mappings,
+// so it reaches whatever is mapped at the addresses, like a DMA controller would.
+pub enum Dma {
+    ToMemory { addr: usize, data: Vec<u8> },
+    FromMemory { addr: usize, len: usize },
+}
+
+pub trait Device {
+    // Used in error messages and when listing the mappings.
+    fn name(&self) -> &'static str;
+    // Number of addresses the device takes up on the bus.
+    fn size(&self) -> usize;
+    // 'offset' is relative to the address the device is mapped at.
+    fn read(&mut self, offset: usize) -> u8;
+    fn write(&mut self, offset: usize, val: u8);
+    // Called once after every executed instruction, returns true to raise the device's interrupt line.
+    fn tick(&mut self) -> bool {
+        false
+    }
+    // Bytes the device has sent to the host, counted against the output limit.
+    fn output_bytes(&self) -> u64 {
+        0
+    }
+    // Called when the run is over, to flush output and write files.
+    fn finish(&mut self) {}
+    // Called before the run with the machine's seed, for devices that make up random numbers.
+    fn seed(&mut self, _seed: u64) {}
+    // Checked after every write to the device, a transfer the write started.
+    fn take_dma(&mut self) -> Option<Dma> {
+        None
+    }
+    // Result of the transfer, the bytes that were read for FromMemory and nothing for ToMemory.
+    fn dma_done(&mut self, _res: Result<Vec<u8>, String>) {}
+    // Devices that are plain memory expose it, so it can be dumped, traced and snapshotted.
+    fn memory(&self) -> Option<&[u8]> {
+        None
+    }
+    fn memory_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+}
+
+pub struct Ram {
+    bytes: Vec<u8>,
+}
+
+impl Ram {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self { bytes }
+    }
+}
+
+impl Device for Ram {
+    fn name(&self) -> &'static str {
+        "ram"
+    }
+
+    fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    fn read(&mut self, offset: usize) -> u8 {
+        self.bytes[offset]
+    }
+
+    fn write(&mut self, offset: usize, val: u8) {
+        self.bytes[offset] = val;
+    }
+
+    fn memory(&self) -> Option<&[u8]> {
+        Some(&self.bytes)
+    }
+
+    fn memory_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.bytes)
+    }
+}
+
+pub struct Mapping {
+    pub base:   usize,
+    pub irq:    Option<u8>,  // Interrupt line the device raises, if it is connected to one.
+    pub device: Box<dyn Device>,
+}
+
+impl Mapping {
+    pub fn new(base: usize, device: Box<dyn Device>) -> Self {
+        Self { base, irq: None, device }
+    }
+
+    fn end(&self) -> usize {
+        self.base + self.device.size()
+    }
+
+    fn contains(&self, addr: usize) -> bool {
+        addr >= self.base && addr < self.end()
+    }
+}
+
+// mappings[0] is always the main RAM, the program is loaded there and pc starts in it.
+pub struct Bus {
+    mappings:       Vec<Mapping>,
+    pub protection: Option<Protection>,  // Every access is allowed without it.
+}
+
+impl Bus {
+    pub fn new(ram: Vec<u8>) -> Self {
+        Self {
+            mappings:   vec![Mapping::new(0, Box::new(Ram::new(ram)))],
+            protection: None,
+        }
+    }
+
+    // Maps a device. The whole device has to fit in the address space
+    // and must not overlap anything that is already mapped.
+    pub fn attach(&mut self, new: Mapping) -> Result<(), String> {
+        if new.device.size() == 0 || new.end() > ADDRESS_SPACE {
+            return Err(format!(
+                "Error: {} at {:#04x} with size {} does not fit in the address space.",
+                new.device.name(), new.base, new.device.size()));
+        }
+        if let Some(irq) = new.irq.filter(|irq| *irq >= NUM_IRQS) {
+            return Err(format!("Error: {} is on interrupt line {}, there are only {}.",
+                new.device.name(), irq, NUM_IRQS));
+        }
+        if let Some(m) = self.mappings.iter().find(|m| new.base < m.end() && m.base < new.end()) {
+            return Err(format!(
+                "Error: {} at {:#04x}..{:#04x} overlaps {} at {:#04x}..{:#04x}.",
+                new.device.name(), new.base, new.end(), m.device.name(), m.base, m.end()));
+        }
+        self.mappings.push(new);
+        Ok(())
+    }
+
+    fn mapping_index(&self, addr: usize) -> Result<usize, String> {
+        self.mappings
+            .iter()
+            .position(|m| m.contains(addr))
+            .ok_or_else(|| format!("Error: Bus error, nothing is mapped at {:#04x}.", addr))
+    }
+
+    fn mapping_at(&mut self, addr: usize) -> Result<&mut Mapping, String> {
+        let i = self.mapping_index(addr)?;
+        Ok(&mut self.mappings[i])
+    }
+
+    // The protection region 'addr' is in. None when protection is off or nothing is mapped there.
+    pub fn region(&self, addr: usize) -> Option<Region> {
+        let p = self.protection?;
+        let i = self.mapping_index(addr).ok()?;
+        let m = &self.mappings[i];
+        let region = match i {
+            0 if addr < p.code_end => Region { name: "code", start: 0, end: p.code_end, perms: p.code_perms() },
+            0                      => Region { name: "data", start: p.code_end, end: m.end(), perms: DATA },
+            _                      => Region { name: m.device.name(), start: m.base, end: m.end(), perms: DATA },
+        };
+        Some(region)
+    }
+
+    fn check(&self, addr: usize, access: Access) -> Result<(), String> {
+        match self.region(addr) {
+            Some(region) => protection::check(&region, addr, access),
+            None         => Ok(()),
+        }
+    }
+
+    pub fn read(&mut self, addr: usize) -> Result<u8, String> {
+        self.check(addr, Access::Read)?;
+        let m = self.mapping_at(addr)?;
+        Ok(m.device.read(addr - m.base))
+    }
+
+    // Reads an instruction byte, which the region has to allow executing.
+    pub fn fetch(&mut self, addr: usize) -> Result<u8, String> {
+        self.check(addr, Access::Execute)?;
+        let m = self.mapping_at(addr)?;
+        Ok(m.device.read(addr - m.base))
+    }
+
+    pub fn write(&mut self, addr: usize, val: u8) -> Result<(), String> {
+        self.check(addr, Access::Write)?;
+        let i = self.mapping_index(addr)?;
+        let m = &mut self.mappings[i];
+        m.device.write(addr - m.base, val);
+        while let Some(dma) = self.mappings[i].device.take_dma() {
+            let res = self.transfer(dma);
+            self.mappings[i].device.dma_done(res);
+        }
+        Ok(())
+    }
+
+    fn transfer(&mut self, dma: Dma) -> Result<Vec<u8>, String> {
+        match dma {
+            Dma::ToMemory { addr, data } => {
+                for (i, b) in data.into_iter().enumerate() {
+                    self.write(addr + i, b)?;
+                }
+                Ok(Vec::new())
+            }
+            Dma::FromMemory { addr, len } => (addr..addr + len).map(|a| self.read(a)).collect(),
+        }
+    }
+
+    // Ticks every device, returns the interrupt lines that were raised as a bit mask.
+    pub fn tick(&mut self) -> u8 {
+        let mut raised = 0;
+        for m in &mut self.mappings {
+            if m.device.tick() {
+                if let Some(irq) = m.irq {
+                    raised |= 1 << irq;
+                }
+            }
+        }
+        raised
+    }
+
+    pub fn output_bytes(&self) -> u64 {
+        self.mappings.iter().map(|m| m.device.output_bytes()).sum()
+    }
+
+    pub fn finish(&mut self) {
+        for m in &mut self.mappings {
+            m.device.finish();
+        }
+    }
+
+    pub fn seed(&mut self, seed: u64) {
+        for m in &mut self.mappings {
+            m.device.seed(seed);
+        }
+    }
+
+    pub fn mappings(&self) -> &[Mapping] {
+        &self.mappings
+    }
+
+    // True if every mapped device is plain memory, so the memories are the whole bus state.
+    pub fn is_plain_memory(&self) -> bool {
+        self.mappings.iter().all(|m| m.device.memory().is_some())
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        self.mappings[0].device.memory().expect("Error: The first mapping is always ram.")
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        self.mappings[0].device.memory_mut().expect("Error: The first mapping is always ram.")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reads back the offset and ignores writes.
+    struct Latch;
+
+    impl Device for Latch {
+        fn name(&self) -> &'static str {
+            "latch"
+        }
+        fn size(&self) -> usize {
+            4
+        }
+        fn read(&mut self, offset: usize) -> u8 {
+            offset as u8
+        }
+        fn write(&mut self, _offset: usize, _val: u8) {}
+    }
+
+    #[test]
+    fn test_routing() {
+        let mut bus = Bus::new(vec![7; 16]);
+        bus.attach(Mapping::new(0xF0, Box::new(Latch))).unwrap();
+
+        assert_eq!(bus.read(3).unwrap(), 7);
+        assert_eq!(bus.read(0xF2).unwrap(), 2);
+        bus.write(0xF1, 9).unwrap();
+        bus.write(4, 1).unwrap();
+        assert_eq!(bus.ram()[4], 1);
+        assert!(bus.read(0x20).is_err());
+        assert!(bus.write(0xF4, 0).is_err());
+        assert!(!bus.is_plain_memory());
+    }
+
+    #[test]
+    fn test_overlap_rejected() {
+        let mut bus = Bus::new(vec![0; 0xE0]);
+        assert!(bus.attach(Mapping::new(0xDE, Box::new(Latch))).is_err());
+        assert!(bus.attach(Mapping::new(0xFE, Box::new(Latch))).is_err());
+        bus.attach(Mapping::new(0xE0, Box::new(Latch))).unwrap();
+        let e = bus.attach(Mapping::new(0xE2, Box::new(Ram::new(vec![0; 8])))).unwrap_err();
+        assert!(e.contains("overlaps latch"));
+        bus.attach(Mapping::new(0xE4, Box::new(Ram::new(vec![0; 8])))).unwrap();
+    }
+}
@@ -15,6 +15,13 @@ impl BytesConverter for u8 {
     }
 }
 
+// Little endian.
+impl BytesConverter for u16 {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.to_le_bytes().to_vec()
+    }
+}
+
 impl BytesConverter for &str {
     fn to_bytes(&self) -> Vec<u8> {
         self.as_bytes().to_vec()
@@ -0,0 +1,230 @@
+// Machine configuration: how much RAM there is and which devices are attached where.
+// Can be built in Rust or read from a text file with one setting per line:
+//
+//   # Comments start with '#'.
+//   ram <size>                      Size of the RAM mapped at address 0.
+//   seed <n>                        Seed for the random devices, --seed overrides it.
+//   self-modifying                  Let the program write to its own code, see protection.rs.
+//   heap-header <narrow | wide>     1 or 2 byte block headers on the heap, narrow is the default.
+//   allocator <first-fit | buddy | slab [slot size]>
+//                                   The allocator behind the heap instructions, see allocator.rs.
+//   sanitize-heap                   Red zones, use after free and leak checks on the heap, see sanitizer.rs.
+//   gc                              Collect garbage when the heap is full, see gc.rs.
+//   device <kind> <base> [args...] [irq <line>]
+//                                   Attach a device at 'base', optionally connected to an interrupt line.
+//
+// Device kinds:
+//   ram <base> <size>               An extra bank of RAM.
+//   console <base>                  Console on stdin and stdout, see console.rs.
+//   timer <base>                    Programmable timer, see timer.rs.
+//   framebuffer <base> [ppm <prefix> | ansi]
+//                                   32x32 framebuffer, frames are written to <prefix>_<n>.ppm or drawn
+//                                   on the terminal. See framebuffer.rs.
+//   storage <base> <image> [ro]     Block device on a disk image file, optionally read-only. See storage.rs.
+//   audio <base> <wav file>         Tone and sample device, mixed into the WAV file at the end. See audio.rs.
+//   random <base>                   Pseudo random numbers from the machine's seed. See random.rs.
+//
+// Numbers are decimal or hex with a 0x prefix. Overlapping mappings are rejected
+// when the machine is built from the config.
+use std::fs;
+use std::io;
+use crate::allocator::{AllocatorKind, DEFAULT_SLOT_SIZE, SLAB_SIZE};
+use crate::audio::Audio;
+use crate::bus::{Device, Mapping, Ram};
+use crate::console::Console;
+use crate::framebuffer::{FrameOutput, Framebuffer};
+use crate::storage::Storage;
+use crate::random::{Random, DEFAULT_SEED};
+use crate::timer::Timer;
+use crate::memory::{ADDRESS_SPACE, MEMORY_SIZE};
+use crate::yoloheap::HeaderWidth;
+use crate::yoloheap::constants::MINIMUM_ALLOCATED_SIZE;
+
+pub struct MachineConfig {
+    pub ram_size:       usize,
+    pub devices:        Vec<Mapping>,
+    pub seed:           u64,
+    pub self_modifying: bool,
+    pub heap_header:    HeaderWidth,
+    pub allocator:      AllocatorKind,
+    pub sanitize_heap:  bool,
+    pub gc:             bool,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        Self {
+            ram_size:       MEMORY_SIZE,
+            devices:        Vec::new(),
+            seed:           DEFAULT_SEED,
+            self_modifying: false,
+            heap_header:    HeaderWidth::Narrow,
+            allocator:      AllocatorKind::FirstFit,
+            sanitize_heap:  false,
+            gc:             false,
+        }
+    }
+}
+
+fn parse_number(s: &str) -> Result<usize, String> {
+    let res = match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None      => s.parse(),
+    };
+    res.map_err(|_| format!("Error: Invalid number: {}", s))
+}
+
+fn make_device(kind: &str, args: &[&str]) -> Result<Box<dyn Device>, String> {
+    match (kind, args) {
+        ("ram", [size])                  => Ok(Box::new(Ram::new(vec![0; parse_number(size)?]))),
+        ("ram", _)                       => Err(String::from("Error: Usage: device ram <base> <size>")),
+        ("console", [])                  => Ok(Box::new(Console::stdio())),
+        ("console", _)                   => Err(String::from("Error: Usage: device console <base>")),
+        ("timer", [])                    => Ok(Box::new(Timer::new())),
+        ("timer", _)                     => Err(String::from("Error: Usage: device timer <base>")),
+        ("framebuffer", [])              => Ok(Box::new(Framebuffer::new(FrameOutput::None))),
+        ("framebuffer", ["ppm", prefix]) => Ok(Box::new(Framebuffer::new(FrameOutput::Ppm(prefix.to_string())))),
+        ("framebuffer", ["ansi"])        => Ok(Box::new(Framebuffer::new(FrameOutput::Ansi(Box::new(io::stdout()))))),
+        ("framebuffer", _)               => Err(String::from("Error: Usage: device framebuffer <base> [ppm <prefix> | ansi]")),
+        ("storage", [image])             => Ok(Box::new(Storage::open(image, false)?)),
+        ("storage", [image, "ro"])       => Ok(Box::new(Storage::open(image, true)?)),
+        ("storage", _)                   => Err(String::from("Error: Usage: device storage <base> <image> [ro]")),
+        ("audio", [wav])                 => Ok(Box::new(Audio::new(wav))),
+        ("audio", _)                     => Err(String::from("Error: Usage: device audio <base> <wav file>")),
+        ("random", [])                   => Ok(Box::new(Random::new(DEFAULT_SEED))),
+        ("random", _)                    => Err(String::from("Error: Usage: device random <base>")),
+        _                                => Err(format!("Error: Unknown device: {}", kind)),
+    }
+}
+
+fn parse_allocator(args: &[&str]) -> Result<AllocatorKind, String> {
+    match args {
+        ["first-fit"]    => Ok(AllocatorKind::FirstFit),
+        ["buddy"]        => Ok(AllocatorKind::Buddy),
+        ["slab"]         => Ok(AllocatorKind::Slab(DEFAULT_SLOT_SIZE)),
+        ["slab", slot]   => {
+            let slot = parse_number(slot)?;
+            // The bitmap has to leave at least one slot free.
+            if !(MINIMUM_ALLOCATED_SIZE..=SLAB_SIZE / 4).contains(&slot) {
+                return Err(format!("Error: Slot size must be between {} and {}.", MINIMUM_ALLOCATED_SIZE, SLAB_SIZE / 4));
+            }
+            Ok(AllocatorKind::Slab(slot))
+        }
+        _ => Err(String::from("Error: Usage: allocator <first-fit | buddy | slab [slot size]>")),
+    }
+}
+
+impl MachineConfig {
+    pub fn parse(src: &str) -> Result<Self, String> {
+        let mut config = Self::default();
+        for (i, line) in src.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let res = match words.as_slice() {
+                [] => Ok(()),
+                ["ram", size] => parse_number(size).and_then(|size| {
+                    if size > ADDRESS_SPACE {
+                        return Err(format!("Error: RAM can't be larger than {} bytes.", ADDRESS_SPACE));
+                    }
+                    config.ram_size = size;
+                    Ok(())
+                }),
+                ["seed", seed] => parse_number(seed).map(|seed| config.seed = seed as u64),
+                ["heap-header", "narrow"] => {
+                    config.heap_header = HeaderWidth::Narrow;
+                    Ok(())
+                }
+                ["heap-header", "wide"] => {
+                    config.heap_header = HeaderWidth::Wide;
+                    Ok(())
+                }
+                ["allocator", kind @ ..] => parse_allocator(kind).map(|a| config.allocator = a),
+                ["gc"] => {
+                    config.gc = true;
+                    Ok(())
+                }
+                ["sanitize-heap"] => {
+                    config.sanitize_heap = true;
+                    Ok(())
+                }
+                ["self-modifying"] => {
+                    config.self_modifying = true;
+                    Ok(())
+                }
+                ["device", kind, base, args @ ..] => parse_number(base).and_then(|base| {
+                    let (args, irq) = match args {
+                        [args @ .., "irq", line] => {
+                            let line = u8::try_from(parse_number(line)?)
+                                .map_err(|_| format!("Error: Invalid interrupt line: {}", line))?;
+                            (args, Some(line))
+                        }
+                        _                        => (args, None),
+                    };
+                    let mut mapping = Mapping::new(base, make_device(kind, args)?);
+                    mapping.irq = irq;
+                    config.devices.push(mapping);
+                    Ok(())
+                }),
+                _ => Err(format!("Error: Unknown setting: {}", line.trim())),
+            };
+            res.map_err(|e| format!("{} (line {})", e, i + 1))?;
+        }
+        Ok(config)
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let src = fs::read_to_string(path).map_err(|e| format!("Error: Reading {}: {}", path, e))?;
+        Self::parse(&src)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::cpu_state::Machine;
+
+    #[test]
+    fn test_parse() {
+        let config = MachineConfig::parse("# Small machine\nram 0x80\n\ndevice ram 0xF0 16  # scratch\n").unwrap();
+        assert_eq!(config.ram_size, 0x80);
+        assert_eq!(config.devices.len(), 1);
+        assert_eq!(config.devices[0].base, 0xF0);
+        assert_eq!(MachineConfig::parse("device timer 0xF0 irq 2\n").unwrap().devices[0].irq, Some(2));
+
+        assert_eq!(MachineConfig::parse("heap-header wide\n").unwrap().heap_header, HeaderWidth::Wide);
+        assert_eq!(MachineConfig::parse("allocator slab 8\n").unwrap().allocator, AllocatorKind::Slab(8));
+        assert!(MachineConfig::parse("allocator slab 100\n").is_err());
+        assert!(MachineConfig::parse("sanitize-heap\n").unwrap().sanitize_heap);
+        assert!(MachineConfig::parse("gc\n").unwrap().gc);
+
+        assert!(MachineConfig::parse("ram 300\n").is_err());
+        assert!(matches!(MachineConfig::parse("device tape 0xF0\n"), Err(e) if e.contains("line 1")));
+        assert!(MachineConfig::parse("ram\n").is_err());
+    }
+
+    #[test]
+    fn test_overlap_rejected_at_setup() {
+        let config = MachineConfig::parse("device ram 0xF0 16\ndevice ram 0xF8 4\n").unwrap();
+        assert!(Machine::from_config(Vec::new(), config).is_err());
+
+        let config = MachineConfig::parse("device ram 0xD0 16\n").unwrap();
+        assert!(Machine::from_config(Vec::new(), config).is_err());
+
+        let config = MachineConfig::parse("device timer 0xF0 irq 8\n").unwrap();
+        assert!(Machine::from_config(Vec::new(), config).is_err());
+    }
+
+    #[test]
+    fn test_st_reaches_device() {
+        use crate::assembler::assemble_source;
+        let program = assemble_source("_START:\n ADDI r1 244\n ADDI r2 7\n ST r1 r2\n LD r3 r1\n HLT\n").mem;
+        let config = MachineConfig::parse("device ram 0xF0 16\n").unwrap();
+        let mut m = Machine::from_config(program, config).unwrap();
+        m.state.verbose = false;
+        while m.state.running {
+            m.step().unwrap();
+        }
+        assert_eq!(m.state.registers[3], 7);
+        assert_eq!(m.bus.mappings()[1].device.memory().unwrap()[4], 7);
+    }
+}
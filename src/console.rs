@@ -0,0 +1,169 @@
+// Console device, two ports:
+//   +0 DATA    Writing sends the byte to the output, reading takes the next input byte (0 at the end of the input).
+//   +1 STATUS  Bit 0 is set when an input byte is available.
+// Reading STATUS blocks until there is input or the input has ended, like a terminal waiting for a line.
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use crate::bus::Device;
+
+const DATA_PORT: usize = 0;
+const STATUS_PORT: usize = 1;
+const STATUS_INPUT_AVAILABLE: u8 = 1;
+
+pub struct Console {
+    input:     Box<dyn Read>,
+    output:    Box<dyn Write>,
+    lookahead: Option<u8>,  // Byte read by STATUS that DATA hasn't returned yet.
+    written:   u64,
+}
+
+impl Console {
+    pub fn new(input: Box<dyn Read>, output: Box<dyn Write>) -> Self {
+        Self {
+            input,
+            output,
+            lookahead: None,
+            written:   0,
+        }
+    }
+
+    pub fn stdio() -> Self {
+        Self::new(Box::new(io::stdin()), Box::new(io::stdout()))
+    }
+
+    // Makes sure the next input byte is in the lookahead, returns false at the end of the input.
+    fn fill(&mut self) -> bool {
+        if self.lookahead.is_none() {
+            // A prompt has to be visible before we wait for the answer.
+            let _ = self.output.flush();
+            let mut b = [0; 1];
+            if let Ok(1) = self.input.read(&mut b) {
+                self.lookahead = Some(b[0]);
+            }
+        }
+        self.lookahead.is_some()
+    }
+}
+
+impl Device for Console {
+    fn name(&self) -> &'static str {
+        "console"
+    }
+
+    fn size(&self) -> usize {
+        2
+    }
+
+    fn read(&mut self, offset: usize) -> u8 {
+        match offset {
+            DATA_PORT => {
+                self.fill();
+                self.lookahead.take().unwrap_or(0)
+            }
+            STATUS_PORT if self.fill() => STATUS_INPUT_AVAILABLE,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: usize, val: u8) {
+        if offset == DATA_PORT {
+            // There is no way to report a failed write to the program, the byte is lost.
+            let _ = self.output.write_all(&[val]);
+            self.written += 1;
+        }
+    }
+
+    fn output_bytes(&self) -> u64 {
+        self.written
+    }
+
+    fn finish(&mut self) {
+        let _ = self.output.flush();
+    }
+}
+
+// An output that can be read back after it has been handed to a device.
+#[allow(dead_code)]
+#[derive(Clone, Default)]
+pub struct SharedBuffer(pub Rc<RefCell<Vec<u8>>>);
+
+#[allow(dead_code)]
+impl SharedBuffer {
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Reading takes the bytes from the front, so a buffer can stand in for a stdin several readers share.
+impl Read for SharedBuffer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut inner = self.0.borrow_mut();
+        let n = buf.len().min(inner.len());
+        buf[..n].copy_from_slice(&inner[..n]);
+        inner.drain(..n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_source;
+    use crate::bus::Mapping;
+    use crate::config::MachineConfig;
+    use crate::cpu::cpu_state::Machine;
+
+    fn run(src: &str, input: &str) -> (Machine, SharedBuffer) {
+        let out = SharedBuffer::default();
+        let console = Console::new(Box::new(io::Cursor::new(input.as_bytes().to_vec())), Box::new(out.clone()));
+        let mut config = MachineConfig::default();
+        config.devices.push(Mapping::new(0xF0, Box::new(console)));
+        let mut m = Machine::from_config(assemble_source(src).mem, config).unwrap();
+        m.state.verbose = false;
+        while m.state.running {
+            m.step().unwrap();
+        }
+        (m, out)
+    }
+
+    #[test]
+    fn test_output() {
+        let (m, out) = run("_START:\n ADDI r1 240\n ADDI r2 72\n ST r1 r2\n ADDI r2 1\n ST r1 r2\n HLT\n", "");
+        assert_eq!(out.contents(), b"HI");
+        assert_eq!(m.bus.output_bytes(), 2);
+    }
+
+    #[test]
+    fn test_echo_until_end_of_input() {
+        // Copies input to output while STATUS says there is more. Jump targets are address + 2.
+        let src = "
+_START:
+    ADDI r1 240
+    ADDI r2 241
+    ADDI r5 10
+    ADDI r6 20
+_LOOP:
+    LD r3 r2
+    JMPZ r6 r3
+    LD r4 r1
+    ST r1 r4
+    JMPZ r5 r0
+_END:
+    HLT
+";
+        let (_, out) = run(src, "abc\n");
+        assert_eq!(out.contents(), b"abc\n");
+    }
+}
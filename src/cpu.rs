@@ -1,31 +1,222 @@
 pub mod cpu_state {
 
     use crate::memory;
+    use crate::bus::Bus;
+    use crate::config::MachineConfig;
     use crate::stack::Stack;
-    #[allow(unused_imports)]
+    use crate::syscalls::Syscalls;
+    use crate::instruction_mapping::instruction_utils::{disassemble, mnemonic, HLT_WITH_STATUS, SYSTEM_UPCODE};
+    use crate::interrupts::{InterruptState, IVT_BASE};
+    use crate::protection::Protection;
+    use crate::sandbox::{run_with_limits, Limits, Termination};
+    use crate::allocator::{self, Allocator};
+    use crate::gc;
+    use crate::sanitizer::Sanitizer;
     use crate::yoloheap::Heap;
+    use crate::yoloheap::constants::{MAX_BLOCK_SIZE, MINIMUM_ALLOCATED_SIZE};
 
-    //const MAX_HEAP_SIZE: usize = 256;
+    // Prints the executed instruction, but only if the state is verbose.
+    macro_rules! trace {
+        ($state:expr, $($arg:tt)*) => {
+            if $state.verbose {
+                println!($($arg)*);
+            }
+        };
+    }
+
+    // An empty heap is a single free block, so it can't be larger than a block.
+    pub const MAX_HEAP_SIZE: usize = MAX_BLOCK_SIZE as usize;
     pub const NUM_REGS: usize = 16;
     pub struct CpuState {
-        registers: [u8; NUM_REGS],
-        pc:        u8,
-        running:   bool,
-        //heap:   Heap,
+        pub registers: [u8; NUM_REGS],
+        pub pc:        u8,
+        pub running:   bool,
+        pub verbose:   bool,
+        pub heap:      Box<dyn Allocator>,
+        pub cycles:    u64,  // Number of instructions executed.
+        pub output:    u64,  // Bytes the host printed for the program, devices count their own.
+        pub interrupts: InterruptState,
+        pub exit_code: u8,   // Set by 'HLT r' and the EXIT system call.
+        pub check_heap: bool, // Check the heap after every heap instruction, see after_heap_instruction.
+        pub heap_map:  bool,  // Draw the heap after every heap instruction, in the instruction trace.
+        pub gc:        bool,  // Collect garbage when ALC or RALC finds the heap full, see gc.rs.
     }
 
     impl CpuState {
         pub fn new_state() -> Self {
-            println!("GITTEST");
             Self {
                 registers: [0; NUM_REGS],
                 pc:        0,
                 running:   true,
-                //heap:      Heap::new_heap(MAX_HEAP_SIZE)
+                verbose:   true,
+                heap:      Box::new(Heap::new_heap(MAX_HEAP_SIZE)),
+                cycles:    0,
+                output:    0,
+                interrupts: InterruptState::default(),
+                exit_code: 0,
+                check_heap: false,
+                heap_map:  false,
+                gc:        false,
+            }
+        }
+    }
+
+    // Everything the cpu needs to run a program: state, stack, the bus with memory and devices
+    // and the host services for SYS.
+    pub struct Machine {
+        pub state:    CpuState,
+        pub stack:    Stack,
+        pub bus:      Bus,
+        pub syscalls: Syscalls,
+    }
+
+    // What a step executed. When an interrupt was taken first, 'pc' is the handler's first instruction.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct Executed {
+        pub pc:          u8,
+        pub instruction: (u8, u8),
+        pub interrupt:   Option<u8>, // The line whose handler was entered before the instruction.
+    }
+
+    impl Machine {
+        // Creates a machine with only RAM on the bus and the program loaded at address 0.
+        pub fn new(program: Vec<u8>) -> Self {
+            Self::from_config(program, MachineConfig::default()).unwrap_or_else(|e| panic!("{e}"))
+        }
+
+        // Creates a machine with RAM and the devices of the config on the bus.
+        // The program is loaded at address 0, the rest of RAM is zero filled.
+        pub fn from_config(program: Vec<u8>, config: MachineConfig) -> Result<Self, String> {
+            if program.len() > config.ram_size {
+                return Err(format!("Error: Program does not fit in {} bytes of RAM.", config.ram_size));
+            }
+            let code_end = program.len();
+            let mut ram = program;
+            ram.resize(config.ram_size, 0);
+            let mut bus = Bus::new(ram);
+            for mapping in config.devices {
+                bus.attach(mapping)?;
+            }
+            bus.seed(config.seed);
+            bus.protection = Some(Protection { code_end, self_modifying: config.self_modifying });
+            let mut state = CpuState::new_state();
+            let heap = allocator::create(config.allocator, config.heap_header);
+            state.heap = if config.sanitize_heap { Box::new(Sanitizer::new(heap)) } else { heap };
+            state.gc = config.gc;
+            Ok(Self {
+                state,
+                stack:    Stack::create_stack(),
+                bus,
+                syscalls: Syscalls::stdio(),
+            })
+        }
+
+        // Returns the instruction at pc without executing it.
+        // Only RAM is looked at, reading a device could change it. Bytes outside of RAM read as 0.
+        pub fn peek_instruction(&self) -> (u8, u8) {
+            let pc = self.state.pc as usize;
+            let byte = |i: usize| self.bus.ram().get(i).copied().unwrap_or(0);
+            (byte(pc), byte(pc + 1))
+        }
+
+        // Called when the run is over, flushes output and lets the devices write their files.
+        // After a HLT the blocks still allocated are reported, if the allocator keeps track of them.
+        pub fn finish(&mut self) {
+            self.syscalls.flush();
+            self.bus.finish();
+            let leaks = self.state.heap.leaks();
+            if !self.state.running && !leaks.is_empty() {
+                println!("Leak report, {} blocks never freed:", leaks.len());
+                for leak in leaks {
+                    println!("  {}", leak);
+                }
+            }
+        }
+
+        // Enters the handler of a pending interrupt, if one can be taken.
+        fn take_interrupt(&mut self) -> Result<Option<u8>, String> {
+            let irq = self.state.interrupts.next_irq();
+            if let Some(irq) = irq {
+                trace!(self.state, "Interrupt {}", irq);
+                self.state.interrupts.pending &= !(1 << irq);
+                self.state.interrupts.enabled = false;
+                self.stack.stack_push(self.state.pc)?;
+                self.state.pc = self.bus.read(IVT_BASE + irq as usize)?;
+            }
+            Ok(irq)
+        }
+
+        // Takes a pending interrupt, fetches and executes a single instruction, then lets the devices tick.
+        // If the instruction faults, pc, stack and interrupts are left as they were before it.
+        pub fn step(&mut self) -> Result<Executed, String> {
+            let pc = self.state.pc;
+            let top = self.stack.top;
+            let interrupts = self.state.interrupts;
+            let res = self.take_interrupt()
+                .and_then(|interrupt| {
+                    let executed_pc = self.state.pc;
+                    self.state.heap.set_pc(executed_pc);
+                    let i = memory::fetch_instruction(&mut self.state.pc, &mut self.bus)?;
+                    Ok(Executed { pc: executed_pc, instruction: i, interrupt })
+                })
+                .and_then(|executed| {
+                    let i = executed.instruction;
+                    execute_instruction(&i, &mut self.state, &mut self.stack, &mut self.bus, &mut self.syscalls)?;
+                    after_heap_instruction(&self.state, &i)?;
+                    Ok(executed)
+                });
+
+            match res {
+                Ok(executed) => {
+                    self.state.cycles += 1;
+                    self.state.interrupts.pending |= self.bus.tick();
+                    Ok(executed)
+                }
+                Err(e) => {
+                    self.state.pc = pc;
+                    self.stack.top = top;
+                    self.state.interrupts = interrupts;
+                    Err(e)
+                }
             }
         }
     }
 
+    // The instructions that change the heap.
+    const HEAP_INSTRUCTIONS: [&str; 5] = ["ALC", "FREE", "WRH", "RALC", "GC"];
+
+    // Debugging aids for the allocators, both only look at the heap after an instruction that changed it.
+    // With heap_map on the trace shows the heap as a map.
+    // With check_heap on, a heap instruction that leaves the heap corrupt faults,
+    // instead of the corruption showing up much later as a panic somewhere in the allocator.
+    fn after_heap_instruction(state: &CpuState, instr: &(u8, u8)) -> Result<(), String> {
+        if !HEAP_INSTRUCTIONS.contains(&mnemonic(instr)) {
+            return Ok(());
+        }
+        if state.heap_map {
+            trace!(state, "{}", allocator::render_map(state.heap.as_ref()).trim_end());
+        }
+        let violations = if state.check_heap { state.heap.check() } else { Vec::new() };
+        if violations.is_empty() {
+            return Ok(());
+        }
+        Err(format!("Error: Heap corrupt after {}:\n  {}", disassemble(instr), violations.join("\n  ")))
+    }
+
+    // Runs the garbage collector with the registers and the stack as the roots.
+    fn collect_garbage(state: &mut CpuState, stack: &Stack) -> Result<usize, String> {
+        let mut roots = state.registers.to_vec();
+        roots.extend_from_slice(&stack.stack[..stack.top]);
+        let freed = gc::collect(state.heap.as_mut(), &roots)?;
+        trace!(state, "  Garbage collected, {} blocks freed.", freed);
+        Ok(freed)
+    }
+
+    // Heap faults name the instruction, the allocator says what was wrong.
+    fn heap_fault(what: String, e: String) -> String {
+        format!("Error: {} failed. {}", what, e.trim_start_matches("Error: "))
+    }
+
     struct DecodedInstruction {
         pub upcode:  u8,
         pub arg1:    u8,
@@ -42,125 +233,317 @@ pub mod cpu_state {
         }
     }
 
-    fn execute_instruction(instr: &(u8, u8), state: &mut CpuState, stack: &mut Stack, mem: &mut [u8]) {
+    fn execute_instruction(instr: &(u8, u8), state: &mut CpuState, stack: &mut Stack, bus: &mut Bus, syscalls: &mut Syscalls) -> Result<(), String> {
         let inst = DecodedInstruction::multibyte_decode(instr);
     
         match inst.upcode {
             0x0 => {
                 // LDI: Load Immediate into register.
-                println!("LDI r{} {}", inst.arg1, inst.arg2);
+                trace!(state, "LDI r{} {}", inst.arg1, inst.arg2);
                 state.registers[inst.arg1 as usize] = inst.arg2;
             }
             0x1 => {
                 // LD: Load from Memory
-                println!("LD r{} r{}", inst.arg1, inst.arg2);
-                state.registers[inst.arg1 as usize] = 
-                    memory::read_from_memory(mem, state.registers[inst.arg2 as usize] as usize);
+                trace!(state, "LD r{} r{}", inst.arg1, inst.arg2);
+                state.registers[inst.arg1 as usize] = bus.read(state.registers[inst.arg2 as usize] as usize)?;
             }
             0x2 => {
                 // ST: Store to Memory
-                println!("ST r{} r{}", inst.arg1, inst.arg2);
-                memory::write_to_memory(
-                    mem, 
-                    state.registers[inst.arg1 as usize] as usize,
-                    state.registers[inst.arg2 as usize]);
+                trace!(state, "ST r{} r{}", inst.arg1, inst.arg2);
+                bus.write(state.registers[inst.arg1 as usize] as usize, state.registers[inst.arg2 as usize])?;
             }
             0x3 => {
                 // MOV: Move Data, set reg[r1] = reg[r2]
-                println!("MOV r{} r{}", inst.arg1, inst.arg2);
+                trace!(state, "MOV r{} r{}", inst.arg1, inst.arg2);
                 state.registers[inst.arg1 as usize] = state.registers[inst.arg2 as usize];
             }
             0x4 => {
                 // ADD: Add value in r1 with r2, place in r1.
-                println!("ADD r{} r{}", inst.arg1, inst.arg2);
-                let _t = state.registers[inst.arg1 as usize].checked_add(state.registers[inst.arg2 as usize]).expect("Overflow happened in add.");
-                println!("R1: {}, R2 (RESULT): {}", state.registers[inst.arg1 as usize], state.registers[inst.arg2 as usize]);
+                trace!(state, "ADD r{} r{}", inst.arg1, inst.arg2);
+                let _t = state.registers[inst.arg1 as usize].checked_add(state.registers[inst.arg2 as usize]).ok_or("Error: Overflow happened in add.")?;
+                trace!(state, "R1: {}, R2 (RESULT): {}", state.registers[inst.arg1 as usize], state.registers[inst.arg2 as usize]);
                 state.registers[inst.arg1 as usize] = _t;
             }
             0x5 => {
                 // SUB: Subtract
-                println!("SUB r{} r{}", inst.arg1, inst.arg2);
-                println!("reg1: {},  reg2: {}", state.registers[inst.arg1 as usize], state.registers[inst.arg2 as usize]);
-                assert!(state.registers[inst.arg1 as usize] >= state.registers[inst.arg2 as usize]);
-                state.registers[inst.arg1 as usize] -= state.registers[inst.arg2 as usize];
+                trace!(state, "SUB r{} r{}", inst.arg1, inst.arg2);
+                trace!(state, "reg1: {},  reg2: {}", state.registers[inst.arg1 as usize], state.registers[inst.arg2 as usize]);
+                state.registers[inst.arg1 as usize] = state.registers[inst.arg1 as usize]
+                    .checked_sub(state.registers[inst.arg2 as usize])
+                    .ok_or("Error: Underflow happened in sub.")?;
             }
             0x6 => {
                 // MUL: Multiply
-                println!("MUL r{} r{}", inst.arg1, inst.arg2);
-                state.registers[inst.arg1 as usize] *= state.registers[inst.arg2 as usize];
+                trace!(state, "MUL r{} r{}", inst.arg1, inst.arg2);
+                state.registers[inst.arg1 as usize] = state.registers[inst.arg1 as usize]
+                    .checked_mul(state.registers[inst.arg2 as usize])
+                    .ok_or("Error: Overflow happened in mul.")?;
             }
             0x7 => {
                 // ADDI: Add Immediate
-                println!("ADDI r{} {}", inst.arg1, inst.arg2);
-                state.registers[inst.arg1 as usize] += inst.arg2;
+                trace!(state, "ADDI r{} {}", inst.arg1, inst.arg2);
+                state.registers[inst.arg1 as usize] = state.registers[inst.arg1 as usize]
+                    .checked_add(inst.arg2)
+                    .ok_or("Error: Overflow happened in addi.")?;
             }
             0x8 => {
                 // AND: Bitwise AND
-                println!("AND r{} r{}", inst.arg1, inst.arg2);
+                trace!(state, "AND r{} r{}", inst.arg1, inst.arg2);
                 state.registers[inst.arg1 as usize] &= state.registers[inst.arg2 as usize];
             }
             0x9 => {
                 // OR: Bitwise OR
-                println!("OR r{} r{}", inst.arg1, inst.arg2);
+                trace!(state, "OR r{} r{}", inst.arg1, inst.arg2);
                 state.registers[inst.arg1 as usize] |= state.registers[inst.arg2 as usize];
             }
             0xA => {
                 // XOR: Bitwise XOR
-                println!("XOR r{} r{}", inst.arg1, inst.arg2);
+                trace!(state, "XOR r{} r{}", inst.arg1, inst.arg2);
                 state.registers[inst.arg1 as usize] ^= state.registers[inst.arg2 as usize];
             }
             0xB => {
                 // NOT: Bitwise NOT
-                println!("NOT r{}", inst.arg1);
+                trace!(state, "NOT r{}", inst.arg1);
                 state.registers[inst.arg1 as usize] = !state.registers[inst.arg1 as usize];
             }
             0xC => {
                 // JMPZ: Jump to r1 if r2 is zero
-                println!("JMPZ r{} r{}", inst.arg1, inst.arg2);
+                trace!(state, "JMPZ r{} r{}", inst.arg1, inst.arg2);
                 if state.registers[inst.arg2 as usize] == 0 {
-                    state.pc = state.registers[inst.arg1 as usize] - 2;
+                    state.pc = state.registers[inst.arg1 as usize]
+                        .checked_sub(2)
+                        .ok_or("Error: Jump target is below address 2.")?;
                 }
             }
             0xD => {
                 // RET: Return to return address
-                println!("RET");
-                if let Ok(ret) = stack.stack_pop() {
-                    state.pc = ret;
-                } else {
-                    panic!("Error in CPU: Stack pop failed in RET.");
-                }
+                trace!(state, "RET");
+                state.pc = stack.stack_pop()?;
             }
             0xE => {
                 // CALL: Calls a function
-                println!("CALL {}", inst.arg2);
-                if let Err(e) = stack.stack_push(state.pc) {
-                    panic!("{e}");
-                }
+                trace!(state, "CALL {}", inst.arg2);
+                stack.stack_push(state.pc)?;
                 state.pc = inst.arg2;
             }
-            0xF => {
-                // HLT - Halts the program.
-                println!("HLT");
-                state.running = false;
+            SYSTEM_UPCODE => {
+                // System instructions, the funct is in the high nibble of arg2.
+                let rs = (inst.arg2 & 0xf) as usize;
+                match inst.arg2 >> 4 {
+                    0x0 => {
+                        // HLT - Halts the program. 'HLT r1' sets rs to 1 and exits with reg[r1], plain HLT exits with 0.
+                        if rs == HLT_WITH_STATUS as usize {
+                            trace!(state, "HLT r{}", inst.arg1);
+                            state.exit_code = state.registers[inst.arg1 as usize];
+                        } else {
+                            trace!(state, "HLT");
+                        }
+                        state.running = false;
+                    }
+                    0x1 => {
+                        // ALC: Allocate reg[rs] bytes on the heap, r1 is the pointer or 0 if the heap is full.
+                        trace!(state, "ALC r{} r{}", inst.arg1, rs);
+                        let size = (state.registers[rs] as usize).max(MINIMUM_ALLOCATED_SIZE);
+                        let mut ptr = state.heap.allocate(size);
+                        if ptr.is_none() && state.gc {
+                            collect_garbage(state, stack)?;
+                            ptr = state.heap.allocate(size);
+                        }
+                        state.registers[inst.arg1 as usize] = ptr.unwrap_or(0) as u8;
+                    }
+                    0x2 => {
+                        // FREE: Free the heap block that r1 points to.
+                        trace!(state, "FREE r{}", inst.arg1);
+                        let ptr = state.registers[inst.arg1 as usize] as usize;
+                        state.heap.free(ptr).map_err(|e| heap_fault(format!("FREE of {}", ptr), e))?;
+                    }
+                    0x3 => {
+                        // WRH: Write reg[rs] to the heap at the address in r1, which must be inside an allocated block.
+                        trace!(state, "WRH r{} r{}", inst.arg1, rs);
+                        let addr = state.registers[inst.arg1 as usize] as usize;
+                        state.heap.write_bytes(addr, &[state.registers[rs]])
+                            .map_err(|e| heap_fault(format!("WRH to {}", addr), e))?;
+                    }
+                    0x4 => {
+                        // EI: Enable interrupts.
+                        trace!(state, "EI");
+                        state.interrupts.enabled = true;
+                    }
+                    0x5 => {
+                        // DI: Disable interrupts.
+                        trace!(state, "DI");
+                        state.interrupts.enabled = false;
+                    }
+                    0x6 => {
+                        // IRET: Return from an interrupt handler and enable interrupts again.
+                        trace!(state, "IRET");
+                        state.pc = stack.stack_pop()?;
+                        state.interrupts.enabled = true;
+                    }
+                    0x7 => {
+                        // IMASK: Set the interrupt mask to r1.
+                        trace!(state, "IMASK r{}", inst.arg1);
+                        state.interrupts.mask = state.registers[inst.arg1 as usize];
+                    }
+                    0x8 => {
+                        // IPEND: Load the pending interrupts into r1.
+                        trace!(state, "IPEND r{}", inst.arg1);
+                        state.registers[inst.arg1 as usize] = state.interrupts.pending;
+                    }
+                    0x9 => {
+                        // SYS: Call host service n, its 8 bits are split between the r1 nibble and the rs nibble.
+                        let n = inst.arg1 << 4 | rs as u8;
+                        trace!(state, "SYS {}", n);
+                        syscalls.call(n, state, bus)?;
+                    }
+                    0xA => {
+                        // RALC: Resize the heap block r1 points to, to reg[rs] bytes. r1 is the new pointer, or 0 if
+                        // there is no room, the old block is still allocated then.
+                        trace!(state, "RALC r{} r{}", inst.arg1, rs);
+                        let ptr = state.registers[inst.arg1 as usize] as usize;
+                        let size = (state.registers[rs] as usize).max(MINIMUM_ALLOCATED_SIZE);
+                        let mut new_ptr = state.heap.realloc(ptr, size)
+                            .map_err(|e| heap_fault(format!("RALC of {}", ptr), e))?;
+                        if new_ptr.is_none() && state.gc {
+                            // r1 is a root, the block being resized survives.
+                            collect_garbage(state, stack)?;
+                            new_ptr = state.heap.realloc(ptr, size)
+                                .map_err(|e| heap_fault(format!("RALC of {}", ptr), e))?;
+                        }
+                        state.registers[inst.arg1 as usize] = new_ptr.unwrap_or(0) as u8;
+                    }
+                    0xB => {
+                        // RDH: Load the heap byte at the address in reg[rs] into r1, the address must be inside an allocated block.
+                        trace!(state, "RDH r{} r{}", inst.arg1, rs);
+                        let addr = state.registers[rs] as usize;
+                        state.registers[inst.arg1 as usize] = state.heap.read_bytes(addr, 1)
+                            .map_err(|e| heap_fault(format!("RDH from {}", addr), e))?[0];
+                    }
+                    0xC => {
+                        // GC: Collect garbage now, whether the gc mode is on or not. r1 is the number of blocks freed.
+                        trace!(state, "GC r{}", inst.arg1);
+                        state.registers[inst.arg1 as usize] = collect_garbage(state, stack)? as u8;
+                    }
+                    f => return Err(format!("Error: Unknown system instruction: {:#X}", f)),
+                }
             }
             _ => {
-                panic!("Error: Unknown opcode: {:#X}", inst.upcode);
+                return Err(format!("Error: Unknown opcode: {:#X}", inst.upcode));
             }
         }   
+        Ok(())
     }
 
-    pub fn execute(mem: &mut [u8]) {
-        let mut state = CpuState::new_state();
-        let mut stack = Stack::create_stack();
-        while state.running {  
-            let i = memory::fetch_instruction(&mut state.pc, mem);
-            execute_instruction(&i, &mut state, &mut stack, mem);
+    // How a run ended, returned by execute_machine.
+    #[derive(Debug, PartialEq)]
+    pub struct Outcome {
+        pub exit_code:    u8,
+        pub registers:    [u8; NUM_REGS],
+        pub instructions: u64,
+        pub reason:       Termination,
+    }
+
+    // The host exit code for runs that did not halt, a program can't tell us anything then.
+    pub const TERMINATED_EXIT_CODE: i32 = 125;
+
+    impl Outcome {
+        pub fn new(machine: &Machine, reason: Termination) -> Self {
+            Self {
+                exit_code:    machine.state.exit_code,
+                registers:    machine.state.registers,
+                instructions: machine.state.cycles,
+                reason,
+            }
+        }
+
+        // The exit code for the host process.
+        pub fn process_exit_code(&self) -> i32 {
+            match self.reason {
+                Termination::Halted => self.exit_code as i32,
+                _                   => TERMINATED_EXIT_CODE,
+            }
         }
-        println!("Fib(n) = {}", state.registers[2]);
-        
+    }
+
+    // Runs a machine that is set up with its program, or restored from a snapshot.
+    pub fn execute_machine(mut machine: Machine, limits: &Limits) -> Outcome {
+        let reason = run_with_limits(&mut machine, limits);
+        machine.finish();
+        Outcome::new(&machine, reason)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::cpu_state::*;
+    use crate::assembler::assemble_source;
+    use crate::instruction_mapping::instruction_utils::disassemble;
+    use crate::sandbox::{Limits, Termination};
+
+    fn run(src: &str) -> Outcome {
+        let mut m = Machine::new(assemble_source(src).mem);
+        m.state.verbose = false;
+        execute_machine(m, &Limits::default())
+    }
+
+    #[test]
+    fn test_exit_status() {
+        let o = run("_START:\n ADDI r0 9\n ADDI r3 42\n HLT r3\n");
+        assert_eq!(o.reason, Termination::Halted);
+        assert_eq!(o.exit_code, 42);
+        assert_eq!(o.instructions, 3);
+        assert_eq!(o.registers[3], 42);
+        assert_eq!(o.process_exit_code(), 42);
+
+        // Plain HLT exits with 0 whatever is in r0.
+        assert_eq!(run("_START:\n ADDI r0 9\n HLT\n").exit_code, 0);
+
+        let o = run("_START:\n RET\n");
+        assert!(matches!(o.reason, Termination::Fault(_)));
+        assert_eq!(o.process_exit_code(), TERMINATED_EXIT_CODE);
+    }
+
+    #[test]
+    fn test_heap_instructions() {
+        let mut m = Machine::new(assemble_source("_START:\n ADDI r1 4\n ALC r2 r1\n MOV r3 r2\n ADDI r3 2\n ADDI r4 9\n WRH r3 r4\n FREE r2\n HLT\n").mem);
+        m.state.verbose = false;
+        for _ in 0..6 {
+            m.step().unwrap();
+        }
+        assert_eq!(m.state.heap.memory()[m.state.registers[3] as usize], 9);
+        m.step().unwrap();
+        assert_eq!(m.state.heap.stats().allocated_bytes, 0);
+
+        // Writing after the free is outside any block.
+        let o = run("_START:\n ADDI r1 4\n ALC r2 r1\n FREE r2\n WRH r2 r1\n HLT\n");
+        assert!(matches!(o.reason, Termination::Fault(_)));
+    }
+
+    #[test]
+    fn test_rdh() {
+        let o = run("_START:\n ADDI r1 4\n ALC r2 r1\n ADDI r3 9\n WRH r2 r3\n RDH r4 r2\n HLT r4\n");
+        assert_eq!(o.exit_code, 9);
 
+        let o = run("_START:\n ADDI r1 4\n ALC r2 r1\n FREE r2\n RDH r4 r2\n HLT\n");
+        assert!(matches!(o.reason, Termination::Fault(e) if e.contains("RDH from 1")));
+    }
 
+    #[test]
+    fn test_check_heap() {
+        let mut m = Machine::new(assemble_source("_START:\n ADDI r1 4\n ALC r2 r1\n WRH r2 r1\n HLT\n").mem);
+        m.state.verbose = false;
+        m.state.check_heap = true;
+        m.step().unwrap();
+        m.step().unwrap();
+        m.state.heap.memory_mut()[7] = 0;
+        let e = m.step().unwrap_err();
+        assert!(e.starts_with("Error: Heap corrupt after WRH r2 r1:\n  Block at 0: header"), "{}", e);
+    }
 
+    #[test]
+    fn test_disassemble_system_instructions() {
+        let mem = assemble_source("_START:\n HLT r3\n HLT\n SYS 200\n IMASK r2\n ALC r1 r4\n RALC r2 r5\n RDH r6 r7\n GC r8\n").mem;
+        let text: Vec<String> = mem.chunks(2).map(|c| disassemble(&(c[0], c[1]))).collect();
+        assert_eq!(text, vec!["HLT r3", "HLT", "SYS 200", "IMASK r2", "ALC r1 r4", "RALC r2 r5", "RDH r6 r7", "GC r8"]);
+    }
+}
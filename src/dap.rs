@@ -0,0 +1,450 @@
+// Debug Adapter Protocol server.
+// Speaks DAP over any reader/writer pair (stdin/stdout from main), and drives the Debugger.
+// Every instruction is on its own source line, so instruction stepping is line stepping.
+use std::io::{self, BufRead, Write};
+use std::panic;
+use crate::assembler::{assemble_program, Program};
+use crate::cpu::cpu_state::{Machine, NUM_REGS};
+use crate::debugger::{Debugger, StopReason};
+use crate::json::Json;
+use crate::syscalls::Syscalls;
+
+const THREAD_ID: i64 = 1;
+const REGISTERS_REF: i64 = 1;
+const STACK_REF: i64 = 2;
+const HEAP_REF: i64 = 3;
+
+struct Session {
+    dbg:           Debugger,
+    program:       Program,
+    path:          String,
+    stop_on_entry: bool,
+}
+
+pub struct DapServer<W: Write> {
+    out:     W,
+    seq:     i64,
+    session: Option<Session>,
+}
+
+// Reads one message, returns None at end of input.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Json>> {
+    let mut content_length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(len) = line.strip_prefix("Content-Length:") {
+            content_length = len.trim().parse::<usize>().ok();
+        }
+    }
+
+    let len = content_length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Error: Missing Content-Length."))?;
+    let mut body = vec![0; len];
+    input.read_exact(&mut body)?;
+    let body = String::from_utf8(body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Json::parse(&body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+impl<W: Write> DapServer<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            seq: 1,
+            session: None,
+        }
+    }
+
+    fn send(&mut self, msg_type: &str, mut fields: Vec<(&str, Json)>) -> io::Result<()> {
+        let mut pairs = vec![("seq", Json::from(self.seq)), ("type", Json::str(msg_type))];
+        pairs.append(&mut fields);
+        self.seq += 1;
+
+        let body = Json::object(pairs).to_string();
+        write!(self.out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        self.out.flush()
+    }
+
+    fn respond(&mut self, req: &Json, result: Result<Json, String>) -> io::Result<()> {
+        let mut fields = vec![
+            ("request_seq", Json::from(req.get("seq").and_then(Json::as_i64).unwrap_or(0))),
+            ("command",     Json::str(req.get("command").and_then(Json::as_str).unwrap_or(""))),
+        ];
+        match result {
+            Ok(body) => {
+                fields.push(("success", Json::from(true)));
+                fields.push(("body", body));
+            }
+            Err(e) => {
+                fields.push(("success", Json::from(false)));
+                fields.push(("message", Json::from(e)));
+            }
+        }
+        self.send("response", fields)
+    }
+
+    fn event(&mut self, name: &str, body: Json) -> io::Result<()> {
+        self.send("event", vec![("event", Json::str(name)), ("body", body)])
+    }
+
+    // Tells the client why execution stopped.
+    fn report_stop(&mut self, reason: StopReason) -> io::Result<()> {
+        let reason = match reason {
+            StopReason::Halted => {
+                let code = self.session.as_ref().map_or(0, |s| s.dbg.machine.state.exit_code);
+                self.event("exited", Json::object(vec![("exitCode", Json::from(code as i64))]))?;
+                return self.event("terminated", Json::object(vec![]));
+            }
+            StopReason::Fault(e) => {
+                return self.event("stopped", Json::object(vec![
+                    ("reason",            Json::str("exception")),
+                    ("description",       Json::from(e.clone())),
+                    ("text",              Json::from(e)),
+                    ("threadId",          Json::from(THREAD_ID)),
+                    ("allThreadsStopped", Json::from(true)),
+                ]));
+            }
+            StopReason::Breakpoint(_) => "breakpoint",
+            StopReason::Stepped | StopReason::Returned => "step",
+        };
+        self.event("stopped", Json::object(vec![
+            ("reason",            Json::str(reason)),
+            ("threadId",          Json::from(THREAD_ID)),
+            ("allThreadsStopped", Json::from(true)),
+        ]))
+    }
+
+    fn session(&mut self) -> Result<&mut Session, String> {
+        self.session.as_mut().ok_or_else(|| String::from("Error: No program has been launched."))
+    }
+
+    fn launch(&mut self, args: &Json) -> Result<Json, String> {
+        let path = args.get("program")
+            .and_then(Json::as_str)
+            .ok_or("Error: 'program' missing from launch arguments.")?
+            .to_string();
+
+        // The assembler panics on bad programs, report that as a failed launch instead.
+        let program = panic::catch_unwind(|| assemble_program(&path))
+            .map_err(|_| format!("Error: Could not assemble '{}'.", path))?;
+
+        // stdin and stdout carry the protocol, so the program gets no input and prints to stderr.
+        let mut machine = Machine::new(program.mem.clone());
+        machine.syscalls = Syscalls::new(Box::new(io::empty()), Box::new(io::stderr()));
+        let dbg = Debugger::new(machine, program.symtab.clone());
+        self.session = Some(Session {
+            dbg,
+            program,
+            path,
+            stop_on_entry: args.get("stopOnEntry").and_then(Json::as_bool).unwrap_or(false),
+        });
+        Ok(Json::Null)
+    }
+
+    fn set_breakpoints(&mut self, args: &Json) -> Result<Json, String> {
+        let s = self.session()?;
+        let requested: Vec<i64> = args.get("breakpoints")
+            .and_then(Json::as_array)
+            .map(|bps| bps.iter().filter_map(|b| b.get("line").and_then(Json::as_i64)).collect())
+            .unwrap_or_default();
+
+        s.dbg.breakpoints.clear();
+        let mut verified = Vec::new();
+        for line in requested {
+            let addr = s.program.address_of_line(line.max(0) as usize);
+            let bp = match addr {
+                Some(addr) => {
+                    s.dbg.add_breakpoint(addr);
+                    let actual = s.program.line_of_address(addr).unwrap_or(0) as i64;
+                    Json::object(vec![("verified", Json::from(true)), ("line", Json::from(actual))])
+                }
+                None => Json::object(vec![
+                    ("verified", Json::from(false)),
+                    ("line",     Json::from(line)),
+                    ("message",  Json::str("No instruction at or after this line.")),
+                ]),
+            };
+            verified.push(bp);
+        }
+        Ok(Json::object(vec![("breakpoints", Json::Array(verified))]))
+    }
+
+    fn stack_trace(&mut self) -> Result<Json, String> {
+        let s = self.session()?;
+        let source = Json::object(vec![("path", Json::str(&s.path))]);
+        let frames: Vec<Json> = s.dbg.backtrace()
+            .into_iter()
+            .enumerate()
+            .map(|(i, addr)| {
+                // Return addresses point after the CALL, show the CALL itself.
+                let site = if i == 0 { addr } else { addr.wrapping_sub(2) };
+                let name = s.dbg.symtab.symtab_lookup_address(site)
+                    .map(|f| f.label.clone())
+                    .unwrap_or_else(|| String::from("??"));
+                Json::object(vec![
+                    ("id",     Json::from(i as i64)),
+                    ("name",   Json::from(name)),
+                    ("source", source.clone()),
+                    ("line",   Json::from(s.program.line_of_address(site).unwrap_or(0) as i64)),
+                    ("column", Json::from(1)),
+                ])
+            })
+            .collect();
+        let total = frames.len() as i64;
+        Ok(Json::object(vec![("stackFrames", Json::Array(frames)), ("totalFrames", Json::from(total))]))
+    }
+
+    fn variables(&mut self, args: &Json) -> Result<Json, String> {
+        let s = self.session()?;
+        let machine = &s.dbg.machine;
+        let var = |name: String, value: String| Json::object(vec![
+            ("name",               Json::from(name)),
+            ("value",              Json::from(value)),
+            ("variablesReference", Json::from(0)),
+        ]);
+
+        let vars: Vec<Json> = match args.get("variablesReference").and_then(Json::as_i64) {
+            Some(REGISTERS_REF) => {
+                let mut v: Vec<Json> = (0..NUM_REGS)
+                    .map(|r| {
+                        let val = machine.state.registers[r];
+                        var(format!("r{}", r), format!("{} ({:#04x})", val, val))
+                    })
+                    .collect();
+                v.push(var(String::from("pc"), format!("{:#04x}", machine.state.pc)));
+                v
+            }
+            Some(STACK_REF) => machine.stack.stack[..machine.stack.top]
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(i, ret)| var(format!("[{}]", i), format!("{:#04x}", ret)))
+                .collect(),
+            Some(HEAP_REF) => machine.state.heap.blocks()
+                .into_iter()
+                .map(|b| var(
+                    format!("@{}", b.offset),
+                    format!("{} bytes, {}", b.size, if b.allocated { "allocated" } else { "free" }),
+                ))
+                .collect(),
+            _ => return Err(String::from("Error: Unknown variables reference.")),
+        };
+        Ok(Json::object(vec![("variables", Json::Array(vars))]))
+    }
+
+    // Runs one of the execution requests and reports where we stopped.
+    fn resume(&mut self, req: &Json, run: impl FnOnce(&mut Debugger) -> Result<StopReason, String>) -> io::Result<()> {
+        let reason = match self.session() {
+            Ok(s)  => run(&mut s.dbg),
+            Err(e) => Err(e),
+        };
+        match reason {
+            Ok(reason) => {
+                self.respond(req, Ok(Json::object(vec![("allThreadsContinued", Json::from(true))])))?;
+                self.report_stop(reason)
+            }
+            Err(e) => self.respond(req, Err(e)),
+        }
+    }
+
+    // Handles one request. Returns false when the client disconnected.
+    fn handle(&mut self, req: &Json) -> io::Result<bool> {
+        let no_args = Json::object(vec![]);
+        let args = req.get("arguments").unwrap_or(&no_args);
+
+        match req.get("command").and_then(Json::as_str).unwrap_or("") {
+            "initialize" => {
+                let caps = Json::object(vec![("supportsConfigurationDoneRequest", Json::from(true))]);
+                self.respond(req, Ok(caps))?;
+            }
+            "launch" => {
+                let res = self.launch(args);
+                let ok = res.is_ok();
+                self.respond(req, res)?;
+                if ok {
+                    self.event("initialized", Json::object(vec![]))?;
+                }
+            }
+            "setBreakpoints" => {
+                let res = self.set_breakpoints(args);
+                self.respond(req, res)?;
+            }
+            "configurationDone" => {
+                let stop_on_entry = match self.session() {
+                    Ok(s)  => s.stop_on_entry,
+                    Err(e) => {
+                        self.respond(req, Err(e))?;
+                        return Ok(true);
+                    }
+                };
+                self.respond(req, Ok(Json::Null))?;
+                if stop_on_entry {
+                    self.event("stopped", Json::object(vec![
+                        ("reason",   Json::str("entry")),
+                        ("threadId", Json::from(THREAD_ID)),
+                    ]))?;
+                } else {
+                    let reason = self.session().map(|s| s.dbg.cont()).unwrap_or(StopReason::Halted);
+                    self.report_stop(reason)?;
+                }
+            }
+            "threads" => {
+                let thread = Json::object(vec![("id", Json::from(THREAD_ID)), ("name", Json::str("main"))]);
+                self.respond(req, Ok(Json::object(vec![("threads", Json::Array(vec![thread]))])))?;
+            }
+            "stackTrace" => {
+                let res = self.stack_trace();
+                self.respond(req, res)?;
+            }
+            "scopes" => {
+                let scope = |name: &str, r: i64| Json::object(vec![
+                    ("name",               Json::str(name)),
+                    ("variablesReference", Json::from(r)),
+                    ("expensive",          Json::from(false)),
+                ]);
+                let scopes = vec![scope("Registers", REGISTERS_REF), scope("Stack", STACK_REF), scope("Heap", HEAP_REF)];
+                self.respond(req, Ok(Json::object(vec![("scopes", Json::Array(scopes))])))?;
+            }
+            "variables" => {
+                let res = self.variables(args);
+                self.respond(req, res)?;
+            }
+            "continue" => self.resume(req, |d| Ok(d.cont()))?,
+            "next"     => self.resume(req, |d| Ok(d.next()))?,
+            "stepIn"   => self.resume(req, |d| Ok(d.step()))?,
+            "stepOut"  => self.resume(req, |d| d.finish())?,
+            "disconnect" => {
+                self.respond(req, Ok(Json::Null))?;
+                return Ok(false);
+            }
+            other => {
+                self.respond(req, Err(format!("Error: Unsupported request '{}'.", other)))?;
+            }
+        }
+        Ok(true)
+    }
+
+    // Serves requests until the client disconnects or the input ends.
+    pub fn run(&mut self, mut input: impl BufRead) -> io::Result<()> {
+        while let Some(req) = read_message(&mut input)? {
+            if !self.handle(&req)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = "_START:
+    ADDI r1 3
+    CALL _DOUBLE
+    HLT
+
+_DOUBLE:
+    ADD r1 r1
+    RET
+";
+
+    // Frames a list of requests the way a client would send them.
+    fn script(requests: &[&str]) -> Vec<u8> {
+        let mut input = Vec::new();
+        for (i, r) in requests.iter().enumerate() {
+            let body = format!("{{\"seq\":{},\"type\":\"request\",{}}}", i + 1, r);
+            input.extend(format!("Content-Length: {}\r\n\r\n{}", body.len(), body).into_bytes());
+        }
+        input
+    }
+
+    fn run_script(requests: &[&str]) -> Vec<Json> {
+        let mut server = DapServer::new(Vec::new());
+        server.run(&script(requests)[..]).unwrap();
+
+        let mut out = &server.out[..];
+        let mut msgs = Vec::new();
+        while let Some(m) = read_message(&mut out).unwrap() {
+            msgs.push(m);
+        }
+        msgs
+    }
+
+    fn find<'a>(msgs: &'a [Json], kind: &str, name: &str) -> Vec<&'a Json> {
+        let key = if kind == "event" { "event" } else { "command" };
+        msgs.iter()
+            .filter(|m| m.get("type").and_then(Json::as_str) == Some(kind))
+            .filter(|m| m.get(key).and_then(Json::as_str) == Some(name))
+            .collect()
+    }
+
+    #[test]
+    fn test_json_roundtrip() {
+        let src = r#"{"a":[1,2.5,-3],"b":{"c":"x\"y\n"},"d":true,"e":null}"#;
+        let v = Json::parse(src).unwrap();
+        assert_eq!(v.get("b").and_then(|b| b.get("c")).and_then(Json::as_str), Some("x\"y\n"));
+        assert_eq!(Json::parse(&v.to_string()).unwrap(), v);
+    }
+
+    #[test]
+    fn test_breakpoint_session() {
+        let path = std::env::temp_dir().join("vm8bit_dap_test.txt");
+        std::fs::write(&path, PROGRAM).unwrap();
+        let path = path.to_str().unwrap().replace('\\', "\\\\");
+
+        let launch = format!(r#""command":"launch","arguments":{{"program":"{}"}}"#, path);
+        let set_bp = format!(
+            r#""command":"setBreakpoints","arguments":{{"source":{{"path":"{}"}},"breakpoints":[{{"line":6}}]}}"#,
+            path,
+        );
+        let msgs = run_script(&[
+            r#""command":"initialize","arguments":{}"#,
+            &launch,
+            &set_bp,
+            r#""command":"configurationDone""#,
+            r#""command":"stackTrace","arguments":{"threadId":1}"#,
+            r#""command":"variables","arguments":{"variablesReference":1}"#,
+            r#""command":"stepOut","arguments":{"threadId":1}"#,
+            r#""command":"continue","arguments":{"threadId":1}"#,
+            r#""command":"disconnect""#,
+        ]);
+
+        assert_eq!(find(&msgs, "event", "initialized").len(), 1);
+
+        // Line 6 is the label, the breakpoint moves to the ADD on line 7.
+        let bps = find(&msgs, "response", "setBreakpoints")[0];
+        let bp = &bps.get("body").unwrap().get("breakpoints").unwrap().as_array().unwrap()[0];
+        assert_eq!(bp.get("line").and_then(Json::as_i64), Some(7));
+
+        let stops = find(&msgs, "event", "stopped");
+        assert_eq!(stops[0].get("body").unwrap().get("reason").and_then(Json::as_str), Some("breakpoint"));
+        assert_eq!(stops[1].get("body").unwrap().get("reason").and_then(Json::as_str), Some("step"));
+
+        let trace = find(&msgs, "response", "stackTrace")[0];
+        let frames = trace.get("body").unwrap().get("stackFrames").unwrap().as_array().unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].get("name").and_then(Json::as_str), Some("_DOUBLE"));
+        assert_eq!(frames[1].get("line").and_then(Json::as_i64), Some(3));
+
+        let vars = find(&msgs, "response", "variables")[0];
+        let r1 = &vars.get("body").unwrap().get("variables").unwrap().as_array().unwrap()[1];
+        assert_eq!(r1.get("value").and_then(Json::as_str), Some("3 (0x03)"));
+
+        assert_eq!(find(&msgs, "event", "terminated").len(), 1);
+    }
+
+    #[test]
+    fn test_launch_missing_program() {
+        let msgs = run_script(&[r#""command":"launch","arguments":{}"#]);
+        let launch = find(&msgs, "response", "launch")[0];
+        assert_eq!(launch.get("success").and_then(Json::as_bool), Some(false));
+    }
+}
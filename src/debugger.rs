@@ -0,0 +1,535 @@
+// Interactive debugger around a Machine.
+// Reads commands line by line, so it can be driven from stdin or from a script in the tests.
+use std::io::{self, BufRead, Write};
+use crate::allocator::render_map;
+use crate::config::MachineConfig;
+use crate::cpu::cpu_state::{Machine, NUM_REGS};
+use crate::instruction_mapping::instruction_utils::disassemble;
+use crate::symtab::SymTab;
+use crate::trace::TraceLog;
+use crate::snapshot::{load_snapshot, save_snapshot};
+
+const CALL_UPCODE: u8 = 0xE;
+const MEM_DUMP_WIDTH: usize = 16;
+
+const HELP: &str = "\
+Commands:
+  break [addr|label]     (b)  Set a breakpoint, lists breakpoints without argument.
+  delete <addr|label>    (d)  Remove a breakpoint.
+  step [n]               (s)  Execute n instructions (default 1).
+  next                   (n)  Step, but run a CALL until it returns.
+  finish                 (f)  Run until the current function returns.
+  continue               (c)  Run until a breakpoint or HLT.
+  regs                   (r)  Dump the registers.
+  setreg <rN> <val>           Set a register.
+  mem <addr> [len]       (x)  Dump memory.
+  setmem <addr> <val>         Set a byte in memory.
+  bt                          Print a backtrace.
+  heap                        Draw the heap block by block, with usage statistics.
+  record                      Start recording, needed for rstep and goto.
+  rstep [n]              (rs) Step n instructions backwards (default 1).
+  goto <n>                    Jump to the state after n recorded instructions.
+  tracesave <file>            Write the recording to a file.
+  save <file>                 Save a snapshot of the machine.
+  load <file>                 Restore the machine from a snapshot.
+  help                   (h)  Print this message.
+  quit                   (q)  Exit the debugger.";
+
+// Why the debugger gave control back to the user.
+#[derive(Debug, PartialEq)]
+pub enum StopReason {
+    Stepped,
+    Breakpoint(u8),
+    Returned,
+    Halted,
+    Fault(String),
+}
+
+pub struct Debugger {
+    pub machine:     Machine,
+    pub symtab:      SymTab,
+    pub breakpoints: Vec<u8>,
+    pub trace:       Option<TraceLog>,
+    // Builds the config a loaded snapshot is restored into, so it gets the same devices.
+    pub config:      Box<dyn Fn() -> Result<MachineConfig, String>>,
+}
+
+impl Debugger {
+    pub fn new(mut machine: Machine, symtab: SymTab) -> Self {
+        // The instruction trace drowns the debugger output.
+        machine.state.verbose = false;
+        Self {
+            machine,
+            symtab,
+            breakpoints: Vec::new(),
+            trace:       None,
+            config:      Box::new(|| Ok(MachineConfig::default())),
+        }
+    }
+
+    // Parses an address, which is either a label from the symtab or a number (decimal or 0x hex).
+    pub fn resolve_address(&self, arg: &str) -> Result<u8, String> {
+        if arg.starts_with('_') {
+            return self.symtab.symtab_lookup(arg);
+        }
+        parse_number(arg)
+    }
+
+    // Formats an address as 'addr <label+offset>' if it is inside a function.
+    pub fn describe_address(&self, addr: u8) -> String {
+        match self.symtab.symtab_lookup_address(addr) {
+            Some(f) => format!("{:#04x} <{}+{}>", addr, f.label, addr - f.address),
+            None    => format!("{:#04x}", addr),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u8) {
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u8) -> Result<(), String> {
+        match self.breakpoints.iter().position(|b| *b == addr) {
+            Some(i) => {
+                self.breakpoints.remove(i);
+                Ok(())
+            }
+            None => Err(format!("Error: No breakpoint at {:#04x}.", addr)),
+        }
+    }
+
+    // Executes a single instruction.
+    pub fn step(&mut self) -> StopReason {
+        if !self.machine.state.running {
+            return StopReason::Halted;
+        }
+        let res = match &mut self.trace {
+            Some(t) => t.step(&mut self.machine),
+            None    => self.machine.step().map(|_| ()),
+        };
+        match res {
+            Err(e) => StopReason::Fault(e),
+            Ok(()) if self.machine.state.running => StopReason::Stepped,
+            Ok(()) => StopReason::Halted,
+        }
+    }
+
+    // Executes 'n' instructions backwards, stops early at the start of the recording.
+    pub fn step_back(&mut self, n: usize) -> Result<(), String> {
+        let trace = self.trace.as_mut().ok_or("Error: Not recording, use 'record' first.")?;
+        for _ in 0..n {
+            if !trace.step_back(&mut self.machine) {
+                return Err(String::from("Error: Reached the start of the recording."));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn goto(&mut self, count: usize) -> Result<(), String> {
+        let trace = self.trace.as_mut().ok_or("Error: Not recording, use 'record' first.")?;
+        trace.goto(&mut self.machine, count)
+    }
+
+    // Must be called when the state is edited by hand, the recorded future no longer happened.
+    fn state_edited(&mut self) {
+        if let Some(t) = &mut self.trace {
+            t.truncate();
+        }
+    }
+
+    // Runs until 'done' holds, a breakpoint is hit or the machine halts or faults.
+    // The instruction at the current pc is always executed, so a breakpoint we are
+    // standing on does not stop us again.
+    fn run_until(&mut self, done: impl Fn(&Machine) -> bool) -> StopReason {
+        loop {
+            let r = self.step();
+            if r != StopReason::Stepped {
+                return r;
+            }
+            if done(&self.machine) {
+                return StopReason::Returned;
+            }
+            if self.breakpoints.contains(&self.machine.state.pc) {
+                return StopReason::Breakpoint(self.machine.state.pc);
+            }
+        }
+    }
+
+    pub fn cont(&mut self) -> StopReason {
+        self.run_until(|_| false)
+    }
+
+    // Like step, but a CALL is executed until it returns to the next instruction.
+    pub fn next(&mut self) -> StopReason {
+        let instr = self.machine.peek_instruction();
+        if instr.0 >> 4 != CALL_UPCODE {
+            return self.step();
+        }
+        let depth    = self.machine.stack.top;
+        let ret_addr = self.machine.state.pc.wrapping_add(2);
+        match self.run_until(|m| m.stack.top == depth && m.state.pc == ret_addr) {
+            StopReason::Returned => StopReason::Stepped,
+            r => r,
+        }
+    }
+
+    // Runs until the current function returns to its caller.
+    pub fn finish(&mut self) -> Result<StopReason, String> {
+        let depth = self.machine.stack.top;
+        if depth == 0 {
+            return Err("Error: 'finish' is not meaningful in the outermost frame.".into());
+        }
+        Ok(self.run_until(|m| m.stack.top < depth))
+    }
+
+    // Returns the frames as addresses, innermost first.
+    // The first frame is the pc, the rest are the return addresses saved on the stack.
+    pub fn backtrace(&self) -> Vec<u8> {
+        let stack = &self.machine.stack;
+        let mut frames = vec![self.machine.state.pc];
+        frames.extend(stack.stack[..stack.top].iter().rev());
+        frames
+    }
+
+    fn print_location(&self, out: &mut impl Write) -> io::Result<()> {
+        let pc = self.machine.state.pc;
+        if !self.machine.state.running {
+            return writeln!(out, "Program halted at {}.", self.describe_address(pc.wrapping_sub(2)));
+        }
+        let instr = self.machine.peek_instruction();
+        writeln!(out, "=> {}: {}", self.describe_address(pc), disassemble(&instr))
+    }
+
+    fn print_stop(&self, reason: StopReason, out: &mut impl Write) -> io::Result<()> {
+        match reason {
+            StopReason::Breakpoint(addr) => writeln!(out, "Breakpoint at {}.", self.describe_address(addr))?,
+            StopReason::Fault(e)         => writeln!(out, "Fault: {}", e)?,
+            _ => (),
+        }
+        self.print_location(out)
+    }
+
+    fn print_registers(&self, out: &mut impl Write) -> io::Result<()> {
+        let regs = &self.machine.state.registers;
+        for row in (0..NUM_REGS).step_by(4) {
+            let line: Vec<String> = (row..row + 4)
+                .map(|r| format!("r{:<2} = {:#04x} ({:>3})", r, regs[r], regs[r]))
+                .collect();
+            writeln!(out, "{}", line.join("   "))?;
+        }
+        let ints = &self.machine.state.interrupts;
+        writeln!(out, "pc  = {:#04x}   sp = {}   ie = {}   mask = {:#010b}   pending = {:#010b}",
+            self.machine.state.pc, self.machine.stack.top, ints.enabled as u8, ints.mask, ints.pending)
+    }
+
+    fn print_memory(&self, start: usize, len: usize, out: &mut impl Write) -> io::Result<()> {
+        let mem = self.machine.bus.ram();
+        let end = (start + len).min(mem.len());
+        for row in (start..end).step_by(MEM_DUMP_WIDTH) {
+            let bytes: Vec<String> = mem[row..(row + MEM_DUMP_WIDTH).min(end)]
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            writeln!(out, "{:#06x}: {}", row, bytes.join(" "))?;
+        }
+        Ok(())
+    }
+
+    fn print_backtrace(&self, out: &mut impl Write) -> io::Result<()> {
+        for (i, addr) in self.backtrace().into_iter().enumerate() {
+            // Return addresses point after the CALL, so look up the CALL itself.
+            let site = if i == 0 { addr } else { addr.wrapping_sub(2) };
+            let name = match self.symtab.symtab_lookup_address(site) {
+                Some(f) => f.label.as_str(),
+                None    => "??",
+            };
+            writeln!(out, "#{} {:#04x} in {}", i, addr, name)?;
+        }
+        Ok(())
+    }
+
+    // Executes one command line. Returns false when the user wants to quit.
+    pub fn exec_command(&mut self, line: &str, out: &mut impl Write) -> io::Result<bool> {
+        let mut words = line.split_whitespace();
+        let cmd  = match words.next() {
+            Some(c) => c,
+            None    => return Ok(true),
+        };
+        let args: Vec<&str> = words.collect();
+
+        let res: Result<(), String> = match (cmd, args.as_slice()) {
+            ("break" | "b", []) => {
+                for b in &self.breakpoints {
+                    writeln!(out, "Breakpoint at {}", self.describe_address(*b))?;
+                }
+                Ok(())
+            }
+            ("break" | "b", [a]) => self.resolve_address(a).map(|addr| {
+                self.add_breakpoint(addr);
+                let _ = writeln!(out, "Breakpoint set at {}.", self.describe_address(addr));
+            }),
+            ("delete" | "d", [a]) => self.resolve_address(a).and_then(|addr| self.remove_breakpoint(addr)),
+            ("step" | "s", []) => {
+                let r = self.step();
+                self.print_stop(r, out)?;
+                Ok(())
+            }
+            ("step" | "s", [n]) => match n.parse::<usize>() {
+                Ok(n) => {
+                    let mut r = StopReason::Stepped;
+                    for _ in 0..n {
+                        r = self.step();
+                        if r != StopReason::Stepped {
+                            break;
+                        }
+                    }
+                    self.print_stop(r, out)?;
+                    Ok(())
+                }
+                Err(_) => Err(format!("Error: '{}' is not a step count.", n)),
+            },
+            ("next" | "n", []) => {
+                let r = self.next();
+                self.print_stop(r, out)?;
+                Ok(())
+            }
+            ("finish" | "f", []) => match self.finish() {
+                Ok(r) => {
+                    self.print_stop(r, out)?;
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            },
+            ("continue" | "c", []) => {
+                let r = self.cont();
+                self.print_stop(r, out)?;
+                Ok(())
+            }
+            ("regs" | "r", []) => {
+                self.print_registers(out)?;
+                Ok(())
+            }
+            ("setreg", [r, v]) => parse_register(r).and_then(|r| {
+                self.machine.state.registers[r] = parse_number(v)?;
+                self.state_edited();
+                Ok(())
+            }),
+            ("mem" | "x", [a]) => match parse_wide_number(a) {
+                Ok(a) => {
+                    self.print_memory(a, MEM_DUMP_WIDTH, out)?;
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            },
+            ("mem" | "x", [a, n]) => match (parse_wide_number(a), parse_wide_number(n)) {
+                (Ok(a), Ok(n)) => {
+                    self.print_memory(a, n, out)?;
+                    Ok(())
+                }
+                (Err(e), _) | (_, Err(e)) => Err(e),
+            },
+            ("setmem", [a, v]) => parse_wide_number(a).and_then(|a| {
+                let v = parse_number(v)?;
+                match self.machine.bus.ram_mut().get_mut(a) {
+                    Some(b) => { *b = v; }
+                    None    => return Err(format!("Error: Address {:#x} is outside memory.", a)),
+                }
+                self.state_edited();
+                Ok(())
+            }),
+            ("bt", []) => {
+                self.print_backtrace(out)?;
+                Ok(())
+            }
+            ("heap", []) => {
+                write!(out, "{}", render_map(self.machine.state.heap.as_ref()))?;
+                Ok(())
+            }
+            ("record", []) => {
+                if self.trace.is_none() {
+                    self.trace = Some(TraceLog::new());
+                }
+                writeln!(out, "Recording.")?;
+                Ok(())
+            }
+            ("rstep" | "rs", []) => self.step_back(1).and_then(|_| self.print_location(out).map_err(|e| e.to_string())),
+            ("rstep" | "rs", [n]) => match n.parse::<usize>() {
+                Ok(n)  => self.step_back(n).and_then(|_| self.print_location(out).map_err(|e| e.to_string())),
+                Err(_) => Err(format!("Error: '{}' is not a step count.", n)),
+            },
+            ("goto", [n]) => match n.parse::<usize>() {
+                Ok(n)  => self.goto(n).and_then(|_| self.print_location(out).map_err(|e| e.to_string())),
+                Err(_) => Err(format!("Error: '{}' is not an instruction count.", n)),
+            },
+            ("tracesave", [file]) => match &self.trace {
+                Some(t) => t.save(file).map_err(|e| e.to_string()),
+                None    => Err(String::from("Error: Not recording, use 'record' first.")),
+            },
+            ("save", [file]) => save_snapshot(&self.machine, file).map_err(|e| e.to_string()),
+            ("load", [file]) => match (self.config)().and_then(|c| load_snapshot(file, c).map_err(|e| e.to_string())) {
+                Ok(mut m) => {
+                    m.state.verbose = false;
+                    self.machine = m;
+                    // The recording belongs to the old machine.
+                    if self.trace.is_some() {
+                        self.trace = Some(TraceLog::new());
+                    }
+                    self.print_location(out)?;
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            },
+            ("help" | "h", []) => {
+                writeln!(out, "{}", HELP)?;
+                Ok(())
+            }
+            ("quit" | "q", []) => return Ok(false),
+            _ => Err(format!("Error: Unknown command or wrong arguments: '{}'. Try 'help'.", line.trim())),
+        };
+
+        if let Err(e) = res {
+            writeln!(out, "{}", e)?;
+        }
+        Ok(true)
+    }
+
+    // Runs the read-eval-print loop until 'quit' or end of input.
+    pub fn run_repl(&mut self, input: impl BufRead, out: &mut impl Write) -> io::Result<()> {
+        self.print_location(out)?;
+        write!(out, "(dbg) ")?;
+        out.flush()?;
+        for line in input.lines() {
+            if !self.exec_command(&line?, out)? {
+                break;
+            }
+            write!(out, "(dbg) ")?;
+            out.flush()?;
+        }
+        self.machine.finish();
+        Ok(())
+    }
+}
+
+// Parses a decimal or 0x prefixed hex number into a byte.
+fn parse_number(arg: &str) -> Result<u8, String> {
+    let n = parse_wide_number(arg)?;
+    u8::try_from(n).map_err(|_| format!("Error: '{}' does not fit in a byte.", arg))
+}
+
+fn parse_wide_number(arg: &str) -> Result<usize, String> {
+    let res = match arg.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None      => arg.parse::<usize>(),
+    };
+    res.map_err(|_| format!("Error: '{}' is not a number.", arg))
+}
+
+fn parse_register(arg: &str) -> Result<usize, String> {
+    match arg.strip_prefix('r').map(|r| r.parse::<usize>()) {
+        Some(Ok(r)) if r < NUM_REGS => Ok(r),
+        _ => Err(format!("Error: '{}' is not a register.", arg)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_source;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const PROGRAM: &str = "
+_START:
+    ADDI r1 3
+    CALL _DOUBLE
+    HLT
+_DOUBLE:
+    ADD r1 r1
+    RET
+";
+
+    fn debugger() -> Debugger {
+        let p = assemble_source(PROGRAM);
+        Debugger::new(Machine::new(p.mem), p.symtab)
+    }
+
+    #[test]
+    fn test_breakpoint_on_label() {
+        let mut dbg = debugger();
+        let addr = dbg.resolve_address("_DOUBLE").unwrap();
+        dbg.add_breakpoint(addr);
+        assert_eq!(dbg.cont(), StopReason::Breakpoint(6));
+        assert_eq!(dbg.backtrace(), vec![6, 4]);
+        assert_eq!(dbg.cont(), StopReason::Halted);
+        assert_eq!(dbg.machine.state.registers[1], 6);
+    }
+
+    #[test]
+    fn test_next_steps_over_call() {
+        let mut dbg = debugger();
+        dbg.step();
+        assert_eq!(dbg.next(), StopReason::Stepped);
+        assert_eq!(dbg.machine.state.pc, 4);
+        assert_eq!(dbg.machine.state.registers[1], 6);
+    }
+
+    #[test]
+    fn test_finish() {
+        let mut dbg = debugger();
+        assert!(dbg.finish().is_err());
+        dbg.step();
+        dbg.step();
+        assert_eq!(dbg.finish(), Ok(StopReason::Returned));
+        assert_eq!(dbg.machine.state.pc, 4);
+        assert_eq!(dbg.machine.stack.top, 0);
+    }
+
+    #[test]
+    fn test_reverse_step() {
+        let mut dbg = debugger();
+        dbg.trace = Some(TraceLog::new());
+        assert_eq!(dbg.cont(), StopReason::Halted);
+        assert_eq!(dbg.machine.state.registers[1], 6);
+
+        dbg.step_back(3).unwrap();
+        assert_eq!(dbg.machine.state.pc, 6);
+        assert_eq!(dbg.backtrace(), vec![6, 4]);
+        assert!(dbg.step_back(10).is_err());
+        assert_eq!(dbg.machine.state.pc, 0);
+
+        dbg.goto(3).unwrap();
+        assert_eq!(dbg.machine.state.registers[1], 6);
+    }
+
+    #[test]
+    fn test_read_int_shares_input_with_repl() {
+        use crate::console::SharedBuffer;
+        use crate::syscalls::{Syscalls, Unbuffered};
+
+        // The REPL and the program read the same input, like both reading stdin.
+        let p = assemble_source("_START:\n SYS 3\n HLT r1\n");
+        let mut dbg = Debugger::new(Machine::new(p.mem), p.symtab);
+        let input = SharedBuffer(Rc::new(RefCell::new(b"s\n5\nc\nq\n".to_vec())));
+        dbg.machine.syscalls = Syscalls::new(Box::new(Unbuffered::new(input.clone())), Box::new(SharedBuffer::default()));
+        let mut out = Vec::new();
+        dbg.run_repl(Unbuffered::new(input), &mut out).unwrap();
+        assert!(!dbg.machine.state.running);
+        assert_eq!(dbg.machine.state.exit_code, 5);
+    }
+
+    #[test]
+    fn test_repl_script() {
+        let mut dbg = debugger();
+        let script = "b _DOUBLE\nc\nsetreg r1 10\nsetmem 0x40 7\nc\nregs\nheap\nq\n";
+        let mut out = Vec::new();
+        dbg.run_repl(script.as_bytes(), &mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Breakpoint at 0x06 <_DOUBLE+0>."));
+        assert!(out.contains("Program halted"));
+        assert!(out.contains("0x0000..0x00fc 1 x 252 bytes free"));
+        assert_eq!(dbg.machine.state.registers[1], 20);
+        assert_eq!(dbg.machine.bus.ram()[0x40], 7);
+    }
+}
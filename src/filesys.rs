@@ -0,0 +1,399 @@
+// File system calls, only registered when the machine is given a host directory to work in (--fs-root).
+// Every path is relative to that directory, absolute paths and '..' are rejected, and so are
+// symlinks pointing out of it.
+//
+//    7 OPEN   Open the zero terminated path at address r1, r2 is one of the MODE_ values. The handle comes back in r1.
+//    8 READ   Read up to r3 bytes from handle r1 into the buffer at r2. The number of bytes read comes back in r1.
+//    9 WRITE  Write r3 bytes from the buffer at r2 to handle r1. The number of bytes written comes back in r1.
+//   10 SEEK   Move handle r1 to the position r2 (low byte) and r3 (high byte) from the start of the file.
+//   11 CLOSE  Close handle r1.
+//
+// The buffer of READ and WRITE is in VM memory when r4 is 0 and on the heap when r4 is 1, a heap buffer has
+// to be inside a single allocated block. Every call leaves one of the FS_ error codes in r2.
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use crate::syscalls::{SysContext, Syscalls};
+
+pub const SYS_OPEN: u8 = 7;
+pub const SYS_READ: u8 = 8;
+pub const SYS_WRITE: u8 = 9;
+pub const SYS_SEEK: u8 = 10;
+pub const SYS_CLOSE: u8 = 11;
+
+pub const MODE_READ: u8 = 0;
+pub const MODE_WRITE: u8 = 1;  // Creates the file, or truncates it.
+pub const MODE_APPEND: u8 = 2; // Creates the file, writes go to the end.
+pub const MODE_UPDATE: u8 = 3; // Reads and writes an existing file.
+
+pub const FS_OK: u8 = 0;
+pub const FS_NOT_FOUND: u8 = 1;
+pub const FS_DENIED: u8 = 2;      // The path leaves the root directory, or the host refused.
+pub const FS_BAD_PATH: u8 = 3;
+pub const FS_BAD_HANDLE: u8 = 4;
+pub const FS_BAD_MODE: u8 = 5;
+pub const FS_TOO_MANY: u8 = 6;
+pub const FS_BAD_BUFFER: u8 = 7;
+pub const FS_IO_ERROR: u8 = 8;
+
+pub const MAX_FILES: usize = 8;
+const MAX_PATH: usize = 64;
+
+const BUFFER_MEMORY: u8 = 0;
+const BUFFER_HEAP: u8 = 1;
+
+// Handle n is files[n - 1], so a handle is never 0.
+struct FileTable {
+    root:  PathBuf,
+    files: [Option<File>; MAX_FILES],
+}
+
+type FsResult<T> = Result<T, u8>;
+type FsCall = fn(&mut FileTable, &mut SysContext) -> FsResult<u8>;
+
+fn io_error(e: std::io::Error) -> u8 {
+    match e.kind() {
+        std::io::ErrorKind::NotFound         => FS_NOT_FOUND,
+        std::io::ErrorKind::PermissionDenied => FS_DENIED,
+        _                                    => FS_IO_ERROR,
+    }
+}
+
+impl FileTable {
+    // Joins a path from the program to the root, refusing anything that could end up outside of it.
+    fn resolve(&self, path: &str) -> FsResult<PathBuf> {
+        let rel = Path::new(path);
+        if path.is_empty() {
+            return Err(FS_BAD_PATH);
+        }
+        if !rel.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+            return Err(FS_DENIED);
+        }
+        let full = self.root.join(rel);
+
+        // A symlink inside the root could still point out of it. The file may not exist yet, then its directory is checked.
+        // exists() follows links, a dangling link would look like a new file and opening it would create the target.
+        // So anything that is there, even a link to nothing, is resolved itself, and a link that can't be is refused.
+        let real = match full.symlink_metadata() {
+            Ok(m)  => full.canonicalize().map_err(|e| if m.file_type().is_symlink() { FS_DENIED } else { io_error(e) })?,
+            Err(_) => full.parent().ok_or(FS_BAD_PATH)?.canonicalize().map_err(io_error)?,
+        };
+        if !real.starts_with(&self.root) {
+            return Err(FS_DENIED);
+        }
+        Ok(full)
+    }
+
+    fn open(&mut self, path: &str, mode: u8) -> FsResult<u8> {
+        let mut opts = OpenOptions::new();
+        match mode {
+            MODE_READ   => opts.read(true),
+            MODE_WRITE  => opts.write(true).create(true).truncate(true),
+            MODE_APPEND => opts.append(true).create(true),
+            MODE_UPDATE => opts.read(true).write(true),
+            _           => return Err(FS_BAD_MODE),
+        };
+        let slot = self.files.iter().position(|f| f.is_none()).ok_or(FS_TOO_MANY)?;
+        let full = self.resolve(path)?;
+        if full.is_dir() {
+            return Err(FS_BAD_PATH);
+        }
+        self.files[slot] = Some(opts.open(full).map_err(io_error)?);
+        Ok(slot as u8 + 1)
+    }
+
+    fn file(&mut self, handle: u8) -> FsResult<&mut File> {
+        (handle as usize)
+            .checked_sub(1)
+            .and_then(|i| self.files.get_mut(i))
+            .and_then(|f| f.as_mut())
+            .ok_or(FS_BAD_HANDLE)
+    }
+
+    fn close(&mut self, handle: u8) -> FsResult<()> {
+        self.file(handle)?;
+        self.files[handle as usize - 1] = None;
+        Ok(())
+    }
+}
+
+// Reads the zero terminated path at 'addr' in VM memory.
+fn read_path(ctx: &mut SysContext, addr: u8) -> FsResult<String> {
+    let mut bytes = Vec::new();
+    let mut addr = addr as usize;
+    loop {
+        let c = ctx.bus.read(addr).map_err(|_| FS_BAD_BUFFER)?;
+        if c == 0 {
+            break;
+        }
+        if bytes.len() == MAX_PATH {
+            return Err(FS_BAD_PATH);
+        }
+        bytes.push(c);
+        addr += 1;
+    }
+    String::from_utf8(bytes).map_err(|_| FS_BAD_PATH)
+}
+
+// Checks that a heap buffer lies inside one allocated block.
+// An empty buffer is never touched, so like in store_buffer it can be anywhere.
+fn check_heap_buffer(ctx: &SysContext, addr: usize, len: usize) -> FsResult<()> {
+    if len == 0 {
+        return Ok(());
+    }
+    ctx.state.heap.read_bytes(addr, len).map(|_| ()).map_err(|_| FS_BAD_BUFFER)
+}
+
+// The program's buffer for a READ or WRITE: address, length and where it lives.
+fn buffer(ctx: &SysContext) -> FsResult<(usize, usize, u8)> {
+    let regs = &ctx.state.registers;
+    let (addr, len, space) = (regs[2] as usize, regs[3] as usize, regs[4]);
+    match space {
+        BUFFER_MEMORY => Ok((addr, len, space)),
+        BUFFER_HEAP   => check_heap_buffer(ctx, addr, len).map(|_| (addr, len, space)),
+        _             => Err(FS_BAD_BUFFER),
+    }
+}
+
+fn load_buffer(ctx: &mut SysContext, addr: usize, len: usize, space: u8) -> FsResult<Vec<u8>> {
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    if space == BUFFER_HEAP {
+        return ctx.state.heap.read_bytes(addr, len).map_err(|_| FS_BAD_BUFFER);
+    }
+    (addr..addr + len).map(|a| ctx.bus.read(a).map_err(|_| FS_BAD_BUFFER)).collect()
+}
+
+fn store_buffer(ctx: &mut SysContext, addr: usize, data: &[u8], space: u8) -> FsResult<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    if space == BUFFER_HEAP {
+        return ctx.state.heap.write_bytes(addr, data).map_err(|_| FS_BAD_BUFFER);
+    }
+    for (i, b) in data.iter().enumerate() {
+        ctx.bus.write(addr + i, *b).map_err(|_| FS_BAD_BUFFER)?;
+    }
+    Ok(())
+}
+
+fn fs_open(table: &mut FileTable, ctx: &mut SysContext) -> FsResult<u8> {
+    let path = read_path(ctx, ctx.state.registers[1])?;
+    table.open(&path, ctx.state.registers[2])
+}
+
+fn fs_read(table: &mut FileTable, ctx: &mut SysContext) -> FsResult<u8> {
+    let (addr, len, space) = buffer(ctx)?;
+    let file = table.file(ctx.state.registers[1])?;
+    let mut data = vec![0; len];
+    let mut n = 0;
+    while n < len {
+        match file.read(&mut data[n..]).map_err(io_error)? {
+            0 => break,
+            read => n += read,
+        }
+    }
+    store_buffer(ctx, addr, &data[..n], space)?;
+    Ok(n as u8)
+}
+
+fn fs_write(table: &mut FileTable, ctx: &mut SysContext) -> FsResult<u8> {
+    let (addr, len, space) = buffer(ctx)?;
+    let data = load_buffer(ctx, addr, len, space)?;
+    let file = table.file(ctx.state.registers[1])?;
+    file.write_all(&data).map_err(io_error)?;
+    Ok(len as u8)
+}
+
+fn fs_seek(table: &mut FileTable, ctx: &mut SysContext) -> FsResult<u8> {
+    let regs = ctx.state.registers;
+    let pos = u16::from_le_bytes([regs[2], regs[3]]);
+    table.file(regs[1])?.seek(SeekFrom::Start(pos as u64)).map_err(io_error)?;
+    Ok(regs[1])
+}
+
+fn fs_close(table: &mut FileTable, ctx: &mut SysContext) -> FsResult<u8> {
+    table.close(ctx.state.registers[1])?;
+    Ok(0)
+}
+
+// Adds the file system calls to 'sys', confined to the directory 'root'.
+pub fn register(sys: &mut Syscalls, root: &str) -> Result<(), String> {
+    let root = Path::new(root)
+        .canonicalize()
+        .map_err(|e| format!("Error: File system root {}: {}", root, e))?;
+    if !root.is_dir() {
+        return Err(format!("Error: File system root {} is not a directory.", root.display()));
+    }
+    let table = Rc::new(RefCell::new(FileTable { root, files: Default::default() }));
+
+    // Each call puts its result in r1 and its error code in r2, on an error r1 is 0.
+    let calls: [(u8, FsCall); 5] = [
+        (SYS_OPEN,  fs_open),
+        (SYS_READ,  fs_read),
+        (SYS_WRITE, fs_write),
+        (SYS_SEEK,  fs_seek),
+        (SYS_CLOSE, fs_close),
+    ];
+    for (n, call) in calls {
+        let table = Rc::clone(&table);
+        sys.register(n, Box::new(move |ctx: &mut SysContext| {
+            let (res, err) = match call(&mut table.borrow_mut(), ctx) {
+                Ok(v)  => (v, FS_OK),
+                Err(e) => (0, e),
+            };
+            ctx.state.registers[1] = res;
+            ctx.state.registers[2] = err;
+            Ok(())
+        }));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_source;
+    use crate::cpu::cpu_state::Machine;
+
+    fn root(name: &str) -> String {
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.to_str().unwrap().to_string()
+    }
+
+    fn machine(src: &str, root: &str, path: &str) -> Machine {
+        let mut m = Machine::new(assemble_source(src).mem);
+        m.state.verbose = false;
+        register(&mut m.syscalls, root).unwrap();
+        m.bus.ram_mut()[0x80..0x80 + path.len()].copy_from_slice(path.as_bytes());
+        m
+    }
+
+    fn run(m: &mut Machine) {
+        while m.state.running {
+            m.step().unwrap();
+        }
+    }
+
+    // Writes the 3 bytes at 160 to the file, seeks back to 1 and reads 2 bytes into 176.
+    const PROGRAM: &str = "
+_START:
+    ADDI r1 128
+    ADDI r2 3
+    SYS 7
+    MOV r7 r1
+    MOV r8 r2
+    ADDI r2 160
+    ADDI r3 3
+    SYS 9
+    MOV r9 r2
+    MOV r1 r7
+    LDI r2 1
+    LDI r3 0
+    SYS 10
+    MOV r1 r7
+    LDI r2 176
+    LDI r3 2
+    SYS 8
+    MOV r10 r1
+    MOV r1 r7
+    SYS 11
+    HLT
+";
+
+    #[test]
+    fn test_write_seek_read() {
+        let dir = root("vm8bit_fs_rw");
+        std::fs::write(format!("{}/data.bin", dir), b"").unwrap();
+        let mut m = machine(PROGRAM, &dir, "data.bin");
+        m.bus.ram_mut()[160..163].copy_from_slice(b"abc");
+        run(&mut m);
+        assert_eq!((m.state.registers[7], m.state.registers[8], m.state.registers[9]), (1, FS_OK, FS_OK));
+        assert_eq!(m.state.registers[10], 2);
+        assert_eq!(&m.bus.ram()[176..178], b"bc");
+        assert_eq!(m.state.registers[2], FS_OK);
+        assert_eq!(std::fs::read(format!("{}/data.bin", dir)).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn test_path_traversal_rejected() {
+        let dir = root("vm8bit_fs_escape");
+        let src = "_START:\n ADDI r1 128\n SYS 7\n HLT\n";
+        for path in ["../secret", "/etc/passwd", "a/../../b"] {
+            let mut m = machine(src, &dir, path);
+            run(&mut m);
+            assert_eq!((m.state.registers[1], m.state.registers[2]), (0, FS_DENIED), "{}", path);
+        }
+
+        let mut m = machine(src, &dir, "missing.txt");
+        run(&mut m);
+        assert_eq!(m.state.registers[2], FS_NOT_FOUND);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_dangling_symlink_rejected() {
+        let dir = root("vm8bit_fs_dangling");
+        let outside = std::env::temp_dir().join("vm8bit_fs_dangling_target");
+        let _ = std::fs::remove_file(&outside);
+        std::os::unix::fs::symlink(&outside, format!("{}/evil", dir)).unwrap();
+
+        // Opening for writing would create the target outside of the root.
+        let mut m = machine("_START:\n ADDI r1 128\n ADDI r2 1\n SYS 7\n HLT\n", &dir, "evil");
+        run(&mut m);
+        assert_eq!((m.state.registers[1], m.state.registers[2]), (0, FS_DENIED));
+        assert!(!outside.exists());
+    }
+
+    #[test]
+    fn test_heap_buffer_and_bad_handle() {
+        let dir = root("vm8bit_fs_heap");
+        std::fs::write(format!("{}/in.txt", dir), b"hey").unwrap();
+        // Reads into a 4 byte heap block, then tries to read 8 bytes, which overflows it.
+        // Reading and writing nothing is fine, even with a buffer that isn't in a block.
+        let src = "
+_START:
+    ADDI r5 4
+    ALC r6 r5
+    ADDI r1 128
+    SYS 7
+    MOV r7 r1
+    MOV r2 r6
+    ADDI r3 3
+    ADDI r4 1
+    SYS 8
+    MOV r8 r1
+    MOV r1 r7
+    MOV r2 r6
+    ADDI r3 5
+    SYS 8
+    MOV r9 r2
+    MOV r1 r7
+    SUB r2 r2
+    SUB r3 r3
+    SYS 8
+    MOV r10 r2
+    MOV r1 r7
+    SUB r2 r2
+    SYS 9
+    MOV r11 r2
+    ADDI r1 5
+    SYS 11
+    HLT
+";
+        let mut m = machine(src, &dir, "in.txt");
+        run(&mut m);
+        let ptr = m.state.registers[6] as usize;
+        assert_eq!(m.state.registers[8], 3);
+        assert_eq!(&m.state.heap.memory()[ptr..ptr + 3], b"hey");
+        assert_eq!(m.state.registers[9], FS_BAD_BUFFER);
+        assert_eq!(m.state.registers[10], FS_OK);
+        assert_eq!(m.state.registers[11], FS_OK);
+        assert_eq!(m.state.registers[2], FS_BAD_HANDLE);
+    }
+}
@@ -0,0 +1,225 @@
+// Framebuffer device, 32x32 pixels of one byte each. The address space is far too small to map the pixels,
+// so they are drawn through ports:
+//   +0 X      Column of the next pixel.
+//   +1 Y      Row of the next pixel.
+//   +2 COLOR  Writing sets the pixel at X, Y and moves X to the next pixel, wrapping to the next row.
+//             Reading returns the pixel at X, Y.
+//   +3 VSYNC  Writing presents the frame: it is written as a PPM image or drawn on the terminal.
+//
+// A pixel is a color in RRRGGGBB form.
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use crate::bus::Device;
+
+pub const WIDTH: usize = 32;
+pub const HEIGHT: usize = 32;
+
+const X_PORT: usize = 0;
+const Y_PORT: usize = 1;
+const COLOR_PORT: usize = 2;
+const VSYNC_PORT: usize = 3;
+
+// Where presented frames go.
+pub enum FrameOutput {
+    None,
+    Ppm(String),            // Files named <prefix>_<frame number>.ppm
+    Ansi(Box<dyn Write>),   // Drawn with half block characters, two pixel rows per line.
+}
+
+pub struct Framebuffer {
+    pixels:     [u8; WIDTH * HEIGHT],
+    x:          u8,
+    y:          u8,
+    output:     FrameOutput,
+    frames:     usize,  // Number of frames presented.
+}
+
+// Expands a RRRGGGBB color to 8 bits per channel.
+pub fn palette(c: u8) -> [u8; 3] {
+    let scale = |v: u8, max: u16| (v as u16 * 255 / max) as u8;
+    [scale((c >> 5) & 0x7, 7), scale((c >> 2) & 0x7, 7), scale(c & 0x3, 3)]
+}
+
+// A frame as a binary PPM (P6) image.
+pub fn to_ppm(pixels: &[u8]) -> Vec<u8> {
+    let mut out = format!("P6\n{} {}\n255\n", WIDTH, HEIGHT).into_bytes();
+    for p in pixels {
+        out.extend_from_slice(&palette(*p));
+    }
+    out
+}
+
+// A frame as lines of colored half blocks, the upper pixel is the foreground and the lower the background.
+pub fn to_ansi(pixels: &[u8]) -> String {
+    let mut out = String::new();
+    for row in (0..HEIGHT).step_by(2) {
+        for col in 0..WIDTH {
+            let [r, g, b] = palette(pixels[row * WIDTH + col]);
+            let [br, bg, bb] = palette(pixels[(row + 1) * WIDTH + col]);
+            out += &format!("\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m\u{2580}", r, g, b, br, bg, bb);
+        }
+        out += "\x1b[0m\n";
+    }
+    out
+}
+
+impl Framebuffer {
+    pub fn new(output: FrameOutput) -> Self {
+        Self {
+            pixels: [0; WIDTH * HEIGHT],
+            x:      0,
+            y:      0,
+            output,
+            frames: 0,
+        }
+    }
+
+    fn index(&self) -> usize {
+        (self.y as usize % HEIGHT) * WIDTH + self.x as usize % WIDTH
+    }
+
+    fn present(&mut self) -> io::Result<()> {
+        match &mut self.output {
+            FrameOutput::None => (),
+            FrameOutput::Ppm(prefix) => {
+                let mut w = BufWriter::new(File::create(format!("{}_{:04}.ppm", prefix, self.frames))?);
+                w.write_all(&to_ppm(&self.pixels))?;
+                w.flush()?;
+            }
+            FrameOutput::Ansi(w) => {
+                w.write_all(to_ansi(&self.pixels).as_bytes())?;
+                w.flush()?;
+            }
+        }
+        self.frames += 1;
+        Ok(())
+    }
+}
+
+impl Device for Framebuffer {
+    fn name(&self) -> &'static str {
+        "framebuffer"
+    }
+
+    fn size(&self) -> usize {
+        4
+    }
+
+    fn read(&mut self, offset: usize) -> u8 {
+        match offset {
+            X_PORT     => self.x,
+            Y_PORT     => self.y,
+            COLOR_PORT => self.pixels[self.index()],
+            _          => 0,
+        }
+    }
+
+    fn write(&mut self, offset: usize, val: u8) {
+        match offset {
+            X_PORT => self.x = val % WIDTH as u8,
+            Y_PORT => self.y = val % HEIGHT as u8,
+            COLOR_PORT => {
+                let i = self.index();
+                self.pixels[i] = val;
+                self.x += 1;
+                if self.x as usize == WIDTH {
+                    self.x = 0;
+                    self.y = (self.y + 1) % HEIGHT as u8;
+                }
+            }
+            VSYNC_PORT => {
+                // The program can't be told about a failed write, so it is only reported.
+                if let Err(e) = self.present() {
+                    eprintln!("Error: Presenting frame {}: {}", self.frames, e);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_source;
+    use crate::bus::Mapping;
+    use crate::config::MachineConfig;
+    use crate::console::SharedBuffer;
+    use crate::cpu::cpu_state::Machine;
+
+    #[test]
+    fn test_palette() {
+        assert_eq!(palette(0x00), [0, 0, 0]);
+        assert_eq!(palette(0xFF), [255, 255, 255]);
+        assert_eq!(palette(0b1110_0000), [255, 0, 0]);
+        assert_eq!(palette(0b0000_0011), [0, 0, 255]);
+    }
+
+    #[test]
+    fn test_color_port_advances() {
+        let mut fb = Framebuffer::new(FrameOutput::None);
+        fb.write(X_PORT, 31);
+        fb.write(Y_PORT, 2);
+        fb.write(COLOR_PORT, 7);
+        fb.write(COLOR_PORT, 9);
+        assert_eq!(fb.pixels[2 * WIDTH + 31], 7);
+        assert_eq!(fb.pixels[3 * WIDTH], 9);
+        assert_eq!((fb.read(X_PORT), fb.read(Y_PORT)), (1, 3));
+        fb.write(X_PORT, 0);
+        assert_eq!(fb.read(COLOR_PORT), 9);
+    }
+
+    // Draws a red pixel at (1, 1) and a blue one after it, then presents the frame.
+    const PROGRAM: &str = "
+_START:
+    ADDI r1 240
+    ADDI r2 241
+    ADDI r3 242
+    ADDI r4 243
+    ADDI r5 1
+    ST r1 r5
+    ST r2 r5
+    ADDI r6 224
+    ST r3 r6
+    ADDI r7 3
+    ST r3 r7
+    ST r4 r0
+    HLT
+";
+
+    fn golden_frame() -> Vec<u8> {
+        let mut expected = b"P6\n32 32\n255\n".to_vec();
+        let mut pixels = vec![[0u8; 3]; WIDTH * HEIGHT];
+        pixels[WIDTH + 1] = [255, 0, 0];
+        pixels[WIDTH + 2] = [0, 0, 255];
+        expected.extend(pixels.concat());
+        expected
+    }
+
+    #[test]
+    fn test_ppm_golden_image() {
+        let prefix = std::env::temp_dir().join("vm8bit_fb_test");
+        let prefix = prefix.to_str().unwrap().to_string();
+        let mut config = MachineConfig::default();
+        config.devices.push(Mapping::new(240, Box::new(Framebuffer::new(FrameOutput::Ppm(prefix.clone())))));
+        let mut m = Machine::from_config(assemble_source(PROGRAM).mem, config).unwrap();
+        m.state.verbose = false;
+        while m.state.running {
+            m.step().unwrap();
+        }
+        let frame = std::fs::read(format!("{}_0000.ppm", prefix)).unwrap();
+        assert_eq!(frame, golden_frame());
+    }
+
+    #[test]
+    fn test_ansi_output() {
+        let out = SharedBuffer::default();
+        let mut fb = Framebuffer::new(FrameOutput::Ansi(Box::new(out.clone())));
+        fb.write(COLOR_PORT, 0xFF);
+        fb.write(VSYNC_PORT, 0);
+        let text = String::from_utf8(out.contents()).unwrap();
+        assert_eq!(text.lines().count(), HEIGHT / 2);
+        assert!(text.starts_with("\x1b[38;2;255;255;255m\x1b[48;2;0;0;0m\u{2580}"));
+        assert_eq!(fb.frames, 1);
+    }
+}
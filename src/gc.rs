@@ -0,0 +1,131 @@
+// Conservative mark-and-sweep garbage collector for the VM heap.
+// Programs that run with it on don't have to FREE, blocks nothing points to any more are freed for them.
+//
+// There are no types, so every byte that could be a pointer is taken to be one:
+//   - The roots are the registers and everything on the stack.
+//   - A value anywhere in the payload of an allocated block keeps that block alive.
+//   - Every byte in the payload of a live block is a value too, so pointers stored on the heap are followed.
+// A pointer kept only in RAM is not a root, ST a pointer there and the block can be collected under it.
+// Something that only looks like a pointer keeps a block alive for nothing, which is the price of being
+// conservative, but never frees a block that is still used.
+//
+// The unreachable blocks are freed through the allocator, which coalesces them like any FREE.
+use crate::allocator::Allocator;
+use crate::yoloheap::BlockInfo;
+
+// The allocated block whose payload holds 'addr'.
+fn block_at(heap: &dyn Allocator, blocks: &[BlockInfo], addr: usize) -> Option<usize> {
+    blocks.iter().position(|b| {
+        b.allocated && addr >= b.offset + heap.header_size() && addr < b.offset + b.size - heap.footer_size()
+    })
+}
+
+// Frees every allocated block that can't be reached from 'roots', returns how many were freed.
+pub fn collect(heap: &mut dyn Allocator, roots: &[u8]) -> Result<usize, String> {
+    let blocks = heap.blocks();
+    let mut marked = vec![false; blocks.len()];
+    let mut work: Vec<u8> = roots.to_vec();
+
+    // Mark.
+    while let Some(value) = work.pop() {
+        let i = match block_at(heap, &blocks, value as usize) {
+            Some(i) if !marked[i] => i,
+            _ => continue,
+        };
+        marked[i] = true;
+        let b = &blocks[i];
+        work.extend_from_slice(&heap.memory()[b.offset + heap.header_size()..b.offset + b.size - heap.footer_size()]);
+    }
+
+    // Sweep.
+    let mut freed = 0;
+    for (b, _) in blocks.iter().zip(&marked).filter(|(b, m)| b.allocated && !**m) {
+        let ptr = heap.pointer_of(b);
+        heap.free(ptr)?;
+        freed += 1;
+    }
+    Ok(freed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::{create, AllocatorKind};
+    use crate::assembler::assemble_source;
+    use crate::config::MachineConfig;
+    use crate::cpu::cpu_state::Machine;
+    use crate::yoloheap::HeaderWidth;
+
+    #[test]
+    fn test_collect() {
+        for kind in [AllocatorKind::FirstFit, AllocatorKind::Buddy, AllocatorKind::Slab(8)] {
+            let mut heap = create(kind, HeaderWidth::Narrow);
+            let a = heap.allocate(4).unwrap();
+            let b = heap.allocate(4).unwrap();
+            let c = heap.allocate(4).unwrap();
+            let _garbage = heap.allocate(4).unwrap();
+
+            // a -> b, c is only reachable through a pointer into its middle.
+            heap.write_bytes(a, &[b as u8]).unwrap();
+            assert_eq!(collect(heap.as_mut(), &[a as u8, c as u8 + 2]), Ok(1), "{:?}", kind);
+            assert_eq!(heap.stats().allocated_blocks, 3, "{:?}", kind);
+
+            assert_eq!(collect(heap.as_mut(), &[]), Ok(3), "{:?}", kind);
+            assert_eq!(heap.stats().allocated_blocks, 0, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn test_cycle_is_collected() {
+        let mut heap = create(AllocatorKind::FirstFit, HeaderWidth::Narrow);
+        let a = heap.allocate(4).unwrap();
+        let b = heap.allocate(4).unwrap();
+        heap.write_bytes(a, &[b as u8]).unwrap();
+        heap.write_bytes(b, &[a as u8]).unwrap();
+        assert_eq!(collect(heap.as_mut(), &[]), Ok(2));
+        assert_eq!(heap.stats().free_blocks, 1);
+    }
+
+    fn run(src: &str, config: &str) -> Machine {
+        let mut m = Machine::from_config(assemble_source(src).mem, MachineConfig::parse(config).unwrap()).unwrap();
+        m.state.verbose = false;
+        while m.state.running {
+            m.step().unwrap();
+        }
+        m
+    }
+
+    // Allocates 60 bytes five times, keeping only the last pointer. Three blocks fit on the heap.
+    // The small numbers in the registers all point into the first block, so that one is never garbage.
+    const LOOP: &str = "
+_START:
+    LDI r5 20
+    LDI r6 12
+    ADDI r1 60
+    ADDI r3 5
+    ADDI r7 1
+_LOOP:
+    ALC r2 r1
+    SUB r3 r7
+    JMPZ r5 r3
+    JMPZ r6 r0
+    HLT
+";
+
+    #[test]
+    fn test_gc_on_allocation_failure() {
+        let m = run(LOOP, "");
+        assert_eq!(m.state.registers[2], 0);
+
+        let m = run(LOOP, "gc\n");
+        assert_eq!(m.state.registers[2], 129);
+        assert_eq!(m.state.heap.stats().allocated_blocks, 3);
+    }
+
+    #[test]
+    fn test_gc_instruction() {
+        let m = run("_START:\n ADDI r1 4\n ALC r2 r1\n ALC r3 r1\n LDI r3 0\n GC r4\n HLT\n", "");
+        assert_eq!(m.state.registers[4], 1);
+        assert_eq!(m.state.heap.stats().allocated_blocks, 1);
+    }
+}
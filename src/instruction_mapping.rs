@@ -2,6 +2,14 @@ pub mod instruction_utils {
     use crate::cpu::cpu_state::NUM_REGS;
 
 
+    // All 16 upcodes are taken, so the system instructions share the upcode of HLT.
+    // They are told apart by the funct, the high nibble of the second byte.
+    // The low nibble of the second byte is then the second register.
+    pub const SYSTEM_UPCODE: u8 = 0xF;
+
+    // HLT has no funct bits to spare, 'HLT r' is told apart from plain HLT by a 1 in the rs nibble.
+    pub const HLT_WITH_STATUS: u8 = 1;
+
     pub enum InstructionNameMap {
         Instruction(u8),
         Label(String),
@@ -25,6 +33,18 @@ pub mod instruction_utils {
             "RET"   => Ok(InstructionNameMap::Instruction(0b1101_0000)),   // 208
             "CALL"  => Ok(InstructionNameMap::Instruction(0b1110_0000)),   // 224
             "HLT"   => Ok(InstructionNameMap::Instruction(0b1111_0000)),  // 240
+            "ALC"   => Ok(InstructionNameMap::Instruction(0b1111_0000)),  // 240, system instruction.
+            "FREE"  => Ok(InstructionNameMap::Instruction(0b1111_0000)),  // 240, system instruction.
+            "WRH"   => Ok(InstructionNameMap::Instruction(0b1111_0000)),  // 240, system instruction.
+            "EI"    => Ok(InstructionNameMap::Instruction(0b1111_0000)),  // 240, system instruction.
+            "DI"    => Ok(InstructionNameMap::Instruction(0b1111_0000)),  // 240, system instruction.
+            "IRET"  => Ok(InstructionNameMap::Instruction(0b1111_0000)),  // 240, system instruction.
+            "IMASK" => Ok(InstructionNameMap::Instruction(0b1111_0000)),  // 240, system instruction.
+            "IPEND" => Ok(InstructionNameMap::Instruction(0b1111_0000)),  // 240, system instruction.
+            "SYS"   => Ok(InstructionNameMap::Instruction(0b1111_0000)),  // 240, system instruction.
+            "RALC"  => Ok(InstructionNameMap::Instruction(0b1111_0000)),  // 240, system instruction.
+            "RDH"   => Ok(InstructionNameMap::Instruction(0b1111_0000)),  // 240, system instruction.
+            "GC"    => Ok(InstructionNameMap::Instruction(0b1111_0000)),  // 240, system instruction.
             _ => {
                 if name.ends_with(':') {
                     let lab = name.trim_end_matches(':');
@@ -36,6 +56,100 @@ pub mod instruction_utils {
         }
     }
 
+    // Returns the funct (already shifted into the high nibble) of a system instruction, 0 for everything else.
+    pub fn get_funct(name: &str) -> u8 {
+        match name {
+            "ALC"   => 0b0001_0000,
+            "FREE"  => 0b0010_0000,
+            "WRH"   => 0b0011_0000,
+            "EI"    => 0b0100_0000,
+            "DI"    => 0b0101_0000,
+            "IRET"  => 0b0110_0000,
+            "IMASK" => 0b0111_0000,
+            "IPEND" => 0b1000_0000,
+            "SYS"   => 0b1001_0000,
+            "RALC"  => 0b1010_0000,
+            "RDH"   => 0b1011_0000,
+            "GC"    => 0b1100_0000,
+            _       => 0,
+        }
+    }
+
+    // Inverse of get_funct, takes the funct (not shifted) and returns the name.
+    pub fn get_system_name(funct: u8) -> &'static str {
+        match funct {
+            0x0 => "HLT",
+            0x1 => "ALC",
+            0x2 => "FREE",
+            0x3 => "WRH",
+            0x4 => "EI",
+            0x5 => "DI",
+            0x6 => "IRET",
+            0x7 => "IMASK",
+            0x8 => "IPEND",
+            0x9 => "SYS",
+            0xA => "RALC",
+            0xB => "RDH",
+            0xC => "GC",
+            _   => "???",
+        }
+    }
+
+    // Returns the name of an encoded instruction.
+    pub fn mnemonic(instr: &(u8, u8)) -> &'static str {
+        if instr.0 >> 4 == SYSTEM_UPCODE {
+            get_system_name(instr.1 >> 4)
+        } else {
+            get_name(instr.0 >> 4)
+        }
+    }
+
+    // Inverse of get_upcodes, takes the upcode (the high nibble) and returns the name.
+    pub fn get_name(upcode: u8) -> &'static str {
+        match upcode {
+            0x0 => "LDI",
+            0x1 => "LD",
+            0x2 => "ST",
+            0x3 => "MOV",
+            0x4 => "ADD",
+            0x5 => "SUB",
+            0x6 => "MUL",
+            0x7 => "ADDI",
+            0x8 => "AND",
+            0x9 => "OR",
+            0xA => "XOR",
+            0xB => "NOT",
+            0xC => "JMPZ",
+            0xD => "RET",
+            0xE => "CALL",
+            0xF => "HLT",
+            _   => "???",
+        }
+    }
+
+    // Turns an encoded instruction back into its assembly text.
+    pub fn disassemble(instr: &(u8, u8)) -> String {
+        let upcode = instr.0 >> 4;
+        let arg1   = instr.0 & 0xf;
+        let arg2   = instr.1;
+        let name   = mnemonic(instr);
+
+        match upcode {
+            0x0 | 0x7 => format!("{} r{} {}", name, arg1, arg2),
+            0xB       => format!("{} r{}", name, arg1),
+            0xD       => String::from(name),
+            0xE       => format!("{} {}", name, arg2),
+            SYSTEM_UPCODE => match arg2 >> 4 {
+                0x1 | 0x3 | 0xA | 0xB => format!("{} r{} r{}", name, arg1, arg2 & 0xf),
+                0x2 | 0x7 | 0x8 | 0xC => format!("{} r{}", name, arg1),
+                0x9                   => format!("{} {}", name, arg1 << 4 | (arg2 & 0xf)),
+                0x0 if arg2 & 0xf == HLT_WITH_STATUS => format!("{} r{}", name, arg1),
+                _                     => String::from(name),
+            },
+            _         => format!("{} r{} r{}", name, arg1, arg2),
+        }
+    }
+
     pub fn map_register_to_value(reg: &str) -> u8 {
         if reg.starts_with('r') {
             if let Ok(val) = reg.strip_prefix("r").unwrap().parse::<u8>() {
@@ -0,0 +1,105 @@
+// Interrupt controller state.
+// Devices raise interrupt lines when they tick, which sets the line's bit in 'pending'.
+// Before the next instruction, the lowest pending line that isn't masked is taken if interrupts are enabled:
+// its pending bit is cleared, pc is pushed on the stack, interrupts are disabled and pc is set to the
+// handler address in the vector table. IRET pops pc and enables interrupts again.
+
+// One byte per line, the address of its handler. It takes up the last bytes of the default RAM.
+pub const IVT_BASE: usize = 0xD8;
+pub const NUM_IRQS: u8 = 8;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Hash)]
+pub struct InterruptState {
+    pub enabled: bool,
+    pub mask:    u8,  // Bit n set means line n is allowed.
+    pub pending: u8,  // Bit n set means line n was raised and not taken yet.
+}
+
+impl InterruptState {
+    // The line to take before the next instruction, if any.
+    pub fn next_irq(&self) -> Option<u8> {
+        let ready = self.pending & self.mask;
+        if self.enabled && ready != 0 {
+            Some(ready.trailing_zeros() as u8)
+        } else {
+            None
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; 3] {
+        [self.enabled as u8, self.mask, self.pending]
+    }
+
+    pub fn from_bytes(b: [u8; 3]) -> Self {
+        Self { enabled: b[0] != 0, mask: b[1], pending: b[2] }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_source;
+    use crate::config::MachineConfig;
+    use crate::cpu::cpu_state::Machine;
+
+    #[test]
+    fn test_next_irq() {
+        let mut s = InterruptState { enabled: false, mask: 0b110, pending: 0b111 };
+        assert_eq!(s.next_irq(), None);
+        s.enabled = true;
+        assert_eq!(s.next_irq(), Some(1));
+        s.mask = 0;
+        assert_eq!(s.next_irq(), None);
+    }
+
+    // The timer interrupts a busy loop, the handler counts in r10 and halts on the third interrupt.
+    // Jump targets are address + 2.
+    const PROGRAM: &str = "
+_START:
+    ADDI r1 216
+    ADDI r2 26
+    ST r1 r2
+    ADDI r3 240
+    ADDI r4 10
+    ST r3 r4
+    ADDI r5 1
+    IMASK r5
+    ADDI r6 26
+    ADDI r12 3
+    ADDI r13 38
+    EI
+_LOOP:
+    JMPZ r6 r0
+_HANDLER:
+    ADDI r10 1
+    MOV r11 r12
+    SUB r11 r10
+    JMPZ r13 r11
+    IRET
+    HLT
+";
+
+    #[test]
+    fn test_timer_interrupts() {
+        let config = MachineConfig::parse("device timer 0xF0 irq 0\n").unwrap();
+        let mut m = Machine::from_config(assemble_source(PROGRAM).mem, config).unwrap();
+        m.state.verbose = false;
+
+        let mut entries = 0;
+        for _ in 0..200 {
+            if !m.state.running {
+                break;
+            }
+            let was_in_handler = m.state.pc >= 26;
+            m.step().unwrap();
+            if !was_in_handler && m.state.pc >= 26 {
+                entries += 1;
+                assert!(!m.state.interrupts.enabled);
+                assert_eq!(m.stack.top, 1);
+            }
+        }
+        assert!(!m.state.running);
+        assert_eq!(m.state.registers[10], 3);
+        assert_eq!(entries, 3);
+    }
+}
@@ -7,16 +7,230 @@ mod stack;
 mod parser;
 mod yoloheap;
 mod byte_utils;
-use assembler::init_program_in_memory;
-use cpu::cpu_state::execute;
+mod debugger;
+mod json;
+mod dap;
+mod trace;
+mod snapshot;
+mod profiler;
+mod sandbox;
+mod bus;
+mod config;
+mod filesys;
+mod console;
+mod interrupts;
+mod timer;
+mod syscalls;
+mod framebuffer;
+mod storage;
+mod audio;
+mod random;
+mod protection;
+mod allocator;
+mod buddy;
+mod slab;
+mod sanitizer;
+mod gc;
+use assembler::assemble_program;
+use config::MachineConfig;
+use cpu::cpu_state::{execute_machine, Machine, Outcome};
+use sandbox::{Limits, Termination};
+use symtab::SymTab;
+use syscalls::Unbuffered;
+use debugger::Debugger;
+use trace::TraceLog;
+use dap::DapServer;
+use std::io::Write;
+use std::time::Duration;
+
+// Parses the value following a flag, exits with a message if it is missing or not a number.
+fn flag_value<T: std::str::FromStr>(flag: &str, val: Option<String>) -> T {
+    match val.as_deref().map(str::parse::<T>) {
+        Some(Ok(v)) => v,
+        _ => {
+            eprintln!("Error: {} expects a number.", flag);
+            std::process::exit(2);
+        }
+    }
+}
+
+// Reports an error that stops the run before or outside the program and exits with 1,
+// so scripts can tell it apart from a program that ran.
+fn fail(e: impl std::fmt::Display) -> ! {
+    let _ = std::io::stdout().flush();
+    eprintln!("{e}");
+    std::process::exit(1);
+}
+
+// Prints how the run ended and exits with the program's exit code.
+fn finish(outcome: Outcome, verbose: bool) -> ! {
+    if outcome.reason != Termination::Halted {
+        println!("Terminated: {}", outcome.reason);
+    }
+    if verbose {
+        println!("Exit code {} after {} instructions.", outcome.exit_code, outcome.instructions);
+    }
+    let _ = std::io::stdout().flush();
+    std::process::exit(outcome.process_exit_code());
+}
+
+// Programs only get file system calls when they are given a directory to work in.
+fn register_fs(machine: &mut Machine, root: Option<&str>) -> Result<(), String> {
+    match root {
+        Some(r) => filesys::register(&mut machine.syscalls, r),
+        None    => Ok(()),
+    }
+}
 
 fn main() {
-    println!("Enter program to run: ");
-    let mut in_buf = String::new();
-    match std::io::stdin().read_line(&mut in_buf) {
-        Ok(_)  => { in_buf = in_buf.trim().to_string() },
-        Err(e) => println!("{e}"),
-    }
-    let mut mem = init_program_in_memory(&in_buf);
-    execute(&mut mem);
+    // The process exits with the exit code of the program, or 125 if it faulted or hit a limit.
+    // It exits with 2 for bad flags and 1 when it fails before or outside the program, like a bad config.
+    // Usage: virtual_machine8bit [--debug | --dap] [--trace <file> | --replay <file> | --profile <folded file>] [--resume <snapshot>]
+    //                            [--config <machine config>] [--fs-root <dir>] [--seed <n>] [--self-modifying] [--check-heap] [--heap-map] [--sanitize-heap] [--gc] [--quiet]
+    //                            [--max-instructions <n>] [--max-time-ms <n>] [--max-heap <bytes>] [--max-output <bytes>] [--detect-loops]
+    //                            [program]
+    let mut debug = false;
+    let mut quiet = false;
+    let mut program = None;
+    let mut trace_file = None;
+    let mut replay_file = None;
+    let mut resume_file = None;
+    let mut profile_file = None;
+    let mut config_file = None;
+    let mut fs_root = None;
+    let mut seed = None;
+    let mut self_modifying = false;
+    let mut check_heap = false;
+    let mut heap_map = false;
+    let mut sanitize_heap = false;
+    let mut gc = false;
+    let mut limits = Limits::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--debug" | "-d" => debug = true,
+            "--quiet" | "-q" => quiet = true,
+            "--dap"          => {
+                // The client tells us which program to launch.
+                let mut server = DapServer::new(std::io::stdout());
+                if let Err(e) = server.run(std::io::stdin().lock()) {
+                    fail(e);
+                }
+                return;
+            }
+            "--trace"        => trace_file = args.next(),
+            "--replay"       => { replay_file = args.next(); debug = true },
+            "--resume"       => resume_file = args.next(),
+            "--profile"      => profile_file = args.next(),
+            "--config"       => config_file = args.next(),
+            "--fs-root"      => fs_root = args.next(),
+            "--seed"         => seed = Some(flag_value(&arg, args.next())),
+            "--self-modifying" => self_modifying = true,
+            "--check-heap"   => check_heap = true,
+            "--heap-map"     => heap_map = true,
+            "--sanitize-heap" => sanitize_heap = true,
+            "--gc"           => gc = true,
+            "--max-instructions" => limits.max_instructions = Some(flag_value(&arg, args.next())),
+            "--max-time-ms"      => limits.max_time = Some(Duration::from_millis(flag_value(&arg, args.next()))),
+            "--max-heap"         => limits.max_heap_bytes = Some(flag_value(&arg, args.next())),
+            "--max-output"       => limits.max_output_bytes = Some(flag_value(&arg, args.next())),
+            "--detect-loops"     => limits.detect_loops = true,
+            _                => program = Some(arg),
+        }
+    }
+
+    // The debugger is driven by a person, who can stop a runaway program themselves.
+    if debug && !limits.is_unlimited() {
+        eprintln!("Error: The limit flags can't be used with --debug or --replay.");
+        std::process::exit(2);
+    }
+
+    // Every machine, fresh or restored from a snapshot, is built from the same config.
+    // It is loaded again each time because devices can't be shared between machines.
+    let machine_config = move || -> Result<MachineConfig, String> {
+        let mut config = match config_file.as_deref() {
+            Some(f) => MachineConfig::load(f)?,
+            None    => MachineConfig::default(),
+        };
+        if let Some(s) = seed {
+            config.seed = s;
+        }
+        config.self_modifying |= self_modifying;
+        config.sanitize_heap |= sanitize_heap;
+        config.gc |= gc;
+        Ok(config)
+    };
+
+    // Resuming needs no program, it is in the snapshot. With the debugger or the profiler a program gives us the labels.
+    // A trace recorded with --trace starts where the machine starts, so it can be replayed against the same snapshot.
+    let (mut machine, symtab) = if let Some(f) = resume_file {
+        let machine = match machine_config().and_then(|c| snapshot::load_snapshot(&f, c).map_err(|e| e.to_string())) {
+            Ok(m)  => m,
+            Err(e) => fail(e),
+        };
+        let symtab = match program {
+            Some(p) => assemble_program(&p).symtab,
+            None    => SymTab::new(),
+        };
+        (machine, symtab)
+    } else {
+        let in_buf = match program {
+            Some(p) => p,
+            None    => {
+                println!("Enter program to run: ");
+                let mut in_buf = String::new();
+                match std::io::stdin().read_line(&mut in_buf) {
+                    Ok(_)  => { in_buf = in_buf.trim().to_string() },
+                    Err(e) => fail(e),
+                }
+                in_buf
+            }
+        };
+
+        let config = match machine_config() {
+            Ok(c)  => c,
+            Err(e) => fail(e),
+        };
+        let prg = assemble_program(&in_buf);
+        match Machine::from_config(prg.mem, config) {
+            Ok(m)  => (m, prg.symtab),
+            Err(e) => fail(e),
+        }
+    };
+    // Without the instruction trace the console output is readable.
+    machine.state.verbose = !quiet;
+    machine.state.check_heap = check_heap;
+    machine.state.heap_map = heap_map;
+    if let Err(e) = register_fs(&mut machine, fs_root.as_deref()) {
+        fail(e);
+    }
+
+    if debug {
+        let mut dbg = Debugger::new(machine, symtab);
+        dbg.config = Box::new(machine_config);
+
+        // A trace recorded with --trace can be walked with goto and rstep.
+        if let Some(f) = replay_file {
+            match TraceLog::load(&f) {
+                Ok(t)  => dbg.trace = Some(t),
+                Err(e) => fail(e),
+            }
+        }
+        if let Err(e) = dbg.run_repl(Unbuffered::new(std::io::stdin()), &mut std::io::stdout()) {
+            fail(e);
+        }
+        return;
+    }
+
+    let outcome = if let Some(f) = profile_file {
+        profiler::execute_profiled(machine, symtab, &f, &limits)
+    } else if let Some(f) = trace_file {
+        trace::execute_traced(machine, &f, &limits)
+    } else {
+        Ok(execute_machine(machine, &limits))
+    };
+    match outcome {
+        Ok(o)  => finish(o, !quiet),
+        Err(e) => fail(e),
+    }
 }
@@ -1,5 +1,11 @@
 
-const MEMORY_SIZE: usize = 1024;
+use crate::bus::Bus;
+
+// Addresses are u8 registers, so the bus can never reach more than this.
+pub const ADDRESS_SPACE: usize = 256;
+// Everything from IO_BASE up is left free for devices, RAM is the part below it.
+pub const IO_BASE: usize = 0xE0;
+pub const MEMORY_SIZE: usize = IO_BASE;
 
 pub fn assert_memory_size(mem: &[u8]) -> bool {
     if mem.len() >= MEMORY_SIZE { return false; }
@@ -23,8 +29,14 @@ pub fn reset_memory(mem: &mut [u8]) {
     mem.fill(0);
 }
 
-pub fn fetch_instruction(index: &mut u8, mem: &[u8]) -> (u8, u8){
-    let i = (mem[*index as usize], mem[*index as usize + 1]);
-    *index += 2;
-    i
+pub fn fetch_instruction(index: &mut u8, bus: &mut Bus) -> Result<(u8, u8), String> {
+    // A protection trap says enough on its own, a bus error here most likely means the program ran off its end.
+    let mut fetch = |addr: usize| bus.fetch(addr).map_err(|e| match bus.region(addr) {
+        Some(r) if r.name == "data" && addr == r.start => format!("{} Ran off the end of the code, is a HLT missing?", e),
+        Some(_) => e,
+        None    => format!("Error: Fetched an instruction outside of memory at {:#04x}, is a HLT missing?", addr),
+    });
+    let i = (fetch(*index as usize)?, fetch(*index as usize + 1)?);
+    *index = index.checked_add(2).ok_or("Error: Ran off the end of the address space, is a HLT missing?")?;
+    Ok(i)
 }
\ No newline at end of file
@@ -1,9 +1,12 @@
 use crate::assembler::InstructionTokenized;
 
-const VALID_NAME_TOKENS: [&str; 16]  = ["LDI", "LD", "ST", "MOV", 
+const VALID_NAME_TOKENS: [&str; 28]  = ["LDI", "LD", "ST", "MOV", 
                                         "ADD", "SUB", "MUL", "ADDI", 
                                         "AND", "OR", "XOR", "NOT", 
-                                        "JMPZ", "RET", "CALL", "HLT"];
+                                        "JMPZ", "RET", "CALL", "HLT",
+                                        "ALC", "FREE", "WRH", "EI",
+                                        "DI", "IRET", "IMASK", "IPEND",
+                                        "SYS", "RALC", "RDH", "GC"];
 
 const VALID_ARGUMENT_TOKENS: [&str; 16] = ["r0", "r1", "r2", 
                                            "r3", "r4", "r5", 
@@ -11,6 +14,36 @@ const VALID_ARGUMENT_TOKENS: [&str; 16] = ["r0", "r1", "r2",
                                            "r9", "r10", "r11", 
                                            "r12", "r13", "r14", "r15"];
 
+// The operands each system instruction takes, 'r' is a register and 'n' an immediate.
+// They share one upcode and the operands are packed around the funct, so the wrong kind would change the instruction.
+const SYSTEM_OPERANDS: [(&str, &[&str]); 13] = [("HLT",   &["", "r"]),
+                                               ("ALC",   &["r r"]),
+                                               ("FREE",  &["r"]),
+                                               ("WRH",   &["r r"]),
+                                               ("EI",    &[""]),
+                                               ("DI",    &[""]),
+                                               ("IRET",  &[""]),
+                                               ("IMASK", &["r"]),
+                                               ("IPEND", &["r"]),
+                                               ("SYS",   &["n"]),
+                                               ("RALC",  &["r r"]),
+                                               ("RDH",   &["r r"]),
+                                               ("GC",    &["r"])];
+
+// Checks the operands of a system instruction against SYSTEM_OPERANDS, other instructions are always fine here.
+fn valid_system_operands(name: &str, inst: &InstructionTokenized) -> bool {
+    let forms = match SYSTEM_OPERANDS.iter().find(|(n, _)| *n == name) {
+        Some((_, forms)) => forms,
+        None             => return true,
+    };
+    let kinds: Vec<&str> = [&inst.arg1, &inst.arg2]
+        .into_iter()
+        .flatten()
+        .map(|a| if VALID_ARGUMENT_TOKENS.contains(&a.as_str()) { "r" } else if a.parse::<u8>().is_ok() { "n" } else { "?" })
+        .collect();
+    forms.contains(&kinds.join(" ").as_str())
+}
+
 pub fn is_valid_instruction(inst: &InstructionTokenized) -> Result<(), &'static str> {
     
     // Validate name:
@@ -30,6 +63,10 @@ pub fn is_valid_instruction(inst: &InstructionTokenized) -> Result<(), &'static
     }
 
     // It is an instruction.
+    if !valid_system_operands(name, inst) {
+        inst.print_instruction_tokenized(2);
+        return Err("Error: Wrong number or kind of arguments for a system instruction.");
+    }
     match (&inst.arg1, &inst.arg2) {
         (Some(arg1), Some(arg2)) => {
             if VALID_ARGUMENT_TOKENS.contains(&arg1.as_str()) {
@@ -39,10 +76,10 @@ pub fn is_valid_instruction(inst: &InstructionTokenized) -> Result<(), &'static
                     Ok(())
                 } else if arg2.parse::<u8>().is_ok() {
                     // If arg1 is register and arg2 is immidiate.
-                    return Ok(());
+                    Ok(())
                 } else {
                     inst.print_instruction_tokenized(2);
-                    return Err("Error: Arg2 is not valid.");
+                    Err("Error: Arg2 is not valid.")
                 }
             } else {
                 // Arg1 is always a register if the instruction uses two args.
@@ -67,4 +104,23 @@ pub fn is_valid_instruction(inst: &InstructionTokenized) -> Result<(), &'static
             Err("Error: Has arg2 but not arg1") 
         }
     }
-}   
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::tokenize_instructions;
+
+    fn valid(line: &str) -> bool {
+        tokenize_instructions(vec![String::from(line)]).is_ok()
+    }
+
+    #[test]
+    fn test_system_operands() {
+        for line in ["HLT", "HLT r3", "ALC r1 r2", "EI", "SYS 200", "GC r1", "RDH r1 r2"] {
+            assert!(valid(line), "{}", line);
+        }
+        for line in ["ALC r1 20", "ALC r1 250", "HLT 7", "EI r5", "SYS r1", "FREE", "FREE r1 r2", "IRET 1", "GC r1 r2", "WRH r1"] {
+            assert!(!valid(line), "{}", line);
+        }
+    }
+}
@@ -0,0 +1,304 @@
+// Instruction level profiler.
+// Counts every executed instruction by opcode and address, and attributes it to the function
+// (symtab label) it belongs to. CALL and RET are followed with a shadow call stack, which gives
+// call counts, inclusive costs and the stacks for the folded output.
+// The cost of an instruction is 1, so costs are instruction counts.
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use crate::cpu::cpu_state::{Machine, Outcome};
+use crate::instruction_mapping::instruction_utils::mnemonic;
+use crate::sandbox::{LimitChecker, Limits, Termination};
+use crate::symtab::SymTab;
+
+const CALL_UPCODE: u8 = 0xE;
+const RET_UPCODE: u8 = 0xD;
+const HOT_ADDRESSES: usize = 10;
+const UNKNOWN_FUNCTION: &str = "??";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FunctionProfile {
+    pub calls:     u64,
+    pub inclusive: u64, // Instructions executed in the function and everything it called.
+    pub exclusive: u64, // Instructions executed in the function itself.
+}
+
+struct Frame {
+    function: String,
+    entry:    u64, // Instruction count when the function was entered.
+}
+
+pub struct Profiler {
+    symtab:              SymTab,
+    pub instructions:    u64,
+    pub opcode_counts:   HashMap<&'static str, u64>,
+    pub address_counts:  HashMap<u8, u64>,
+    pub functions:       HashMap<String, FunctionProfile>,
+    pub folded:          HashMap<String, u64>,
+    pub max_stack_depth: usize,
+    call_stack:          Vec<Frame>,
+}
+
+impl Profiler {
+    pub fn new(symtab: SymTab) -> Self {
+        Self {
+            symtab,
+            instructions:    0,
+            opcode_counts:   HashMap::new(),
+            address_counts:  HashMap::new(),
+            functions:       HashMap::new(),
+            folded:          HashMap::new(),
+            max_stack_depth: 0,
+            call_stack:      Vec::new(),
+        }
+    }
+
+    fn function_at(&self, addr: u8) -> String {
+        match self.symtab.symtab_lookup_address(addr) {
+            Some(f) => f.label.clone(),
+            None    => String::from(UNKNOWN_FUNCTION),
+        }
+    }
+
+    fn enter(&mut self, function: String) {
+        self.functions.entry(function.clone()).or_default().calls += 1;
+        self.call_stack.push(Frame { function, entry: self.instructions });
+    }
+
+    // Credits the inclusive cost of a frame that is left.
+    // A recursive function is only credited by its outermost frame, otherwise its cost would be counted once per level.
+    fn leave(&mut self) {
+        if let Some(frame) = self.call_stack.pop() {
+            if !self.call_stack.iter().any(|f| f.function == frame.function) {
+                self.functions.entry(frame.function).or_default().inclusive += self.instructions - frame.entry;
+            }
+        }
+    }
+
+    // Executes one instruction on the machine and accounts for it.
+    // The instruction is the one the machine reports it executed: when an interrupt is taken first,
+    // it is the first instruction of the handler, which is entered like a CALL and left by IRET like a RET.
+    // A faulting instruction is not counted.
+    pub fn step(&mut self, machine: &mut Machine) -> Result<(), String> {
+        // The first instruction enters the function we start in.
+        if self.call_stack.is_empty() && self.instructions == 0 {
+            let start = self.function_at(machine.state.pc);
+            self.enter(start);
+        }
+
+        let executed = machine.step()?;
+        let pc = executed.pc;
+        let name = mnemonic(&executed.instruction);
+        let function = self.function_at(pc);
+        if executed.interrupt.is_some() {
+            self.enter(function.clone());
+        }
+
+        self.instructions += 1;
+        *self.opcode_counts.entry(name).or_default() += 1;
+        *self.address_counts.entry(pc).or_default() += 1;
+        self.functions.entry(function.clone()).or_default().exclusive += 1;
+
+        // The stack is the called functions, with the function we are in at the top.
+        let mut stack: Vec<&str> = self.call_stack.iter().map(|f| f.function.as_str()).collect();
+        stack.pop();
+        stack.push(&function);
+        *self.folded.entry(stack.join(";")).or_default() += 1;
+
+        match executed.instruction.0 >> 4 {
+            CALL_UPCODE => {
+                let target = self.function_at(machine.state.pc);
+                self.enter(target);
+            }
+            RET_UPCODE => self.leave(),
+            _ if name == "IRET" => self.leave(),
+            _ => (),
+        }
+        self.max_stack_depth = self.max_stack_depth.max(machine.stack.top);
+        Ok(())
+    }
+
+    // Closes the frames that never returned, like the entry function after HLT.
+    pub fn finish(&mut self) {
+        while !self.call_stack.is_empty() {
+            self.leave();
+        }
+    }
+
+    pub fn write_report(&self, out: &mut impl Write) -> io::Result<()> {
+        let total = self.instructions.max(1) as f64;
+        writeln!(out, "Instructions executed: {}", self.instructions)?;
+        writeln!(out, "Max stack depth:       {}", self.max_stack_depth)?;
+
+        writeln!(out, "\nOpcodes:")?;
+        let mut opcodes: Vec<(&str, u64)> = self.opcode_counts.iter().map(|(op, n)| (*op, *n)).collect();
+        opcodes.sort_by_key(|(op, n)| (Reverse(*n), *op));
+        for (op, n) in opcodes {
+            writeln!(out, "  {:<6} {:>8} {:>6.1}%", op, n, n as f64 * 100.0 / total)?;
+        }
+
+        writeln!(out, "\nFunctions:")?;
+        writeln!(out, "  {:<16} {:>8} {:>10} {:>10}", "label", "calls", "inclusive", "exclusive")?;
+        let mut functions: Vec<(&String, &FunctionProfile)> = self.functions.iter().collect();
+        functions.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(b.0)));
+        for (label, f) in functions {
+            writeln!(out, "  {:<16} {:>8} {:>10} {:>10}", label, f.calls, f.inclusive, f.exclusive)?;
+        }
+
+        writeln!(out, "\nHot addresses:")?;
+        let mut addresses: Vec<(&u8, &u64)> = self.address_counts.iter().collect();
+        addresses.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (addr, n) in addresses.into_iter().take(HOT_ADDRESSES) {
+            let f = self.function_at(*addr);
+            writeln!(out, "  {:#04x} {:<16} {:>8}", addr, f, n)?;
+        }
+        Ok(())
+    }
+
+    // Writes 'stack count' lines, the input format of flamegraph.pl and inferno.
+    pub fn write_folded(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut stacks: Vec<(&String, &u64)> = self.folded.iter().collect();
+        stacks.sort();
+        for (stack, n) in stacks {
+            writeln!(out, "{} {}", stack, n)?;
+        }
+        Ok(())
+    }
+}
+
+// Runs a machine under the profiler, prints the report and writes the folded stacks to 'folded_path'.
+// The limits apply as in a plain run, a program stopped by one is still profiled up to there.
+pub fn execute_profiled(mut machine: Machine, symtab: SymTab, folded_path: &str, limits: &Limits) -> io::Result<Outcome> {
+    machine.state.verbose = false;
+    let mut profiler = Profiler::new(symtab);
+    let mut checker = LimitChecker::new(limits, &machine);
+    let mut reason = Termination::Halted;
+    while machine.state.running {
+        if let Some(t) = checker.before_step(&machine) {
+            reason = t;
+            break;
+        }
+        if let Err(e) = profiler.step(&mut machine) {
+            reason = Termination::Fault(e);
+            break;
+        }
+        if let Some(t) = checker.after_step(&machine) {
+            reason = t;
+            break;
+        }
+    }
+    profiler.finish();
+    machine.finish();
+
+    profiler.write_report(&mut io::stdout())?;
+    let mut w = BufWriter::new(File::create(folded_path)?);
+    profiler.write_folded(&mut w)?;
+    w.flush()?;
+    Ok(Outcome::new(&machine, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_source;
+    use crate::config::MachineConfig;
+
+    const PROGRAM: &str = "
+_START:
+    ADDI r1 1
+    CALL _OUTER
+    CALL _INNER
+    HLT
+_OUTER:
+    CALL _INNER
+    ADD r1 r1
+    RET
+_INNER:
+    ADD r1 r1
+    RET
+";
+
+    fn profile() -> Profiler {
+        let program = assemble_source(PROGRAM);
+        let mut machine = Machine::new(program.mem);
+        machine.state.verbose = false;
+        let mut profiler = Profiler::new(program.symtab);
+        while machine.state.running {
+            profiler.step(&mut machine).unwrap();
+        }
+        profiler.finish();
+        profiler
+    }
+
+    #[test]
+    fn test_function_costs() {
+        let p = profile();
+        assert_eq!(p.instructions, 11);
+        assert_eq!(p.max_stack_depth, 2);
+        assert_eq!(p.functions["_START"], FunctionProfile { calls: 1, inclusive: 11, exclusive: 4 });
+        assert_eq!(p.functions["_OUTER"], FunctionProfile { calls: 1, inclusive: 5, exclusive: 3 });
+        assert_eq!(p.functions["_INNER"], FunctionProfile { calls: 2, inclusive: 4, exclusive: 4 });
+        assert_eq!(p.opcode_counts["CALL"], 3);
+    }
+
+    #[test]
+    fn test_folded_stacks() {
+        let p = profile();
+        let mut out = Vec::new();
+        p.write_folded(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "_START 4\n_START;_INNER 2\n_START;_OUTER 3\n_START;_OUTER;_INNER 2\n",
+        );
+    }
+
+    // The timer interrupts a busy loop at 24, the handler at 26 halts on the third interrupt.
+    // Jump targets are address + 2.
+    const INTERRUPT_PROGRAM: &str = "
+_START:
+    ADDI r1 216
+    ADDI r2 26
+    ST r1 r2
+    ADDI r3 240
+    ADDI r4 10
+    ST r3 r4
+    ADDI r5 1
+    IMASK r5
+    ADDI r6 26
+    ADDI r12 3
+    ADDI r13 38
+    EI
+    JMPZ r6 r0
+_HANDLER:
+    ADDI r10 1
+    MOV r11 r12
+    SUB r11 r10
+    JMPZ r13 r11
+    IRET
+    HLT
+";
+
+    #[test]
+    fn test_interrupt_is_a_call() {
+        let program = assemble_source(INTERRUPT_PROGRAM);
+        let config = MachineConfig::parse("device timer 0xF0 irq 0\n").unwrap();
+        let mut machine = Machine::from_config(program.mem, config).unwrap();
+        machine.state.verbose = false;
+        let mut p = Profiler::new(program.symtab);
+        while machine.state.running {
+            p.step(&mut machine).unwrap();
+        }
+        p.finish();
+
+        // Three entries of five instructions, the first two leave with IRET, the last one halts.
+        assert_eq!(p.functions["_HANDLER"], FunctionProfile { calls: 3, inclusive: 15, exclusive: 15 });
+        assert_eq!(p.functions["_START"].calls, 1);
+        assert_eq!(p.functions["_START"].inclusive, p.instructions);
+        assert_eq!(p.functions["_START"].exclusive, p.instructions - 15);
+        assert_eq!(p.address_counts[&26], 3);
+        assert_eq!(p.opcode_counts["IRET"], 2);
+        assert_eq!(p.folded["_START;_HANDLER"], 15);
+        assert_eq!(p.max_stack_depth, 1);
+    }
+}
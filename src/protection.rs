@@ -0,0 +1,133 @@
+// Memory protection. With it on, the address space is split into regions with their own permissions:
+//   code    The program, from 0 to the end of the program. Read and execute.
+//   data    The rest of RAM. Read and write.
+//   devices Every other mapping, named after the device. Read and write.
+// An access the region doesn't allow stops the program with a protection trap naming the region.
+// Self-modifying code can be allowed per run, the code region is then writable as well.
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Perms {
+    pub read:    bool,
+    pub write:   bool,
+    pub execute: bool,
+}
+
+pub const CODE: Perms = Perms { read: true, write: false, execute: true };
+pub const CODE_WRITABLE: Perms = Perms { read: true, write: true, execute: true };
+pub const DATA: Perms = Perms { read: true, write: true, execute: false };
+
+impl Perms {
+    pub fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read    => self.read,
+            Access::Write   => self.write,
+            Access::Execute => self.execute,
+        }
+    }
+}
+
+// Written like file permissions, 'r-x'.
+impl fmt::Display for Perms {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |on: bool, c: char| if on { c } else { '-' };
+        write!(f, "{}{}{}", flag(self.read, 'r'), flag(self.write, 'w'), flag(self.execute, 'x'))
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Access::Read    => write!(f, "read from"),
+            Access::Write   => write!(f, "write to"),
+            Access::Execute => write!(f, "execute at"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Region {
+    pub name:  &'static str,
+    pub start: usize,
+    pub end:   usize,
+    pub perms: Perms,
+}
+
+// The settings the regions are worked out from, the bus knows where RAM and the devices are.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Protection {
+    pub code_end:       usize,
+    pub self_modifying: bool,
+}
+
+impl Protection {
+    pub fn code_perms(&self) -> Perms {
+        if self.self_modifying { CODE_WRITABLE } else { CODE }
+    }
+}
+
+// Fails with the trap message if 'region' doesn't allow 'access' at 'addr'.
+pub fn check(region: &Region, addr: usize, access: Access) -> Result<(), String> {
+    if region.perms.allows(access) {
+        return Ok(());
+    }
+    Err(format!("Error: Protection trap, {} {:#04x} in the {} region ({:#04x}..{:#04x}, {}).",
+        access, addr, region.name, region.start, region.end, region.perms))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_source;
+    use crate::config::MachineConfig;
+    use crate::cpu::cpu_state::Machine;
+
+    fn run(src: &str, self_modifying: bool) -> Result<Machine, String> {
+        let config = MachineConfig { self_modifying, ..MachineConfig::default() };
+        let mut m = Machine::from_config(assemble_source(src).mem, config)?;
+        m.state.verbose = false;
+        while m.state.running {
+            m.step()?;
+        }
+        Ok(m)
+    }
+
+    #[test]
+    fn test_perms() {
+        assert_eq!(CODE.to_string(), "r-x");
+        assert_eq!(DATA.to_string(), "rw-");
+        assert!(!CODE.allows(Access::Write));
+        assert!(!DATA.allows(Access::Execute));
+    }
+
+    #[test]
+    fn test_write_to_code_traps() {
+        // Writes the first byte of an 'ADDI r1' over the HLT.
+        let src = "_START:\n ADDI r1 6\n LDI r2 113\n ST r1 r2\n HLT\n";
+        let e = run(src, false).err().unwrap();
+        assert!(e.contains("write to 0x06 in the code region"), "{}", e);
+        assert!(e.contains("r-x"));
+
+        // Allowed, the HLT turns into 'ADDI r1 ...', and the program runs off into the zeroed data.
+        let e = run(src, true).err().unwrap();
+        assert!(e.contains("execute at 0x08 in the data region"), "{}", e);
+    }
+
+    #[test]
+    fn test_device_not_executable() {
+        let config = MachineConfig::parse("device ram 0xF0 16\n").unwrap();
+        let mut m = Machine::from_config(assemble_source("_START:\n ADDI r1 242\n JMPZ r1 r0\n").mem, config).unwrap();
+        m.state.verbose = false;
+        m.step().unwrap();
+        m.step().unwrap();
+        let e = m.step().unwrap_err();
+        assert!(e.contains("execute at 0xf0 in the ram region"), "{}", e);
+    }
+}
@@ -0,0 +1,104 @@
+// Pseudo random number device, one port:
+//   +0 DATA  Reading returns the next random byte, writing reseeds the generator with the byte.
+//
+// The machine seeds it before the run, from the 'seed' setting of the config or --seed. The same seed
+// always gives the same bytes, so runs can be reproduced.
+use crate::bus::Device;
+
+pub const DEFAULT_SEED: u64 = 1;
+
+pub struct Random {
+    state: u64,
+}
+
+// Spreads the bits of a seed, so small and similar seeds still give unrelated sequences.
+// This is splitmix64. xorshift would stay at 0 forever, so a zero state is bumped to 1.
+fn mix(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    (z ^ (z >> 31)).max(1)
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        Self { state: mix(seed) }
+    }
+
+    // xorshift64*, the high byte of the product is the best mixed.
+    fn next_byte(&mut self) -> u8 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+    }
+}
+
+impl Device for Random {
+    fn name(&self) -> &'static str {
+        "random"
+    }
+
+    fn size(&self) -> usize {
+        1
+    }
+
+    fn read(&mut self, _offset: usize) -> u8 {
+        self.next_byte()
+    }
+
+    fn write(&mut self, _offset: usize, val: u8) {
+        self.state = mix(val as u64);
+    }
+
+    fn seed(&mut self, seed: u64) {
+        self.state = mix(seed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_source;
+    use crate::config::MachineConfig;
+    use crate::cpu::cpu_state::Machine;
+
+    fn bytes(r: &mut Random, n: usize) -> Vec<u8> {
+        (0..n).map(|_| r.read(0)).collect()
+    }
+
+    #[test]
+    fn test_reseed() {
+        let mut a = Random::new(7);
+        let first = bytes(&mut a, 16);
+        assert_ne!(first, bytes(&mut Random::new(8), 16));
+        assert!(first.iter().any(|b| *b != first[0]));
+
+        a.write(0, 7);
+        assert_eq!(bytes(&mut a, 16), first);
+    }
+
+    // XORs three random bytes into r2.
+    const PROGRAM: &str = "_START:\n ADDI r1 240\n LD r3 r1\n XOR r2 r3\n LD r3 r1\n XOR r2 r3\n LD r3 r1\n XOR r2 r3\n HLT\n";
+
+    fn run(config: &str, seed: Option<u64>) -> u8 {
+        let mut config = MachineConfig::parse(config).unwrap();
+        if let Some(s) = seed {
+            config.seed = s;
+        }
+        let mut m = Machine::from_config(assemble_source(PROGRAM).mem, config).unwrap();
+        m.state.verbose = false;
+        while m.state.running {
+            m.step().unwrap();
+        }
+        m.state.registers[2]
+    }
+
+    #[test]
+    fn test_seeded_runs_repeat() {
+        let config = "seed 42\ndevice random 0xF0\n";
+        assert_eq!(run(config, None), run(config, None));
+        assert_eq!(run(config, None), run("device random 0xF0\n", Some(42)));
+        assert_ne!(run(config, None), run(config, Some(43)));
+    }
+}
@@ -0,0 +1,190 @@
+// Limits for running programs we don't trust, like student submissions in a grading run.
+// A program that exceeds a limit is stopped, and the reason tells which limit it was.
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+use crate::cpu::cpu_state::Machine;
+
+// Checking the clock every instruction is slow, so it is checked this often.
+const TIME_CHECK_INTERVAL: u64 = 1024;
+
+// Every limit is optional, the default is no limits at all.
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    pub max_instructions: Option<u64>,
+    pub max_time:         Option<Duration>,
+    pub max_heap_bytes:   Option<usize>,
+    pub max_output_bytes: Option<u64>,
+    pub detect_loops:     bool,
+}
+
+impl Limits {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_instructions.is_none() && self.max_time.is_none() && self.max_heap_bytes.is_none()
+            && self.max_output_bytes.is_none() && !self.detect_loops
+    }
+}
+
+// Why a run ended.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Termination {
+    Halted,
+    InstructionLimit,
+    TimeLimit,
+    HeapLimit,
+    OutputLimit,
+    TightLoop(u8),  // The pc where the same state was seen again.
+    Fault(String),
+}
+
+impl fmt::Display for Termination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Termination::Halted           => write!(f, "halted"),
+            Termination::InstructionLimit => write!(f, "instruction limit exceeded"),
+            Termination::TimeLimit        => write!(f, "time limit exceeded"),
+            Termination::HeapLimit        => write!(f, "heap limit exceeded"),
+            Termination::OutputLimit      => write!(f, "output limit exceeded"),
+            Termination::TightLoop(pc)    => write!(f, "tight loop detected at {:#04x}", pc),
+            Termination::Fault(e)         => write!(f, "fault: {}", e),
+        }
+    }
+}
+
+// Hashes everything the next instructions depend on.
+// If the same hash shows up twice at the same pc the program will repeat itself forever.
+fn state_hash(machine: &Machine) -> u64 {
+    let mut h = DefaultHasher::new();
+    machine.state.registers.hash(&mut h);
+    machine.state.pc.hash(&mut h);
+    machine.state.interrupts.hash(&mut h);
+    machine.stack.stack[..machine.stack.top].hash(&mut h);
+    for m in machine.bus.mappings() {
+        m.device.memory().hash(&mut h);
+    }
+    machine.state.heap.memory().hash(&mut h);
+    h.finish()
+}
+
+// Checks the limits around every instruction, so every way of running a program is bounded the same way.
+pub struct LimitChecker {
+    limits:       Limits,
+    start:        Instant,
+    executed:     u64,
+    seen:         HashMap<u8, u64>,  // Last state hash seen at each pc we jumped back to.
+    detect_loops: bool,
+    pc:           u8,                // Where the instruction being checked started.
+}
+
+impl LimitChecker {
+    pub fn new(limits: &Limits, machine: &Machine) -> Self {
+        Self {
+            limits:       limits.clone(),
+            start:        Instant::now(),
+            executed:     0,
+            seen:         HashMap::new(),
+            detect_loops: limits.detect_loops && machine.bus.is_plain_memory(),
+            pc:           machine.state.pc,
+        }
+    }
+
+    // The limits that stop an instruction from running at all.
+    pub fn before_step(&mut self, machine: &Machine) -> Option<Termination> {
+        if self.limits.max_instructions.is_some_and(|max| self.executed >= max) {
+            return Some(Termination::InstructionLimit);
+        }
+        if let Some(max) = self.limits.max_time {
+            if self.executed.is_multiple_of(TIME_CHECK_INTERVAL) && self.start.elapsed() > max {
+                return Some(Termination::TimeLimit);
+            }
+        }
+        self.pc = machine.state.pc;
+        None
+    }
+
+    // The limits an executed instruction can break.
+    pub fn after_step(&mut self, machine: &Machine) -> Option<Termination> {
+        self.executed += 1;
+
+        if self.limits.max_heap_bytes.is_some_and(|max| machine.state.heap.stats().allocated_bytes > max) {
+            return Some(Termination::HeapLimit);
+        }
+        if self.limits.max_output_bytes.is_some_and(|max| machine.state.output + machine.bus.output_bytes() > max) {
+            return Some(Termination::OutputLimit);
+        }
+
+        // Loops only happen by going backwards, so only those are checked.
+        // Devices that aren't plain memory have state we can't hash, a program polling one isn't stuck.
+        if self.detect_loops && machine.state.running && machine.state.pc <= self.pc {
+            let h = state_hash(machine);
+            if self.seen.insert(machine.state.pc, h) == Some(h) {
+                return Some(Termination::TightLoop(machine.state.pc));
+            }
+        }
+        None
+    }
+}
+
+// Runs the machine until it halts, faults or breaks one of the limits.
+pub fn run_with_limits(machine: &mut Machine, limits: &Limits) -> Termination {
+    let mut checker = LimitChecker::new(limits, machine);
+    while machine.state.running {
+        if let Some(t) = checker.before_step(machine) {
+            return t;
+        }
+        if let Err(e) = machine.step() {
+            return Termination::Fault(e);
+        }
+        if let Some(t) = checker.after_step(machine) {
+            return t;
+        }
+    }
+    Termination::Halted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_source;
+
+    fn machine(src: &str) -> Machine {
+        let mut m = Machine::new(assemble_source(src).mem);
+        m.state.verbose = false;
+        m
+    }
+
+    #[test]
+    fn test_instruction_limit() {
+        // Counts r1 up forever, never the same state twice until it overflows.
+        let mut m = machine("_START:\n ADDI r2 4\n ADDI r1 1\n JMPZ r2 r0\n");
+        let limits = Limits { max_instructions: Some(50), ..Default::default() };
+        assert_eq!(run_with_limits(&mut m, &limits), Termination::InstructionLimit);
+        assert_eq!(m.state.cycles, 50);
+    }
+
+    #[test]
+    fn test_tight_loop() {
+        let mut m = machine("_START:\n ADDI r2 4\n JMPZ r2 r0\n");
+        let limits = Limits { detect_loops: true, ..Default::default() };
+        assert_eq!(run_with_limits(&mut m, &limits), Termination::TightLoop(2));
+    }
+
+    #[test]
+    fn test_run_off_the_end_is_a_fault() {
+        let mut m = machine("_START:\n ADDI r1 1\n");
+        match run_with_limits(&mut m, &Limits::default()) {
+            Termination::Fault(e) => assert!(e.contains("HLT missing")),
+            other => panic!("Expected a fault, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_heap_limit() {
+        let mut m = machine("_START:\n ADDI r1 20\n ALC r2 r1\n ALC r3 r1\n HLT\n");
+        let limits = Limits { max_heap_bytes: Some(30), ..Default::default() };
+        assert_eq!(run_with_limits(&mut m, &limits), Termination::HeapLimit);
+        assert_ne!(m.state.registers[2], 0);
+    }
+}
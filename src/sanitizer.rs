@@ -0,0 +1,346 @@
+// Heap sanitizer, a debug mode that wraps the allocator to catch the heap bugs that otherwise go unnoticed:
+//   - Every payload gets RED_ZONE canary bytes on both sides, an overwritten canary is reported on free.
+//   - Freed payloads are poisoned, reading or writing them traps as a use after free.
+//   - Freeing a block twice traps, naming where it was allocated and first freed.
+//   - Blocks still allocated at HLT are reported as leaks, with the instruction that allocated them.
+//
+// Where things happened is told by the cpu through Allocator::set_pc.
+// The records are kept outside of memory(), snapshots and reverse stepping get them through Allocator::state.
+use std::collections::BTreeMap;
+
+use crate::allocator::{AllocStats, Allocator, AllocatorKind};
+use crate::yoloheap::BlockInfo;
+
+pub const RED_ZONE: usize = 2;
+pub const CANARY: u8 = 0xCA;
+pub const POISON: u8 = 0xDD;
+
+struct Allocation {
+    size:     usize,  // What was asked for, without the red zones.
+    alloc_pc: u8,
+    free_pc:  u8,     // Only set once it is freed.
+}
+
+pub struct Sanitizer {
+    inner: Box<dyn Allocator>,
+    live:  BTreeMap<usize, Allocation>,  // By the pointer the program got.
+    freed: BTreeMap<usize, Allocation>,  // Until the allocator hands the bytes out again.
+    pc:    u8,
+}
+
+impl Sanitizer {
+    pub fn new(inner: Box<dyn Allocator>) -> Self {
+        Self { inner, live: BTreeMap::new(), freed: BTreeMap::new(), pc: 0 }
+    }
+
+    // The freed block whose payload holds any of 'addr..addr + len'.
+    fn freed_around(&self, addr: usize, len: usize) -> Option<&Allocation> {
+        self.freed.iter()
+            .find(|(p, a)| addr < *p + a.size && addr + len.max(1) > **p)
+            .map(|(_, a)| a)
+    }
+
+    // State layout: the state of the allocator under it as a u8 length and the bytes, then the live and the freed
+    // records, each as a u16 count followed by pointer (u16), size (u16), alloc_pc and free_pc.
+    fn write_records(out: &mut Vec<u8>, records: &BTreeMap<usize, Allocation>) {
+        out.extend_from_slice(&(records.len() as u16).to_le_bytes());
+        for (ptr, a) in records {
+            out.extend_from_slice(&(*ptr as u16).to_le_bytes());
+            out.extend_from_slice(&(a.size as u16).to_le_bytes());
+            out.extend_from_slice(&[a.alloc_pc, a.free_pc]);
+        }
+    }
+
+    // Reads what write_records wrote from the start of 'state', returns the rest.
+    fn read_records<'a>(state: &'a [u8], records: &mut BTreeMap<usize, Allocation>) -> Option<&'a [u8]> {
+        let (count, mut rest) = state.split_first_chunk::<2>()?;
+        records.clear();
+        for _ in 0..u16::from_le_bytes(*count) {
+            let (e, r) = rest.split_first_chunk::<6>()?;
+            let ptr = u16::from_le_bytes([e[0], e[1]]) as usize;
+            records.insert(ptr, Allocation { size: u16::from_le_bytes([e[2], e[3]]) as usize, alloc_pc: e[4], free_pc: e[5] });
+            rest = r;
+        }
+        Some(rest)
+    }
+
+    fn use_after_free(&self, access: &str, addr: usize, len: usize) -> Result<(), String> {
+        match self.freed_around(addr, len) {
+            Some(a) => Err(format!("Error: Use after free, {} {:#04x} in a block allocated at pc {:#04x} and freed at pc {:#04x}.",
+                access, addr, a.alloc_pc, a.free_pc)),
+            None    => Ok(()),
+        }
+    }
+
+    // Which red zone of the live block at 'ptr' was written to, if any.
+    fn overwritten_red_zone(&self, ptr: usize, a: &Allocation) -> Option<&'static str> {
+        let intact = |start| self.inner.memory()[start..start + RED_ZONE].iter().all(|b| *b == CANARY);
+        if !intact(ptr - RED_ZONE) {
+            Some("before")
+        } else if !intact(ptr + a.size) {
+            Some("after")
+        } else {
+            None
+        }
+    }
+}
+
+impl Allocator for Sanitizer {
+    fn kind(&self) -> AllocatorKind {
+        self.inner.kind()
+    }
+
+    fn allocate(&mut self, size: usize) -> Option<usize> {
+        let p = self.inner.allocate(size + 2 * RED_ZONE)?;
+        let ptr = p + RED_ZONE;
+        self.inner.memory_mut()[p..ptr].fill(CANARY);
+        self.inner.memory_mut()[ptr + size..ptr + size + RED_ZONE].fill(CANARY);
+
+        // The bytes are in use again, accessing them is no longer a use after free.
+        self.freed.retain(|f, a| *f + a.size + RED_ZONE <= p || *f >= ptr + size + 2 * RED_ZONE);
+        self.live.insert(ptr, Allocation { size, alloc_pc: self.pc, free_pc: 0 });
+        Some(ptr)
+    }
+
+    fn free(&mut self, ptr: usize) -> Result<(), String> {
+        if let Some(a) = self.freed.get(&ptr) {
+            return Err(format!("Error: Double free of {:#04x}, allocated at pc {:#04x} and already freed at pc {:#04x}.",
+                ptr, a.alloc_pc, a.free_pc));
+        }
+        let a = self.live.get(&ptr).ok_or_else(|| format!("Error: {} is not an allocated block.", ptr))?;
+        if let Some(side) = self.overwritten_red_zone(ptr, a) {
+            return Err(format!("Error: Heap buffer overflow, the red zone {} {:#04x} (allocated at pc {:#04x}) was overwritten.",
+                side, ptr, a.alloc_pc));
+        }
+
+        let mut a = self.live.remove(&ptr).unwrap();
+        self.inner.memory_mut()[ptr..ptr + a.size].fill(POISON);
+        self.inner.free(ptr - RED_ZONE)?;
+        a.free_pc = self.pc;
+        self.freed.insert(ptr, a);
+        Ok(())
+    }
+
+    // Always moves the block, so stale pointers to the old one are caught.
+    fn realloc(&mut self, ptr: usize, size: usize) -> Result<Option<usize>, String> {
+        let old = match self.live.get(&ptr) {
+            Some(a) => a.size,
+            None    => return self.free(ptr).map(|_| None),  // Fails, with the reason why.
+        };
+        let data = self.inner.memory()[ptr..ptr + old.min(size)].to_vec();
+        let new_ptr = match self.allocate(size) {
+            Some(p) => p,
+            None    => return Ok(None),
+        };
+        self.inner.memory_mut()[new_ptr..new_ptr + data.len()].copy_from_slice(&data);
+        self.free(ptr)?;
+        Ok(Some(new_ptr))
+    }
+
+    // Writes into the red zones are let through, they are what the canaries are there to catch.
+    fn write_bytes(&mut self, addr: usize, data: &[u8]) -> Result<(), String> {
+        self.use_after_free("write to", addr, data.len())?;
+        self.inner.write_bytes(addr, data)
+    }
+
+    fn read_bytes(&self, addr: usize, len: usize) -> Result<Vec<u8>, String> {
+        self.use_after_free("read from", addr, len)?;
+        self.inner.read_bytes(addr, len)
+    }
+
+    fn blocks(&self) -> Vec<BlockInfo> {
+        self.inner.blocks()
+    }
+
+    fn header_size(&self) -> usize {
+        self.inner.header_size()
+    }
+
+    fn footer_size(&self) -> usize {
+        self.inner.footer_size()
+    }
+
+    fn peak(&self) -> usize {
+        self.inner.peak()
+    }
+
+    fn set_peak(&mut self, peak: usize) {
+        self.inner.set_peak(peak);
+    }
+
+    fn pointer_of(&self, block: &BlockInfo) -> usize {
+        self.inner.pointer_of(block) + RED_ZONE
+    }
+
+    fn stats(&self) -> AllocStats {
+        self.inner.stats()
+    }
+
+    fn memory(&self) -> &[u8] {
+        self.inner.memory()
+    }
+
+    fn memory_mut(&mut self) -> &mut [u8] {
+        self.inner.memory_mut()
+    }
+
+    fn check(&self) -> Vec<String> {
+        let mut problems = self.inner.check();
+        for (ptr, a) in &self.live {
+            if let Some(side) = self.overwritten_red_zone(*ptr, a) {
+                problems.push(format!("Block at {:#04x}: the red zone {} it was overwritten", ptr, side));
+            }
+        }
+        problems
+    }
+
+    fn set_pc(&mut self, pc: u8) {
+        self.pc = pc;
+    }
+
+    fn is_sanitized(&self) -> bool {
+        true
+    }
+
+    fn state(&self) -> Vec<u8> {
+        let inner = self.inner.state();
+        let mut out = vec![inner.len() as u8];
+        out.extend_from_slice(&inner);
+        Self::write_records(&mut out, &self.live);
+        Self::write_records(&mut out, &self.freed);
+        out
+    }
+
+    fn set_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let invalid = || String::from("Error: Invalid sanitizer state.");
+        let (len, rest) = state.split_first().ok_or_else(invalid)?;
+        let (inner, rest) = rest.split_at_checked(*len as usize).ok_or_else(invalid)?;
+        self.inner.set_state(inner)?;
+        let rest = Self::read_records(rest, &mut self.live).ok_or_else(invalid)?;
+        match Self::read_records(rest, &mut self.freed) {
+            Some([]) => Ok(()),
+            _        => Err(invalid()),
+        }
+    }
+
+    fn leaks(&self) -> Vec<String> {
+        self.live.iter()
+            .map(|(ptr, a)| format!("{} bytes at {:#04x}, allocated at pc {:#04x}", a.size, ptr, a.alloc_pc))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_source;
+    use crate::config::MachineConfig;
+    use crate::cpu::cpu_state::Machine;
+    use crate::snapshot::{read_snapshot, write_snapshot};
+    use crate::trace::TraceLog;
+
+    fn run(src: &str) -> (Machine, Result<(), String>) {
+        let config = MachineConfig { sanitize_heap: true, ..MachineConfig::default() };
+        let mut m = Machine::from_config(assemble_source(src).mem, config).unwrap();
+        m.state.verbose = false;
+        while m.state.running {
+            if let Err(e) = m.step() {
+                return (m, Err(e));
+            }
+        }
+        (m, Ok(()))
+    }
+
+    #[test]
+    fn test_red_zones() {
+        let (mut m, res) = run("_START:\n ADDI r1 4\n ALC r2 r1\n HLT\n");
+        res.unwrap();
+        let p = m.state.registers[2] as usize;
+        assert_eq!(&m.state.heap.memory()[p - 2..p + 6], &[CANARY, CANARY, 0, 0, 0, 0, CANARY, CANARY]);
+
+        // One past the end is still inside the block, but not the payload.
+        m.state.heap.write_bytes(p + 4, &[1]).unwrap();
+        assert_eq!(m.state.heap.check(), vec!["Block at 0x03: the red zone after it was overwritten"]);
+        let e = m.state.heap.free(p).unwrap_err();
+        assert!(e.contains("red zone after 0x03 (allocated at pc 0x02)"), "{}", e);
+    }
+
+    #[test]
+    fn test_use_after_free() {
+        let (m, res) = run("_START:\n ADDI r1 4\n ALC r2 r1\n FREE r2\n WRH r2 r1\n HLT\n");
+        assert_eq!(res.unwrap_err(), "Error: WRH to 3 failed. Use after free, write to 0x03 in a block allocated at pc 0x02 and freed at pc 0x04.");
+        assert_eq!(m.state.heap.memory()[3..7], [POISON; 4]);
+
+        let (_, res) = run("_START:\n ADDI r1 4\n ALC r2 r1\n FREE r2\n RDH r3 r2\n HLT\n");
+        assert!(res.unwrap_err().contains("read from 0x03"));
+    }
+
+    #[test]
+    fn test_double_free() {
+        let (_, res) = run("_START:\n ADDI r1 4\n ALC r2 r1\n FREE r2\n FREE r2\n HLT\n");
+        assert_eq!(res.unwrap_err(), "Error: FREE of 3 failed. Double free of 0x03, allocated at pc 0x02 and already freed at pc 0x04.");
+
+        // Once the bytes are handed out again the old pointer is just a pointer into the new block.
+        let (m, res) = run("_START:\n ADDI r1 4\n ALC r2 r1\n FREE r2\n ALC r3 r1\n WRH r2 r1\n HLT\n");
+        res.unwrap();
+        assert_eq!(m.state.registers[3], 3);
+    }
+
+    #[test]
+    fn test_snapshot_keeps_records() {
+        let src = "_START:\n ADDI r1 4\n ALC r2 r1\n FREE r2\n FREE r2\n HLT\n";
+        let config = MachineConfig { sanitize_heap: true, ..MachineConfig::default() };
+        let mut m = Machine::from_config(assemble_source(src).mem, config).unwrap();
+        m.state.verbose = false;
+        m.step().unwrap();
+        m.step().unwrap();
+
+        // Restored without asking for the sanitizer, the pointer in r2 still has to work.
+        let mut buf = Vec::new();
+        write_snapshot(&m, &mut buf).unwrap();
+        let mut restored = read_snapshot(&mut &buf[..], MachineConfig::default()).unwrap();
+        restored.state.verbose = false;
+        assert!(restored.state.heap.is_sanitized());
+        restored.step().unwrap();
+        assert!(restored.step().unwrap_err().contains("Double free of 0x03"));
+    }
+
+    #[test]
+    fn test_sanitize_on_resume() {
+        let config = || MachineConfig { sanitize_heap: true, ..MachineConfig::default() };
+        let mut m = Machine::new(assemble_source("_START:\n HLT\n").mem);
+        let mut buf = Vec::new();
+        write_snapshot(&m, &mut buf).unwrap();
+        assert!(read_snapshot(&mut &buf[..], config()).unwrap().state.heap.is_sanitized());
+
+        // Blocks allocated without red zones can't be sanitized after the fact.
+        m.state.heap.allocate(4).unwrap();
+        buf.clear();
+        write_snapshot(&m, &mut buf).unwrap();
+        assert!(read_snapshot(&mut &buf[..], config()).is_err());
+    }
+
+    #[test]
+    fn test_reverse_step_over_free() {
+        let config = MachineConfig { sanitize_heap: true, ..MachineConfig::default() };
+        let mut m = Machine::from_config(assemble_source("_START:\n ADDI r1 4\n ALC r2 r1\n FREE r2\n HLT\n").mem, config).unwrap();
+        m.state.verbose = false;
+        let mut log = TraceLog::new();
+        for _ in 0..3 {
+            log.step(&mut m).unwrap();
+        }
+        log.step_back(&mut m);
+        assert_eq!(m.state.heap.leaks().len(), 1);
+
+        // Executed again, the FREE is the same free and not a second one.
+        log.truncate();
+        log.step(&mut m).unwrap();
+        assert!(m.state.heap.leaks().is_empty());
+    }
+
+    #[test]
+    fn test_leaks() {
+        let (m, _) = run("_START:\n ADDI r1 4\n ALC r2 r1\n ALC r3 r1\n RALC r3 r1\n FREE r2\n HLT\n");
+        assert_eq!(m.state.heap.leaks(), vec!["4 bytes at 0x1b, allocated at pc 0x06"]);
+    }
+}
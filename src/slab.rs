@@ -0,0 +1,138 @@
+// Slab allocator. The heap is cut into slots of one fixed size, every allocation takes a whole slot.
+// There is no external fragmentation at all, instead every allocation smaller than a slot wastes the rest of it,
+// and nothing larger than a slot can be allocated.
+//
+// A bitmap at the bottom of the heap says which slots are taken, bit i (LSB first) is slot i.
+// The slots the bitmap itself takes up are never handed out.
+use crate::allocator::{allocated_bytes, block_around, Allocator, AllocatorKind};
+use crate::yoloheap::BlockInfo;
+use crate::yoloheap::constants::MINIMUM_ALLOCATED_SIZE;
+
+pub struct Slab {
+    heap:  Vec<u8>,
+    slot:  usize,
+    first: usize,  // First slot after the bitmap.
+    peak:  usize,
+}
+
+impl Slab {
+    // Asserts that at least one slot is left over after the bitmap.
+    pub fn new(size: usize, slot: usize) -> Self {
+        assert!(slot >= MINIMUM_ALLOCATED_SIZE, "Error: Slots must be at least {} bytes.", MINIMUM_ALLOCATED_SIZE);
+        let slots = size / slot;
+        let first = slots.div_ceil(8).div_ceil(slot);
+        assert!(first < slots, "Error: A {} byte heap has no room for {} byte slots.", size, slot);
+        Self { heap: vec![0; size], slot, first, peak: 0 }
+    }
+
+    fn slots(&self) -> usize {
+        self.heap.len() / self.slot
+    }
+
+    fn is_taken(&self, i: usize) -> bool {
+        self.heap[i / 8] & (1 << (i % 8)) != 0
+    }
+
+    fn set_taken(&mut self, i: usize, taken: bool) {
+        if taken {
+            self.heap[i / 8] |= 1 << (i % 8);
+        } else {
+            self.heap[i / 8] &= !(1 << (i % 8));
+        }
+    }
+}
+
+impl Allocator for Slab {
+    fn kind(&self) -> AllocatorKind {
+        AllocatorKind::Slab(self.slot)
+    }
+
+    fn allocate(&mut self, size: usize) -> Option<usize> {
+        if size > self.slot {
+            return None;
+        }
+        let i = (self.first..self.slots()).find(|i| !self.is_taken(*i))?;
+        self.set_taken(i, true);
+        self.peak = self.peak.max(allocated_bytes(&self.blocks()));
+        Some(i * self.slot)
+    }
+
+    fn free(&mut self, ptr: usize) -> Result<(), String> {
+        let i = ptr / self.slot;
+        if !ptr.is_multiple_of(self.slot) || i < self.first || i >= self.slots() || !self.is_taken(i) {
+            return Err(format!("Error: {} is not an allocated block.", ptr));
+        }
+        self.set_taken(i, false);
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, addr: usize, data: &[u8]) -> Result<(), String> {
+        block_around(&self.blocks(), 0, addr, data.len())?;
+        self.heap[addr..addr + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read_bytes(&self, addr: usize, len: usize) -> Result<Vec<u8>, String> {
+        block_around(&self.blocks(), 0, addr, len)?;
+        Ok(self.heap[addr..addr + len].to_vec())
+    }
+
+    // Only the slots that can be handed out, not the ones under the bitmap.
+    fn blocks(&self) -> Vec<BlockInfo> {
+        (self.first..self.slots())
+            .map(|i| BlockInfo { offset: i * self.slot, size: self.slot, allocated: self.is_taken(i) })
+            .collect()
+    }
+
+    fn header_size(&self) -> usize {
+        0
+    }
+
+    fn peak(&self) -> usize {
+        self.peak
+    }
+
+    fn set_peak(&mut self, peak: usize) {
+        self.peak = peak;
+    }
+
+    fn memory(&self) -> &[u8] {
+        &self.heap
+    }
+
+    fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.heap
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots() {
+        // 64 slots of 4 bytes need an 8 byte bitmap, which covers the first two slots.
+        let mut s = Slab::new(256, 4);
+        assert_eq!(s.first, 2);
+        assert_eq!(s.allocate(4), Some(8));
+        assert_eq!(s.allocate(2), Some(12));
+        assert_eq!(s.allocate(5), None);
+        assert_eq!(s.heap[0], 0b0000_1100);
+
+        s.free(8).unwrap();
+        assert!(s.free(8).is_err());
+        assert!(s.free(13).is_err());
+        assert!(s.free(0).is_err());
+        assert_eq!(s.allocate(3), Some(8));
+
+        while s.allocate(4).is_some() {}
+        assert_eq!(s.stats().allocated_blocks, 62);
+        assert_eq!(s.stats().free_bytes, 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_slot_too_large() {
+        let _ = Slab::new(256, 256);
+    }
+}
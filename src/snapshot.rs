@@ -0,0 +1,302 @@
+// Saving and restoring the complete machine state.
+// A restored machine continues exactly where the saved one was, so long runs can be checkpointed.
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use crate::config::MachineConfig;
+use crate::cpu::cpu_state::{Machine, NUM_REGS};
+use crate::interrupts::InterruptState;
+use crate::protection::Protection;
+use crate::sanitizer::Sanitizer;
+use crate::allocator::{self, AllocatorKind};
+use crate::yoloheap::HeaderWidth;
+
+const SNAPSHOT_MAGIC: &[u8; 4] = b"VM8S";
+const SNAPSHOT_VERSION: u8 = 1;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// Writes a length prefixed byte vector.
+fn write_bytes(w: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(bytes.len()).map_err(|_| invalid("Error: Region too large for a snapshot."))?;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(bytes)
+}
+
+fn read_bytes(r: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0; 2];
+    r.read_exact(&mut len)?;
+    let mut bytes = vec![0; u16::from_le_bytes(len) as usize];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+// Layout: magic, version, registers, pc, running, exit code, the instructions executed and the bytes printed
+// (both as a u64), stack, top, memory, heap, the interrupt state,
+// the protection settings, the heap header width, the allocator, whether it is sanitized and its state
+// (see Allocator::state).
+// Memory, heap and allocator state are prefixed with their length as a u16.
+// Only RAM is saved, the devices come from the machine config and start out fresh when the snapshot is restored.
+pub fn write_snapshot(machine: &Machine, w: &mut impl Write) -> io::Result<()> {
+    w.write_all(SNAPSHOT_MAGIC)?;
+    w.write_all(&[SNAPSHOT_VERSION])?;
+
+    w.write_all(&machine.state.registers)?;
+    w.write_all(&[machine.state.pc, machine.state.running as u8, machine.state.exit_code])?;
+    w.write_all(&machine.state.cycles.to_le_bytes())?;
+    w.write_all(&machine.state.output.to_le_bytes())?;
+
+    w.write_all(&machine.stack.stack)?;
+    w.write_all(&[machine.stack.top as u8])?;
+
+    write_bytes(w, machine.bus.ram())?;
+    write_bytes(w, machine.state.heap.memory())?;
+    w.write_all(&machine.state.interrupts.to_bytes())?;
+    write_protection(w, machine.bus.protection)?;
+    w.write_all(&[machine.state.heap.header_size() as u8])?;
+    w.write_all(&machine.state.heap.kind().to_bytes())?;
+    w.write_all(&[machine.state.heap.is_sanitized() as u8])?;
+    write_bytes(w, &machine.state.heap.state())
+}
+
+// One byte saying whether protection is on (1) or on with self-modifying code (2), then where the code ends as a u16.
+// A program can fill all 256 bytes of RAM, so the end doesn't fit in a byte.
+fn write_protection(w: &mut impl Write, p: Option<Protection>) -> io::Result<()> {
+    let (on, code_end) = match p {
+        None    => (0, 0),
+        Some(p) => (1 + p.self_modifying as u8, u16::try_from(p.code_end).map_err(|_| invalid("Error: Code too large for a snapshot."))?),
+    };
+    w.write_all(&[on])?;
+    w.write_all(&code_end.to_le_bytes())
+}
+
+fn read_protection(r: &mut impl Read) -> io::Result<Option<Protection>> {
+    let mut p = [0; 3];
+    r.read_exact(&mut p)?;
+    match p[0] {
+        0     => Ok(None),
+        1 | 2 => Ok(Some(Protection { code_end: u16::from_le_bytes([p[1], p[2]]) as usize, self_modifying: p[0] == 2 })),
+        _     => Err(invalid("Error: Invalid protection setting.")),
+    }
+}
+
+// Restores a snapshot into a machine built from 'config', which should be the one the saved machine was built from.
+// The allocator is the one in the snapshot, whatever the config says. A sanitized heap stays sanitized, its pointers
+// only make sense with the red zones. The config can only turn the sanitizer on if nothing is allocated yet.
+pub fn read_snapshot(r: &mut impl Read, config: MachineConfig) -> io::Result<Machine> {
+    let mut head = [0; 5];
+    r.read_exact(&mut head)?;
+    if &head[..4] != SNAPSHOT_MAGIC {
+        return Err(invalid("Error: Not a snapshot file."));
+    }
+    if head[4] != SNAPSHOT_VERSION {
+        return Err(invalid("Error: Unsupported snapshot version."));
+    }
+
+    let sanitize = config.sanitize_heap;
+    let mut machine = Machine::from_config(Vec::new(), config).map_err(|e| invalid(&e))?;
+
+    let mut regs = [0; NUM_REGS];
+    r.read_exact(&mut regs)?;
+    machine.state.registers = regs;
+
+    let mut pc_running = [0; 3];
+    r.read_exact(&mut pc_running)?;
+    machine.state.pc = pc_running[0];
+    machine.state.running = pc_running[1] != 0;
+    machine.state.exit_code = pc_running[2];
+
+    let mut counter = [0; 8];
+    r.read_exact(&mut counter)?;
+    machine.state.cycles = u64::from_le_bytes(counter);
+    r.read_exact(&mut counter)?;
+    machine.state.output = u64::from_le_bytes(counter);
+
+    r.read_exact(&mut machine.stack.stack)?;
+    let mut top = [0; 1];
+    r.read_exact(&mut top)?;
+    if top[0] as usize > machine.stack.stack.len() {
+        return Err(invalid("Error: Stack top is outside the stack."));
+    }
+    machine.stack.top = top[0] as usize;
+
+    let mem = read_bytes(r)?;
+    if mem.len() != machine.bus.ram().len() {
+        return Err(invalid("Error: Snapshot memory does not match the RAM of the machine config."));
+    }
+    machine.bus.ram_mut().copy_from_slice(&mem);
+
+    let heap = read_bytes(r)?;
+
+    let mut interrupts = [0; 3];
+    r.read_exact(&mut interrupts)?;
+    machine.state.interrupts = InterruptState::from_bytes(interrupts);
+
+    machine.bus.protection = read_protection(r)?;
+
+    // The heap comes last, which allocator it belongs to is only known now.
+    let mut width = [0; 1];
+    r.read_exact(&mut width)?;
+    let mut kind = [0; 2];
+    r.read_exact(&mut kind)?;
+    let mut sanitized = [0; 1];
+    r.read_exact(&mut sanitized)?;
+    let state = read_bytes(r)?;
+    let kind = AllocatorKind::from_bytes(kind).ok_or_else(|| invalid("Error: Unknown allocator."))?;
+    let header = match width[0] {
+        _ if kind != AllocatorKind::FirstFit => HeaderWidth::Narrow,
+        1 => HeaderWidth::Narrow,
+        2 => HeaderWidth::Wide,
+        _ => return Err(invalid("Error: Invalid heap header width.")),
+    };
+    machine.state.heap = match sanitized[0] {
+        0 => allocator::create(kind, header),
+        1 => Box::new(Sanitizer::new(allocator::create(kind, header))),
+        _ => return Err(invalid("Error: Invalid sanitizer setting.")),
+    };
+    if heap.len() != machine.state.heap.memory().len() {
+        return Err(invalid("Error: Snapshot heap does not match the allocator."));
+    }
+    machine.state.heap.memory_mut().copy_from_slice(&heap);
+    machine.state.heap.set_state(&state).map_err(|e| invalid(&e))?;
+
+    if sanitize && !machine.state.heap.is_sanitized() {
+        if machine.state.heap.blocks().iter().any(|b| b.allocated) {
+            return Err(invalid("Error: Can't sanitize the heap of a snapshot that already has blocks allocated."));
+        }
+        let heap = std::mem::replace(&mut machine.state.heap, allocator::create(kind, header));
+        machine.state.heap = Box::new(Sanitizer::new(heap));
+    }
+
+    Ok(machine)
+}
+
+pub fn save_snapshot(machine: &Machine, path: &str) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    write_snapshot(machine, &mut w)?;
+    w.flush()
+}
+
+pub fn load_snapshot(path: &str, config: MachineConfig) -> io::Result<Machine> {
+    read_snapshot(&mut BufReader::new(File::open(path)?), config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_source;
+    use crate::config::MachineConfig;
+
+    const PROGRAM: &str = "
+_START:
+    ADDI r1 1
+    ADDI r2 5
+    ADDI r3 1
+    ADDI r5 10
+_LOOP:
+    ADD r1 r1
+    SUB r2 r3
+    JMPZ r6 r2
+    JMPZ r5 r0
+    HLT
+";
+
+    fn run(machine: &mut Machine) {
+        while machine.state.running {
+            machine.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_resume_from_snapshot() {
+        let mut m = Machine::new(assemble_source(PROGRAM).mem);
+        m.state.verbose = false;
+        m.state.registers[6] = 18;
+        m.bus.ram_mut()[200] = 42;
+        m.state.heap.memory_mut()[100] = 7;
+        for _ in 0..9 {
+            m.step().unwrap();
+        }
+
+        let mut buf = Vec::new();
+        write_snapshot(&m, &mut buf).unwrap();
+        let mut restored = read_snapshot(&mut &buf[..], MachineConfig::default()).unwrap();
+        restored.state.verbose = false;
+
+        assert_eq!(restored.state.registers, m.state.registers);
+        assert_eq!(restored.state.pc, m.state.pc);
+        assert_eq!(restored.stack.top, m.stack.top);
+        assert_eq!(restored.bus.ram(), m.bus.ram());
+        assert_eq!(restored.state.heap.memory(), m.state.heap.memory());
+        assert_eq!(restored.bus.protection, m.bus.protection);
+        assert_eq!(restored.state.cycles, 9);
+        assert_eq!(restored.state.output, m.state.output);
+
+        run(&mut m);
+        run(&mut restored);
+        assert_eq!(restored.state.registers, m.state.registers);
+        assert_eq!(restored.state.registers[1], 32);
+        assert_eq!(restored.state.cycles, m.state.cycles);
+
+        // A halted machine stays halted with its exit code, and so does the count of printed bytes.
+        m.state.exit_code = 3;
+        m.state.output = 12;
+        let mut buf = Vec::new();
+        write_snapshot(&m, &mut buf).unwrap();
+        let halted = read_snapshot(&mut &buf[..], MachineConfig::default()).unwrap();
+        assert!(!halted.state.running);
+        assert_eq!(halted.state.exit_code, 3);
+        assert_eq!(halted.state.output, 12);
+        assert_eq!(halted.state.cycles, m.state.cycles);
+    }
+
+    #[test]
+    fn test_keeps_allocator() {
+        let config = MachineConfig { allocator: AllocatorKind::Slab(8), ..MachineConfig::default() };
+        let mut m = Machine::from_config(assemble_source(PROGRAM).mem, config).unwrap();
+        let p = m.state.heap.allocate(8).unwrap();
+
+        let mut buf = Vec::new();
+        write_snapshot(&m, &mut buf).unwrap();
+        let mut restored = read_snapshot(&mut &buf[..], MachineConfig::default()).unwrap();
+        assert_eq!(restored.state.heap.kind(), AllocatorKind::Slab(8));
+        assert_eq!(restored.state.heap.peak(), 8);
+        restored.state.heap.free(p).unwrap();
+    }
+
+    #[test]
+    fn test_devices_come_from_config() {
+        let config = "ram 0x80\ndevice ram 0xF0 16\n";
+        let src = "_START:\n ADDI r1 7\n ADDI r5 240\n ST r5 r1\n LD r2 r5\n HLT\n";
+        let mut m = Machine::from_config(assemble_source(src).mem, MachineConfig::parse(config).unwrap()).unwrap();
+        m.state.verbose = false;
+        m.step().unwrap();
+        m.step().unwrap();
+
+        let mut buf = Vec::new();
+        write_snapshot(&m, &mut buf).unwrap();
+        let mut restored = read_snapshot(&mut &buf[..], MachineConfig::parse(config).unwrap()).unwrap();
+        restored.state.verbose = false;
+        run(&mut restored);
+        assert_eq!(restored.state.registers[2], 7);
+
+        // A config with a different amount of RAM is not the machine that was saved.
+        assert!(read_snapshot(&mut &buf[..], MachineConfig::default()).is_err());
+    }
+
+    #[test]
+    fn test_code_filling_ram() {
+        let mut buf = Vec::new();
+        let m = Machine::from_config(vec![0; 256], MachineConfig { ram_size: 256, ..MachineConfig::default() }).unwrap();
+        write_snapshot(&m, &mut buf).unwrap();
+        let restored = read_snapshot(&mut &buf[..], MachineConfig { ram_size: 256, ..MachineConfig::default() }).unwrap();
+        assert_eq!(restored.bus.protection.unwrap().code_end, 256);
+    }
+
+    #[test]
+    fn test_reject_garbage() {
+        assert!(read_snapshot(&mut &b"VM8T\x01"[..], MachineConfig::default()).is_err());
+        assert!(read_snapshot(&mut &b"VM8S\x01\x00"[..], MachineConfig::default()).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+// Block storage device backed by a disk image file on the host, four ports:
+//   +0 SECTOR_LO  Low byte of the sector number.
+//   +1 SECTOR_HI  High byte of the sector number.
+//   +2 BUFFER     Address in VM memory the sector is read to or written from.
+//   +3 COMMAND    Writing 1 reads the sector into the buffer, writing 2 writes the buffer to the sector.
+//      STATUS     Reading gives the result of the last command, one of the STATUS_ values.
+//
+// A sector is SECTOR_SIZE bytes, the disk has as many sectors as fit in the image file.
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use crate::bus::{Device, Dma};
+
+pub const SECTOR_SIZE: usize = 32;
+
+const SECTOR_LO_PORT: usize = 0;
+const SECTOR_HI_PORT: usize = 1;
+const BUFFER_PORT: usize = 2;
+const COMMAND_PORT: usize = 3;
+
+const COMMAND_READ: u8 = 1;
+const COMMAND_WRITE: u8 = 2;
+
+pub const STATUS_OK: u8 = 0;
+pub const STATUS_OUT_OF_RANGE: u8 = 1;
+pub const STATUS_READ_ONLY: u8 = 2;
+pub const STATUS_IO_ERROR: u8 = 3;
+pub const STATUS_BAD_COMMAND: u8 = 4;
+pub const STATUS_BUS_ERROR: u8 = 5;
+
+pub struct Storage {
+    file:      File,
+    sectors:   u64,
+    read_only: bool,
+    sector:    u16,
+    buffer:    u8,
+    status:    u8,
+    dma:       Option<Dma>,
+    writing:   bool,  // The transfer in progress fetches the buffer for a write.
+}
+
+impl Storage {
+    pub fn open(path: &str, read_only: bool) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(path)
+            .map_err(|e| format!("Error: Opening disk image {}: {}", path, e))?;
+        let len = file.metadata().map_err(|e| format!("Error: Reading disk image {}: {}", path, e))?.len();
+        Ok(Self {
+            file,
+            sectors: len / SECTOR_SIZE as u64,
+            read_only,
+            sector:  0,
+            buffer:  0,
+            status:  STATUS_OK,
+            dma:     None,
+            writing: false,
+        })
+    }
+
+    fn offset(&self) -> u64 {
+        self.sector as u64 * SECTOR_SIZE as u64
+    }
+
+    fn read_sector(&mut self) -> std::io::Result<Vec<u8>> {
+        let mut data = vec![0; SECTOR_SIZE];
+        self.file.seek(SeekFrom::Start(self.offset()))?;
+        self.file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn write_sector(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.offset()))?;
+        self.file.write_all(data)?;
+        self.file.flush()
+    }
+
+    fn command(&mut self, cmd: u8) {
+        self.status = match cmd {
+            _ if cmd != COMMAND_READ && cmd != COMMAND_WRITE => STATUS_BAD_COMMAND,
+            _ if self.sector as u64 >= self.sectors          => STATUS_OUT_OF_RANGE,
+            COMMAND_WRITE if self.read_only                  => STATUS_READ_ONLY,
+            COMMAND_READ => match self.read_sector() {
+                Ok(data) => {
+                    self.dma = Some(Dma::ToMemory { addr: self.buffer as usize, data });
+                    STATUS_OK
+                }
+                Err(_) => STATUS_IO_ERROR,
+            },
+            _ => {
+                self.dma = Some(Dma::FromMemory { addr: self.buffer as usize, len: SECTOR_SIZE });
+                self.writing = true;
+                STATUS_OK
+            }
+        };
+    }
+}
+
+impl Device for Storage {
+    fn name(&self) -> &'static str {
+        "storage"
+    }
+
+    fn size(&self) -> usize {
+        4
+    }
+
+    fn read(&mut self, offset: usize) -> u8 {
+        let [lo, hi] = self.sector.to_le_bytes();
+        match offset {
+            SECTOR_LO_PORT => lo,
+            SECTOR_HI_PORT => hi,
+            BUFFER_PORT    => self.buffer,
+            COMMAND_PORT   => self.status,
+            _              => 0,
+        }
+    }
+
+    fn write(&mut self, offset: usize, val: u8) {
+        match offset {
+            SECTOR_LO_PORT => self.sector = self.sector & 0xFF00 | val as u16,
+            SECTOR_HI_PORT => self.sector = self.sector & 0x00FF | (val as u16) << 8,
+            BUFFER_PORT    => self.buffer = val,
+            COMMAND_PORT   => self.command(val),
+            _              => (),
+        }
+    }
+
+    fn take_dma(&mut self) -> Option<Dma> {
+        self.dma.take()
+    }
+
+    fn dma_done(&mut self, res: Result<Vec<u8>, String>) {
+        let writing = std::mem::take(&mut self.writing);
+        self.status = match res {
+            Err(_) => STATUS_BUS_ERROR,
+            Ok(data) if writing => match self.write_sector(&data) {
+                Ok(())  => STATUS_OK,
+                Err(_)  => STATUS_IO_ERROR,
+            },
+            Ok(_) => STATUS_OK,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{Bus, Mapping};
+
+    fn image(name: &str, sectors: usize) -> String {
+        let path = std::env::temp_dir().join(name);
+        let data: Vec<u8> = (0..sectors * SECTOR_SIZE).map(|i| (i / SECTOR_SIZE) as u8 + 1).collect();
+        std::fs::write(&path, data).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn bus(path: &str, read_only: bool) -> Bus {
+        let mut bus = Bus::new(vec![0; 0xE0]);
+        bus.attach(Mapping::new(0xF0, Box::new(Storage::open(path, read_only).unwrap()))).unwrap();
+        bus
+    }
+
+    #[test]
+    fn test_read_and_write_sectors() {
+        let path = image("vm8bit_storage_rw.img", 4);
+        let mut bus = bus(&path, false);
+
+        bus.write(0xF0, 2).unwrap();
+        bus.write(0xF2, 0x40).unwrap();
+        bus.write(0xF3, COMMAND_READ).unwrap();
+        assert_eq!(bus.read(0xF3).unwrap(), STATUS_OK);
+        assert_eq!(&bus.ram()[0x40..0x40 + SECTOR_SIZE], &[3; SECTOR_SIZE]);
+
+        bus.ram_mut()[0x80..0x80 + SECTOR_SIZE].fill(0xAA);
+        bus.write(0xF0, 1).unwrap();
+        bus.write(0xF2, 0x80).unwrap();
+        bus.write(0xF3, COMMAND_WRITE).unwrap();
+        assert_eq!(bus.read(0xF3).unwrap(), STATUS_OK);
+
+        let disk = std::fs::read(&path).unwrap();
+        assert_eq!(&disk[SECTOR_SIZE..2 * SECTOR_SIZE], &[0xAA; SECTOR_SIZE]);
+        assert_eq!(disk[2 * SECTOR_SIZE], 3);
+    }
+
+    #[test]
+    fn test_errors() {
+        let path = image("vm8bit_storage_ro.img", 2);
+        let mut bus = bus(&path, true);
+
+        bus.write(0xF1, 1).unwrap();
+        bus.write(0xF3, COMMAND_READ).unwrap();
+        assert_eq!(bus.read(0xF3).unwrap(), STATUS_OUT_OF_RANGE);
+
+        bus.write(0xF1, 0).unwrap();
+        bus.write(0xF3, COMMAND_WRITE).unwrap();
+        assert_eq!(bus.read(0xF3).unwrap(), STATUS_READ_ONLY);
+
+        bus.write(0xF3, 9).unwrap();
+        assert_eq!(bus.read(0xF3).unwrap(), STATUS_BAD_COMMAND);
+
+        // The sector doesn't fit below the end of RAM.
+        bus.write(0xF2, 0xD0).unwrap();
+        bus.write(0xF3, COMMAND_READ).unwrap();
+        assert_eq!(bus.read(0xF3).unwrap(), STATUS_BUS_ERROR);
+    }
+}
@@ -1,5 +1,6 @@
 const SYMTAB_INIT_SIZE: usize = 10;
 
+#[derive(Clone)]
 pub struct Function {
     pub label:   String,
     pub address: u8,
@@ -14,6 +15,7 @@ impl Function {
     }
 }
 
+#[derive(Clone)]
 pub struct SymTab {
     pub table: Vec<Function>,
 }
@@ -32,6 +34,15 @@ impl SymTab {
         Err(format!("Error: Could not find the target function: LABEL = '{}'", target))
     } 
     
+    // Returns the function that contains 'addr', that is the label with the
+    // highest address that is still below or at 'addr'.
+    pub fn symtab_lookup_address(&self, addr: u8) -> Option<&Function> {
+        self.table
+            .iter()
+            .filter(|f| f.address <= addr)
+            .max_by_key(|f| f.address)
+    }
+
     pub fn symtab_insert(&mut self, f: Function) -> Result<(), String>{
         // Check if it already exists.
         if self.table.iter().any(|fun| fun.label == f.label) {
@@ -0,0 +1,283 @@
+// System calls, services implemented by the host that programs reach with 'SYS n'.
+// Calling convention: the argument is in r1, results come back in r1 (and r2 where noted).
+//
+//   0 EXIT        Stop the program with exit code r1.
+//   1 PRINT_INT   Print r1 as a decimal number.
+//   2 PRINT_CHAR  Print r1 as a character.
+//   3 READ_INT    Read a line and parse it as a number into r1. r2 is 0 on success, 1 on a bad number or end of input.
+//   4 CYCLES      Number of executed instructions, low byte in r1 and the next byte in r2.
+//   5 SLEEP       Sleep r1 milliseconds.
+//   6 PRINT_STR   Print the zero terminated string at address r1.
+//   7-11          File system calls, see filesys.rs.
+//
+// Embedders can register their own services, or replace these, with Syscalls::register.
+use std::collections::HashMap;
+use std::io::{self, BufRead, Read, Write};
+use std::thread;
+use std::time::Duration;
+use crate::bus::Bus;
+use crate::cpu::cpu_state::CpuState;
+
+pub const SYS_EXIT: u8 = 0;
+pub const SYS_PRINT_INT: u8 = 1;
+pub const SYS_PRINT_CHAR: u8 = 2;
+pub const SYS_READ_INT: u8 = 3;
+pub const SYS_CYCLES: u8 = 4;
+pub const SYS_SLEEP: u8 = 5;
+pub const SYS_PRINT_STR: u8 = 6;
+
+// What a service gets to work with.
+pub struct SysContext<'a> {
+    pub state:  &'a mut CpuState,
+    pub bus:    &'a mut Bus,
+    pub input:  &'a mut dyn BufRead,
+    pub output: &'a mut dyn Write,
+}
+
+impl SysContext<'_> {
+    // Writes to the output and counts the bytes against the output limit.
+    pub fn print(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.output.write_all(bytes).map_err(|e| format!("Error: Writing output: {}", e))?;
+        self.state.output += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+// Reads from 'inner' one byte at a time, so it never holds on to bytes that weren't asked for.
+// Stdin is buffered once by std, the console device and the debugger read it too and would miss what a second
+// buffer took. Stdin is only locked for each read, holding the lock would hang whoever reads it next.
+pub struct Unbuffered<R: Read> {
+    inner: R,
+    byte:  Option<u8>,
+}
+
+impl<R: Read> Unbuffered<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, byte: None }
+    }
+}
+
+impl<R: Read> Read for Unbuffered<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match (self.byte.take(), buf.first_mut()) {
+            (Some(b), Some(first)) => {
+                *first = b;
+                Ok(1)
+            }
+            (b, _) => {
+                self.byte = b;
+                self.inner.read(buf)
+            }
+        }
+    }
+}
+
+impl<R: Read> BufRead for Unbuffered<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.byte.is_none() {
+            let mut b = [0; 1];
+            if self.inner.read(&mut b)? == 1 {
+                self.byte = Some(b[0]);
+            }
+        }
+        Ok(self.byte.as_slice())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if amt > 0 {
+            self.byte = None;
+        }
+    }
+}
+
+pub type Service = Box<dyn FnMut(&mut SysContext) -> Result<(), String>>;
+
+pub struct Syscalls {
+    services: HashMap<u8, Service>,
+    input:    Box<dyn BufRead>,
+    output:   Box<dyn Write>,
+}
+
+fn sys_exit(ctx: &mut SysContext) -> Result<(), String> {
+    ctx.state.exit_code = ctx.state.registers[1];
+    ctx.state.running = false;
+    Ok(())
+}
+
+fn sys_print_int(ctx: &mut SysContext) -> Result<(), String> {
+    let s = ctx.state.registers[1].to_string();
+    ctx.print(s.as_bytes())
+}
+
+fn sys_print_char(ctx: &mut SysContext) -> Result<(), String> {
+    let c = ctx.state.registers[1];
+    ctx.print(&[c])
+}
+
+fn sys_read_int(ctx: &mut SysContext) -> Result<(), String> {
+    // A prompt has to be visible before we wait for the answer.
+    let _ = ctx.output.flush();
+    let mut line = String::new();
+    let read = ctx.input.read_line(&mut line).map_err(|e| format!("Error: Reading input: {}", e))?;
+    match line.trim().parse::<u8>() {
+        Ok(n) if read > 0 => {
+            ctx.state.registers[1] = n;
+            ctx.state.registers[2] = 0;
+        }
+        _ => {
+            ctx.state.registers[1] = 0;
+            ctx.state.registers[2] = 1;
+        }
+    }
+    Ok(())
+}
+
+fn sys_cycles(ctx: &mut SysContext) -> Result<(), String> {
+    let [lo, hi, ..] = ctx.state.cycles.to_le_bytes();
+    ctx.state.registers[1] = lo;
+    ctx.state.registers[2] = hi;
+    Ok(())
+}
+
+fn sys_sleep(ctx: &mut SysContext) -> Result<(), String> {
+    thread::sleep(Duration::from_millis(ctx.state.registers[1] as u64));
+    Ok(())
+}
+
+fn sys_print_str(ctx: &mut SysContext) -> Result<(), String> {
+    let mut s = Vec::new();
+    let mut addr = ctx.state.registers[1] as usize;
+    loop {
+        let c = ctx.bus.read(addr)?;
+        if c == 0 {
+            break;
+        }
+        s.push(c);
+        addr += 1;
+    }
+    ctx.print(&s)
+}
+
+impl Syscalls {
+    // The built in services, reading from 'input' and printing to 'output'.
+    pub fn new(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Self {
+        let mut sys = Self {
+            services: HashMap::new(),
+            input,
+            output,
+        };
+        sys.register(SYS_EXIT, Box::new(sys_exit));
+        sys.register(SYS_PRINT_INT, Box::new(sys_print_int));
+        sys.register(SYS_PRINT_CHAR, Box::new(sys_print_char));
+        sys.register(SYS_READ_INT, Box::new(sys_read_int));
+        sys.register(SYS_CYCLES, Box::new(sys_cycles));
+        sys.register(SYS_SLEEP, Box::new(sys_sleep));
+        sys.register(SYS_PRINT_STR, Box::new(sys_print_str));
+        sys
+    }
+
+    pub fn stdio() -> Self {
+        Self::new(Box::new(Unbuffered::new(io::stdin())), Box::new(io::stdout()))
+    }
+
+    // Adds a service, replacing the one that had the number before.
+    pub fn register(&mut self, n: u8, service: Service) {
+        self.services.insert(n, service);
+    }
+
+    pub fn call(&mut self, n: u8, state: &mut CpuState, bus: &mut Bus) -> Result<(), String> {
+        let service = self.services
+            .get_mut(&n)
+            .ok_or_else(|| format!("Error: Unknown system call: {}", n))?;
+        let mut ctx = SysContext {
+            state,
+            bus,
+            input:  &mut *self.input,
+            output: &mut *self.output,
+        };
+        service(&mut ctx)
+    }
+
+    pub fn flush(&mut self) {
+        let _ = self.output.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_source;
+    use crate::console::SharedBuffer;
+    use crate::cpu::cpu_state::Machine;
+
+    fn machine(src: &str, input: &str) -> (Machine, SharedBuffer) {
+        let out = SharedBuffer::default();
+        let mut m = Machine::new(assemble_source(src).mem);
+        m.state.verbose = false;
+        m.syscalls = Syscalls::new(Box::new(io::Cursor::new(input.as_bytes().to_vec())), Box::new(out.clone()));
+        (m, out)
+    }
+
+    fn run(m: &mut Machine) -> Result<(), String> {
+        while m.state.running {
+            m.step()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_print_and_exit() {
+        let src = "_START:\n ADDI r1 42\n SYS 1\n LDI r1 10\n SYS 2\n LDI r1 3\n SYS 0\n ADDI r4 1\n";
+        let (mut m, out) = machine(src, "");
+        run(&mut m).unwrap();
+        assert_eq!(out.contents(), b"42\n");
+        assert_eq!(m.state.output, 3);
+        assert_eq!(m.state.exit_code, 3);
+        assert_eq!(m.state.registers[4], 0);
+    }
+
+    #[test]
+    fn test_print_str() {
+        let src = "_START:\n ADDI r1 100\n ADDI r2 111\n ST r1 r2\n ADDI r1 1\n ST r1 r2\n LDI r1 99\n SYS 6\n HLT\n";
+        let (mut m, out) = machine(src, "");
+        m.bus.ram_mut()[99] = b'f';
+        run(&mut m).unwrap();
+        assert_eq!(out.contents(), b"foo");
+    }
+
+    #[test]
+    fn test_read_int() {
+        let src = "_START:\n SYS 3\n MOV r3 r1\n MOV r4 r2\n SYS 3\n HLT\n";
+        let (mut m, _) = machine(src, "17\nnope\n");
+        run(&mut m).unwrap();
+        assert_eq!(m.state.registers[3], 17);
+        assert_eq!(m.state.registers[4], 0);
+        assert_eq!(m.state.registers[1], 0);
+        assert_eq!(m.state.registers[2], 1);
+    }
+
+    #[test]
+    fn test_registered_service() {
+        // Service numbers use all 8 bits, 200 is split over both instruction bytes.
+        let (mut m, _) = machine("_START:\n ADDI r1 20\n SYS 200\n SYS 4\n HLT\n", "");
+        m.syscalls.register(200, Box::new(|ctx: &mut SysContext| {
+            ctx.bus.write(100, ctx.state.registers[1] * 2)
+        }));
+        run(&mut m).unwrap();
+        assert_eq!(m.bus.ram()[100], 40);
+        assert_eq!(m.state.registers[1], 2);
+
+        let (mut m, _) = machine("_START:\n SYS 99\n HLT\n", "");
+        assert!(run(&mut m).unwrap_err().contains("Unknown system call"));
+        assert_eq!(m.state.pc, 0);
+    }
+
+    #[test]
+    fn test_unbuffered_leaves_the_rest() {
+        let mut input = &b"17\nrest"[..];
+        let mut line = String::new();
+        Unbuffered::new(&mut input).read_line(&mut line).unwrap();
+        assert_eq!(line, "17\n");
+        assert_eq!(input, b"rest");
+    }
+}
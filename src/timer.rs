@@ -0,0 +1,76 @@
+// Programmable timer, two ports:
+//   +0 PERIOD_LO  Low byte of the period, in instructions.
+//   +1 PERIOD_HI  High byte of the period.
+// Writing either byte restarts the count. Every 'period' instructions the timer raises its interrupt line,
+// a period of 0 stops it.
+use crate::bus::Device;
+
+const PERIOD_LO_PORT: usize = 0;
+const PERIOD_HI_PORT: usize = 1;
+
+#[derive(Default)]
+pub struct Timer {
+    period:  u16,
+    counter: u16,  // Instructions left until the next interrupt.
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Device for Timer {
+    fn name(&self) -> &'static str {
+        "timer"
+    }
+
+    fn size(&self) -> usize {
+        2
+    }
+
+    fn read(&mut self, offset: usize) -> u8 {
+        let [lo, hi] = self.period.to_le_bytes();
+        match offset {
+            PERIOD_LO_PORT => lo,
+            PERIOD_HI_PORT => hi,
+            _              => 0,
+        }
+    }
+
+    fn write(&mut self, offset: usize, val: u8) {
+        let mut bytes = self.period.to_le_bytes();
+        bytes[offset] = val;
+        self.period = u16::from_le_bytes(bytes);
+        self.counter = self.period;
+    }
+
+    fn tick(&mut self) -> bool {
+        if self.period == 0 {
+            return false;
+        }
+        self.counter -= 1;
+        if self.counter == 0 {
+            self.counter = self.period;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_period() {
+        let mut t = Timer::new();
+        assert!(!(0..100).any(|_| t.tick()));
+
+        t.write(PERIOD_HI_PORT, 1);
+        t.write(PERIOD_LO_PORT, 4);
+        assert_eq!(t.read(PERIOD_LO_PORT), 4);
+        let fired: Vec<usize> = (1..=600).filter(|_| t.tick()).collect();
+        assert_eq!(fired, vec![260, 520]);
+    }
+}
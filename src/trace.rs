@@ -0,0 +1,486 @@
+// Execution trace recording.
+// Every executed instruction is stored as a delta: what changed and what it changed from.
+// Keeping both the old and new values lets us undo (reverse step) and redo without re-executing.
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use crate::cpu::cpu_state::{Machine, Outcome};
+use crate::interrupts::InterruptState;
+use crate::sandbox::{LimitChecker, Limits, Termination};
+
+const TRACE_MAGIC: &[u8; 4] = b"VM8T";
+const TRACE_VERSION: u8 = 1;
+const HALTED_FLAG: u8 = 1;
+const INTERRUPTS_FLAG: u8 = 2;  // The record changed the interrupt state.
+const HEAP_STATE_FLAG: u8 = 4;  // The record changed what the allocator keeps outside of the heap, see Allocator::state.
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TraceRecord {
+    pub pc:      u8,                   // pc of the executed instruction.
+    pub next_pc: u8,                   // pc after the instruction.
+    pub halted:  bool,                 // The instruction stopped the machine.
+    pub regs:    Vec<(u8, u8, u8)>,    // (register, old, new)
+    pub mem:     Vec<(u16, u8, u8)>,   // (address, old, new)
+    pub heap:    Vec<(u16, u8, u8)>,   // (heap index, old, new)
+    pub pops:    Vec<u8>,              // Values popped from the stack, in pop order.
+    pub pushes:  Vec<u8>,              // Values pushed to the stack, in push order.
+    pub interrupts: Option<(InterruptState, InterruptState)>,  // (old, new) if it changed.
+    pub heap_state: Option<(Vec<u8>, Vec<u8>)>,                // (old, new) if it changed.
+}
+
+// Returns every index where 'old' and 'new' differ.
+fn diff_bytes(old: &[u8], new: &[u8]) -> Vec<(u16, u8, u8)> {
+    old.iter()
+        .zip(new)
+        .enumerate()
+        .filter(|(_, (o, n))| o != n)
+        .map(|(i, (o, n))| (i as u16, *o, *n))
+        .collect()
+}
+
+impl TraceRecord {
+    // Executes one instruction on the machine and records what it changed.
+    // A faulting instruction changes nothing, so there is nothing to record.
+    // Only RAM is diffed, what an instruction did to other devices on the bus can't be undone.
+    pub fn capture(machine: &mut Machine) -> Result<Self, String> {
+        let pc   = machine.state.pc;
+        let regs = machine.state.registers;
+        let mem  = machine.bus.ram().to_vec();
+        let heap = machine.state.heap.memory().to_vec();
+        let stack = machine.stack.stack;
+        let top   = machine.stack.top;
+        let interrupts = machine.state.interrupts;
+        let heap_state = machine.state.heap.state();
+
+        machine.step()?;
+
+        // The stack only moves at the top, first everything above the common part was popped,
+        // then the new values were pushed.
+        let new_top = machine.stack.top;
+        let mut common = top.min(new_top);
+        while common > 0 && stack[common - 1] != machine.stack.stack[common - 1] {
+            common -= 1;
+        }
+
+        Ok(Self {
+            pc,
+            next_pc: machine.state.pc,
+            halted:  !machine.state.running,
+            regs:    diff_bytes(&regs, &machine.state.registers)
+                        .into_iter()
+                        .map(|(r, o, n)| (r as u8, o, n))
+                        .collect(),
+            mem:     diff_bytes(&mem, machine.bus.ram()),
+            heap:    diff_bytes(&heap, machine.state.heap.memory()),
+            pops:    stack[common..top].iter().rev().copied().collect(),
+            pushes:  machine.stack.stack[common..new_top].to_vec(),
+            interrupts: Some((interrupts, machine.state.interrupts)).filter(|(o, n)| o != n),
+            heap_state: Some((heap_state, machine.state.heap.state())).filter(|(o, n)| o != n),
+        })
+    }
+
+    // Puts the machine back in the state it had before this record.
+    pub fn undo(&self, machine: &mut Machine) {
+        for (r, old, _) in &self.regs {
+            machine.state.registers[*r as usize] = *old;
+        }
+        for (a, old, _) in &self.mem {
+            machine.bus.ram_mut()[*a as usize] = *old;
+        }
+        for (a, old, _) in &self.heap {
+            machine.state.heap.memory_mut()[*a as usize] = *old;
+        }
+        machine.stack.top -= self.pushes.len();
+        for v in self.pops.iter().rev() {
+            machine.stack.stack[machine.stack.top] = *v;
+            machine.stack.top += 1;
+        }
+        if let Some((old, _)) = self.interrupts {
+            machine.state.interrupts = old;
+        }
+        // The state came from this allocator, it can't be refused.
+        if let Some((old, _)) = &self.heap_state {
+            let _ = machine.state.heap.set_state(old);
+        }
+        machine.state.pc = self.pc;
+        machine.state.running = true;
+        machine.state.cycles = machine.state.cycles.saturating_sub(1);
+    }
+
+    // Applies the record to a machine in the state before it, without executing anything.
+    pub fn redo(&self, machine: &mut Machine) {
+        for (r, _, new) in &self.regs {
+            machine.state.registers[*r as usize] = *new;
+        }
+        for (a, _, new) in &self.mem {
+            machine.bus.ram_mut()[*a as usize] = *new;
+        }
+        for (a, _, new) in &self.heap {
+            machine.state.heap.memory_mut()[*a as usize] = *new;
+        }
+        machine.stack.top -= self.pops.len();
+        for v in &self.pushes {
+            machine.stack.stack[machine.stack.top] = *v;
+            machine.stack.top += 1;
+        }
+        if let Some((_, new)) = self.interrupts {
+            machine.state.interrupts = new;
+        }
+        if let Some((_, new)) = &self.heap_state {
+            let _ = machine.state.heap.set_state(new);
+        }
+        machine.state.pc = self.next_pc;
+        machine.state.running = !self.halted;
+        machine.state.cycles += 1;
+    }
+
+    // Record layout: pc, next_pc, flags, then the regs, mem, heap, pops and pushes
+    // each as a u16 count followed by the entries. If INTERRUPTS_FLAG is set, the old and new
+    // interrupt state follow as 3 bytes each. If HEAP_STATE_FLAG is set, the old and new allocator state
+    // follow, each as a u16 length and the bytes.
+    pub fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        let mut flags = 0;
+        if self.halted {
+            flags |= HALTED_FLAG;
+        }
+        if self.interrupts.is_some() {
+            flags |= INTERRUPTS_FLAG;
+        }
+        if self.heap_state.is_some() {
+            flags |= HEAP_STATE_FLAG;
+        }
+        w.write_all(&[self.pc, self.next_pc, flags])?;
+
+        w.write_all(&(self.regs.len() as u16).to_le_bytes())?;
+        for (r, o, n) in &self.regs {
+            w.write_all(&[*r, *o, *n])?;
+        }
+        for bytes in [&self.mem, &self.heap] {
+            w.write_all(&(bytes.len() as u16).to_le_bytes())?;
+            for (a, o, n) in bytes {
+                w.write_all(&a.to_le_bytes())?;
+                w.write_all(&[*o, *n])?;
+            }
+        }
+        for vals in [&self.pops, &self.pushes] {
+            w.write_all(&(vals.len() as u16).to_le_bytes())?;
+            w.write_all(vals)?;
+        }
+        if let Some((old, new)) = self.interrupts {
+            w.write_all(&old.to_bytes())?;
+            w.write_all(&new.to_bytes())?;
+        }
+        if let Some((old, new)) = &self.heap_state {
+            for state in [old, new] {
+                w.write_all(&(state.len() as u16).to_le_bytes())?;
+                w.write_all(state)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Reads a record, returns None if the input ended cleanly before it.
+    pub fn read_from(r: &mut impl Read) -> io::Result<Option<Self>> {
+        let mut head = [0; 3];
+        match r.read_exact(&mut head) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let mut rec = TraceRecord {
+            pc:      head[0],
+            next_pc: head[1],
+            halted:  head[2] & HALTED_FLAG != 0,
+            ..Default::default()
+        };
+
+        for _ in 0..read_u16(r)? {
+            let mut e = [0; 3];
+            r.read_exact(&mut e)?;
+            rec.regs.push((e[0], e[1], e[2]));
+        }
+        for bytes in [&mut rec.mem, &mut rec.heap] {
+            for _ in 0..read_u16(r)? {
+                let mut e = [0; 4];
+                r.read_exact(&mut e)?;
+                bytes.push((u16::from_le_bytes([e[0], e[1]]), e[2], e[3]));
+            }
+        }
+        for vals in [&mut rec.pops, &mut rec.pushes] {
+            let mut v = vec![0; read_u16(r)? as usize];
+            r.read_exact(&mut v)?;
+            *vals = v;
+        }
+        if head[2] & INTERRUPTS_FLAG != 0 {
+            let mut e = [0; 6];
+            r.read_exact(&mut e)?;
+            rec.interrupts = Some((
+                InterruptState::from_bytes([e[0], e[1], e[2]]),
+                InterruptState::from_bytes([e[3], e[4], e[5]]),
+            ));
+        }
+        if head[2] & HEAP_STATE_FLAG != 0 {
+            let old = read_vec(r)?;
+            rec.heap_state = Some((old, read_vec(r)?));
+        }
+        Ok(Some(rec))
+    }
+}
+
+fn read_u16(r: &mut impl Read) -> io::Result<u16> {
+    let mut b = [0; 2];
+    r.read_exact(&mut b)?;
+    Ok(u16::from_le_bytes(b))
+}
+
+// Reads a u16 length and that many bytes.
+fn read_vec(r: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut v = vec![0; read_u16(r)? as usize];
+    r.read_exact(&mut v)?;
+    Ok(v)
+}
+
+fn write_header(w: &mut impl Write) -> io::Result<()> {
+    w.write_all(TRACE_MAGIC)?;
+    w.write_all(&[TRACE_VERSION])
+}
+
+// The recorded history of a run and where in it the machine currently is.
+// 'position' is the number of instructions executed, records[position] is the next one.
+#[derive(Default)]
+pub struct TraceLog {
+    pub records:  Vec<TraceRecord>,
+    pub position: usize,
+}
+
+impl TraceLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Moves one instruction forward. Replays the log if we stepped back earlier,
+    // otherwise executes and records.
+    pub fn step(&mut self, machine: &mut Machine) -> Result<(), String> {
+        if let Some(rec) = self.records.get(self.position) {
+            rec.redo(machine);
+        } else {
+            self.records.push(TraceRecord::capture(machine)?);
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    // Moves one instruction back, returns false if we are at the start of the recording.
+    pub fn step_back(&mut self, machine: &mut Machine) -> bool {
+        if self.position == 0 {
+            return false;
+        }
+        self.position -= 1;
+        self.records[self.position].undo(machine);
+        true
+    }
+
+    // Jumps to the state after 'count' recorded instructions.
+    pub fn goto(&mut self, machine: &mut Machine, count: usize) -> Result<(), String> {
+        if count > self.records.len() {
+            return Err(format!("Error: Only {} instructions have been recorded.", self.records.len()));
+        }
+        while self.position > count {
+            self.step_back(machine);
+        }
+        while self.position < count {
+            self.step(machine)?;
+        }
+        Ok(())
+    }
+
+    // Forgets everything after the current position.
+    // Needed when the state is changed by hand, the recorded future is no longer valid.
+    pub fn truncate(&mut self) {
+        self.records.truncate(self.position);
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        write_header(&mut w)?;
+        for r in &self.records {
+            r.write_to(&mut w)?;
+        }
+        w.flush()
+    }
+
+    pub fn load(path: &str) -> io::Result<Self> {
+        let mut r = BufReader::new(File::open(path)?);
+        let mut head = [0; 5];
+        r.read_exact(&mut head)?;
+        if &head[..4] != TRACE_MAGIC || head[4] != TRACE_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Error: Not a trace file."));
+        }
+        let mut log = TraceLog::new();
+        while let Some(rec) = TraceRecord::read_from(&mut r)? {
+            log.records.push(rec);
+        }
+        Ok(log)
+    }
+}
+
+// Runs a program to the end or until it breaks one of the limits, streaming every record to 'path' as it is executed.
+pub fn execute_traced(mut machine: Machine, path: &str, limits: &Limits) -> io::Result<Outcome> {
+    let mut w = BufWriter::new(File::create(path)?);
+    write_header(&mut w)?;
+    let mut checker = LimitChecker::new(limits, &machine);
+    let mut reason = Termination::Halted;
+    while machine.state.running {
+        if let Some(t) = checker.before_step(&machine) {
+            reason = t;
+            break;
+        }
+        match TraceRecord::capture(&mut machine) {
+            Ok(rec) => rec.write_to(&mut w)?,
+            Err(e)  => {
+                reason = Termination::Fault(e);
+                break;
+            }
+        }
+        if let Some(t) = checker.after_step(&machine) {
+            reason = t;
+            break;
+        }
+    }
+    w.flush()?;
+    machine.finish();
+    Ok(Outcome::new(&machine, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_source;
+
+    const PROGRAM: &str = "
+_START:
+    ADDI r1 3
+    ADDI r2 40
+    ST r2 r1
+    CALL _DOUBLE
+    HLT
+_DOUBLE:
+    ADD r1 r1
+    RET
+";
+
+    fn machine() -> Machine {
+        let mut m = Machine::new(assemble_source(PROGRAM).mem);
+        m.state.verbose = false;
+        m
+    }
+
+    #[test]
+    fn test_capture_deltas() {
+        let mut m = machine();
+        let mut log = TraceLog::new();
+        while m.state.running {
+            log.step(&mut m).unwrap();
+        }
+        assert_eq!(log.records.len(), 7);
+        assert_eq!(log.records[0].regs, vec![(1, 0, 3)]);
+        assert_eq!(log.records[2].mem, vec![(40, 0, 3)]);
+        assert_eq!(log.records[3].pushes, vec![8]);
+        assert_eq!(log.records[5].pops, vec![8]);
+        assert!(log.records[6].halted);
+    }
+
+    #[test]
+    fn test_reverse_and_goto() {
+        let mut m = machine();
+        let mut log = TraceLog::new();
+        while m.state.running {
+            log.step(&mut m).unwrap();
+        }
+
+        // Back to just after the CALL, inside _DOUBLE.
+        log.goto(&mut m, 4).unwrap();
+        assert_eq!(m.state.pc, 10);
+        assert_eq!(m.stack.top, 1);
+        assert_eq!(m.state.registers[1], 3);
+        assert!(m.state.running);
+
+        // All the way back is the initial machine.
+        log.goto(&mut m, 0).unwrap();
+        let fresh = machine();
+        assert_eq!(m.state.registers, fresh.state.registers);
+        assert_eq!(m.bus.ram(), fresh.bus.ram());
+        assert_eq!(m.stack.top, 0);
+
+        log.goto(&mut m, 7).unwrap();
+        assert!(!m.state.running);
+        assert_eq!(m.state.registers[1], 6);
+        assert!(log.goto(&mut m, 8).is_err());
+    }
+
+    #[test]
+    fn test_interrupt_state_is_undone() {
+        let mut m = Machine::new(assemble_source("_START:\n ADDI r1 5\n IMASK r1\n EI\n HLT\n").mem);
+        m.state.verbose = false;
+        let mut log = TraceLog::new();
+        while m.state.running {
+            log.step(&mut m).unwrap();
+        }
+        assert!(m.state.interrupts.enabled);
+        assert!(log.records[0].interrupts.is_none());
+
+        log.goto(&mut m, 2).unwrap();
+        assert!(!m.state.interrupts.enabled);
+        assert_eq!(m.state.interrupts.mask, 5);
+        log.goto(&mut m, 0).unwrap();
+        assert_eq!(m.state.interrupts, InterruptState::default());
+    }
+
+    #[test]
+    fn test_heap_state_is_undone() {
+        let mut m = Machine::new(assemble_source("_START:\n ADDI r1 4\n ALC r2 r1\n FREE r2\n HLT\n").mem);
+        m.state.verbose = false;
+        let mut log = TraceLog::new();
+        while m.state.running {
+            log.step(&mut m).unwrap();
+        }
+        assert_eq!(m.state.heap.peak(), 8);
+        assert!(log.records[2].heap_state.is_none());
+
+        log.goto(&mut m, 1).unwrap();
+        assert_eq!(m.state.heap.peak(), 0);
+        log.goto(&mut m, 3).unwrap();
+        assert_eq!(m.state.heap.peak(), 8);
+
+        let mut buf = Vec::new();
+        log.records[1].write_to(&mut buf).unwrap();
+        assert_eq!(TraceRecord::read_from(&mut &buf[..]).unwrap().as_ref(), Some(&log.records[1]));
+    }
+
+    #[test]
+    fn test_file_roundtrip() {
+        let mut m = machine();
+        let mut log = TraceLog::new();
+        while m.state.running {
+            log.step(&mut m).unwrap();
+        }
+        let path = std::env::temp_dir().join("vm8bit_trace_test.trace");
+        let path = path.to_str().unwrap();
+        log.save(path).unwrap();
+
+        let loaded = TraceLog::load(path).unwrap();
+        assert_eq!(loaded.records, log.records);
+    }
+
+    #[test]
+    fn test_traced_run_is_limited() {
+        let mut m = Machine::new(assemble_source("_START:\n ADDI r2 4\n ADDI r1 1\n JMPZ r2 r0\n").mem);
+        m.state.verbose = false;
+        let path = std::env::temp_dir().join("vm8bit_trace_limit_test.trace");
+        let path = path.to_str().unwrap();
+        let limits = Limits { max_instructions: Some(20), ..Default::default() };
+        let o = execute_traced(m, path, &limits).unwrap();
+        assert_eq!(o.reason, Termination::InstructionLimit);
+        assert_eq!(TraceLog::load(path).unwrap().records.len(), 20);
+    }
+}
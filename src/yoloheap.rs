@@ -2,6 +2,9 @@
 
 // This is a greedy heap, meaning it will search for the first free block and split it if possible.
 // If it is not possible we just return that block. This is a simpler implementation of a heap, its good for my project.
+use std::fmt;
+
+use crate::allocator::{allocated_bytes, AllocStats};
 use crate::byte_utils::BytesConverter;
 use crate::yoloheap::constants::*;
 
@@ -13,6 +16,7 @@ pub mod constants {
     pub const MINIMUM_BLOCK_SIZE: usize = 4;                         // The minimum size a block can be.
     pub const MINIMUM_ALLOCATED_SIZE: usize = 2;                     // The minimum size that a user can ask the be allocated.
     pub const MAX_BLOCK_SIZE: u8 = u8::MAX - PB_B_ALLOCED;           // The maximum size a block can be.
+    pub const MAX_WIDE_BLOCK_SIZE: usize = u16::MAX as usize - PB_B_ALLOCED as usize; // The maximum size with 2 byte headers.
     pub const B_ALLOCED: u8 = 1;                                     // Value in header/footer if block is allocated.
     pub const PB_ALLOCED: u8 = 2;                                    // Value in header/footer if previous block is allocated.
     pub const PB_B_ALLOCED: u8 = 3;                                  // Value in header/footer if block and previous block is allocated.
@@ -41,7 +45,7 @@ impl _Header {
 
         // Important invariant for the header.
         assert!(block_size >= 4); 
-        assert!(block_size % 4 == 0);
+        assert!(block_size.is_multiple_of(4));
         assert!(block_alloc == 0 || block_alloc == 1);
         assert!(pblock_alloc == 0 || pblock_alloc == 1);
 
@@ -87,6 +91,16 @@ impl _Header {
         self.block_size as u8 + self.block_alloc + (self.pblock_alloc << 1)
     }
 
+    // The header as a number, the size with the allocation values in the two low bits.
+    // This is the byte for 1 byte headers and the little endian word for 2 byte headers.
+    pub fn _to_raw(&self) -> usize {
+        self.block_size + self.block_alloc as usize + ((self.pblock_alloc as usize) << 1)
+    }
+
+    pub fn _from_raw(raw: usize) -> Self {
+        _Header::_new(raw & !(BLOCK_ALLOC_VALS_MASK as usize), (raw & 1) as u8, ((raw & 2) >> 1) as u8)
+    }
+
     // Construct a header/footer from a byte.
     // Does not check invariants, since it calls _Header::_new().
     pub fn _from_byte(byte: &u8) -> Self {
@@ -99,27 +113,195 @@ impl _Header {
     }
 }
 
+// A block as seen from outside the heap, used when inspecting the heap.
+#[derive(Debug, PartialEq)]
+pub struct BlockInfo {
+    pub offset:    usize, // Index of the header.
+    pub size:      usize, // Size including header and footer.
+    pub allocated: bool,
+}
+
+// Something Heap::check found wrong with the block at 'offset'.
+#[derive(Debug, PartialEq)]
+pub struct Violation {
+    pub offset:  usize,
+    pub problem: Problem,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    FooterMismatch { header: usize, footer: usize },  // The raw tags.
+    WrongPrevAlloc { expected: bool },                 // The pblock_alloc bit doesn't match the block below.
+    NotCoalesced,                                      // Free, and so is the block below.
+    BadSize(usize),                                    // Not a multiple of 4, or smaller than a block can be.
+    PastEnd(usize),                                    // The block would end there, after the end of the heap.
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Block at {}: ", self.offset)?;
+        match self.problem {
+            Problem::FooterMismatch { header, footer } => write!(f, "header {:#x} and footer {:#x} don't match", header, footer),
+            Problem::WrongPrevAlloc { expected }       => write!(f, "pblock_alloc is {}, but the block below is {}",
+                                                              !expected as u8, if expected { "allocated" } else { "free" }),
+            Problem::NotCoalesced                      => write!(f, "free block next to a free block, they should have been coalesced"),
+            Problem::BadSize(size)                     => write!(f, "size {} is not a valid block size", size),
+            Problem::PastEnd(end)                      => write!(f, "block ends at {}, past the end of the heap", end),
+        }
+    }
+}
+
+// How many bytes a header/footer takes. 1 byte limits blocks, and so the heap, to MAX_BLOCK_SIZE bytes.
+// 2 bytes allow up to MAX_WIDE_BLOCK_SIZE.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HeaderWidth {
+    #[default]
+    Narrow,
+    Wide,
+}
+
+impl HeaderWidth {
+    pub fn bytes(&self) -> usize {
+        match self {
+            HeaderWidth::Narrow => 1,
+            HeaderWidth::Wide   => 2,
+        }
+    }
+
+    pub fn max_block_size(&self) -> usize {
+        match self {
+            HeaderWidth::Narrow => MAX_BLOCK_SIZE as usize,
+            HeaderWidth::Wide   => MAX_WIDE_BLOCK_SIZE,
+        }
+    }
+}
+
 #[derive(Clone)]
 #[allow(dead_code)]
 pub struct Heap {
-    pub heap: Vec<u8>,
-    pub size: usize,
+    pub heap:   Vec<u8>,
+    pub size:   usize,
+    pub header: HeaderWidth,
+    pub peak:   usize,  // Most bytes in allocated blocks at once, see stats.
 }
 
 impl Heap {
-    fn _print_heap(&self) {
-        for i in &self.heap {
-            println!("{i}");
+    pub fn header_size(&self) -> usize {
+        self.header.bytes()
+    }
+
+    pub fn header_footer_size(&self) -> usize {
+        2 * self.header_size()
+    }
+
+    // The smallest block that still has room for MINIMUM_ALLOCATED_SIZE bytes, rounded to a multiple of 4.
+    fn _min_block_size(&self) -> usize {
+        (MINIMUM_ALLOCATED_SIZE + self.header_footer_size() + 3) & !3
+    }
+
+    // The header/footer at 'i' as a number, see _Header::_to_raw.
+    fn _raw_tag(&self, i: usize) -> usize {
+        match self.header {
+            HeaderWidth::Narrow => self.heap[i] as usize,
+            HeaderWidth::Wide   => u16::from_le_bytes([self.heap[i], self.heap[i + 1]]) as usize,
+        }
+    }
+
+    fn _read_tag(&self, i: usize) -> _Header {
+        _Header::_from_raw(self._raw_tag(i))
+    }
+
+    fn _write_tag(&mut self, i: usize, h: &_Header) {
+        let raw = h._to_raw();
+        match self.header {
+            HeaderWidth::Narrow => self.heap[i] = raw as u8,
+            HeaderWidth::Wide   => self.heap[i..i + 2].copy_from_slice(&(raw as u16).to_le_bytes()),
         }
     }
+
+    // Counts the bytes in allocated blocks and remembers the most there ever were, called after the heap grew.
+    fn _update_peak(&mut self) {
+        self.peak = self.peak.max(allocated_bytes(&self.block_list()));
+    }
+
+    // How the heap is used right now, and the peak over its lifetime.
+    pub fn stats(&self) -> AllocStats {
+        AllocStats::from_blocks(&self.block_list(), self.size, self.peak)
+    }
     
 
-    // Creates a _new heap of size: size. 
+    // Walks the heap from the bottom and returns every block.
+    // Stops early if a header is corrupt (size 0 or going past the end), so it never loops forever.
+    pub fn block_list(&self) -> Vec<BlockInfo> {
+        let mut blocks = Vec::new();
+        let mut i = BOTTOM_OF_HEAP;
+        while i < self.size {
+            let raw = self._raw_tag(i);
+            let size = raw & !(BLOCK_ALLOC_VALS_MASK as usize);
+            if size == 0 || i + size > self.size {
+                break;
+            }
+            blocks.push(BlockInfo {
+                offset:    i,
+                size,
+                allocated: raw & 1 == B_ALLOCED as usize,
+            });
+            i += size;
+        }
+        blocks
+    }
+
+    // Walks every block and returns everything that is wrong with the heap, nothing if it is intact.
+    // Unlike the allocator itself it never panics on a corrupt header, the walk just stops where it can't go on.
+    pub fn check(&self) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        let mut i = BOTTOM_OF_HEAP;
+        let mut below_alloc = None; // None at the bottom of the heap, there is no block below.
+        while i < self.size {
+            let raw = self._raw_tag(i);
+            let size = raw & !(BLOCK_ALLOC_VALS_MASK as usize);
+            let alloc = raw & 1 == 1;
+            let palloc = raw & 2 == 2;
+
+            // The tag masks off the low bits, so a bad size mostly shows up as one too small.
+            if !size.is_multiple_of(4) || size < MINIMUM_BLOCK_SIZE {
+                violations.push(Violation { offset: i, problem: Problem::BadSize(size) });
+                break;
+            }
+            if i + size > self.size {
+                violations.push(Violation { offset: i, problem: Problem::PastEnd(i + size) });
+                break;
+            }
+
+            let footer = self._raw_tag(i + size - self.header_size());
+            if footer != raw {
+                violations.push(Violation { offset: i, problem: Problem::FooterMismatch { header: raw, footer } });
+            }
+            let expected = below_alloc.unwrap_or(false);
+            if palloc != expected {
+                violations.push(Violation { offset: i, problem: Problem::WrongPrevAlloc { expected } });
+            }
+            if !alloc && below_alloc == Some(false) {
+                violations.push(Violation { offset: i, problem: Problem::NotCoalesced });
+            }
+            below_alloc = Some(alloc);
+            i += size;
+        }
+        violations
+    }
+
+    // Creates a _new heap of size: size, with 1 byte headers.
     // Initializes a header and footer so the heap is one large free block.
     // Asserts that size i at least 4.
     
     #[allow(dead_code)] 
     pub fn new_heap(size: usize) -> Self {
+        Heap::with_header(size, HeaderWidth::Narrow)
+    }
+
+    // Same as new_heap, but the headers are 'header' wide.
+    // Asserts that the heap fits in a single block, the initial free block has to be described by one header.
+    pub fn with_header(size: usize, header: HeaderWidth) -> Self {
         assert!(size >= 4); // Size invariant.
         
         // Cocky move, but if the size given is not a multiple of 4, we round up to closest multiple of 4.
@@ -127,19 +309,20 @@ impl Heap {
         let mut _size = size;
 
         // Hacker function that changes size to closest (roof) multiple of 4.
-        if size % 4 == 0 { } else { _size = (size + 3) & !3 }
+        if size.is_multiple_of(4) { } else { _size = (size + 3) & !3 }
 
-        let init_h = _Header::_new(_size, 0, 0);
-        let init_f = _Footer::_new(_size, 0, 0);
-        let mut h = vec![0; _size];
-        
-        h[BOTTOM_OF_HEAP] = init_h._to_byte();
-        h[_size-1]        = init_f._to_byte();
+        assert!(_size <= header.max_block_size(), "Error: A heap of {} bytes needs wider headers.", _size);
 
-        Self {
-            heap: h,
+        let mut heap = Self {
+            heap: vec![0; _size],
             size: _size,
-        }  
+            header,
+            peak: 0,
+        };
+        let init_h = _Header::_new(_size, 0, 0);
+        heap._write_tag(BOTTOM_OF_HEAP, &init_h);
+        heap._write_tag(_size - heap.header_size(), &init_h);
+        heap
     }
 
     // Writes a header and footer from index.
@@ -147,16 +330,15 @@ impl Heap {
     // -- i: Index to write the _new header.
     fn _write_header_footer(&mut self, h: &_Header, i: usize) {
         // If the block goes out of bounds, then something is wrong so we panic.
-        assert!(i + h.block_size - FOOTER_SIZE < self.size);
+        assert!(i + h.block_size - self.header_size() < self.size);
 
         // We know that either it is the first block or a block above.
         // This panics if the first block is corrupt.
-        println!("I: {i}");
         assert!(i == BOTTOM_OF_HEAP || i >= MINIMUM_BLOCK_SIZE);
 
         // Write the header to i and footer to i + size - footer_size.
-        self.heap[i] = h._to_byte();
-        self.heap[i + h.block_size - FOOTER_SIZE] = h._to_byte();
+        self._write_tag(i, h);
+        self._write_tag(i + h.block_size - self.header_size(), h);
     }
     
     // Returns the index the free blocks header, of at least size 'size'.
@@ -174,11 +356,11 @@ impl Heap {
         while i < self.size {
 
             // Gets the current header from bytes.
-            let curr_header = _Header::_from_byte(&self.heap[i]);
+            let curr_header = self._read_tag(i);
 
             // We now have to check if a multiple of 4 is possible.
             // This rounds the smallest possible size to the nearest multiple of 4.
-            let minimum_size = (size + HEADER_FOOTER_SIZE + 3) & !3;
+            let minimum_size = (size + self.header_footer_size() + 3) & !3;
 
             // We always assume that current block is of size multiple of 4.
             // If the size is smaller or equal the current block size and it is not allocated we good.
@@ -188,7 +370,7 @@ impl Heap {
                 // We check if the size if not too small.
                 // We also check if the rest block is a multiple of 4.
                 // If we can then we do it.
-                if curr_header.block_size - minimum_size >= MINIMUM_BLOCK_SIZE && (curr_header.block_size - minimum_size) % 4 == 0 {
+                if curr_header.block_size - minimum_size >= self._min_block_size() && (curr_header.block_size - minimum_size).is_multiple_of(4) {
 
                     // The _new block size is just size + header + footer.
                     // The _new alloc is just if previous is alloced.
@@ -203,7 +385,6 @@ impl Heap {
 
                     // we insert the _new header and footer to create our _new block.
                     // And we then update the footer for the old block, as well as insert af _new header above the _new block.
-                    //self._print_heap();
                     self._write_header_footer(&new_header, i);
                     self._write_header_footer(&rest_header, i + new_size);
 
@@ -235,14 +416,14 @@ impl Heap {
             Some((i, bsize)) => {
 
                 // Set the current block as allocated
-                let mut curr_h = _Header::_from_byte(&self.heap[i]);
+                let mut curr_h = self._read_tag(i);
                 curr_h.block_alloc = 1;
 
                 // Check if there is a previous block, then set current block to pblock_alloc.
                 if i > BOTTOM_OF_HEAP {
                     // THOUGHT: Since we use immidiate coalecing, if it is not the bottom block, prev would always be allocated?
                     // Now we must toggle this to prev alloc, if previous is alloced.
-                    let prev_f = _Footer::_from_byte(&self.heap[i-FOOTER_SIZE]);  
+                    let prev_f = self._read_tag(i-self.header_size());  
                     if prev_f.block_alloc == 1 {
                         // Then set curr_h prev to alloc.
                         curr_h.pblock_alloc = 1;
@@ -255,7 +436,7 @@ impl Heap {
                 let next_block_start = i + bsize;
 
                 if next_block_start < self.size {
-                    let mut next_h = _Header::_from_byte(&self.heap[next_block_start]);
+                    let mut next_h = self._read_tag(next_block_start);
                     
                     // We know that it was not marked to begin with, otherwise it is corrupt.
                     assert_eq!(next_h.pblock_alloc, 0);
@@ -265,7 +446,8 @@ impl Heap {
                 }
                 
                 // Return the start of allocated data (right after the header)
-                Some(i + HEADER_SIZE)
+                self._update_peak();
+                Some(i + self.header_size())
             }
             // Find free block returned None, so we do the same since there is no free block to match our demand.
             None => None,
@@ -282,17 +464,18 @@ impl Heap {
         // TODO: Invariants.
 
         // The _new size is at least the size of the current block.
-        let curr_h = _Header::_from_byte(&self.heap[ptr-HEADER_SIZE]);
+        let curr_h = self._read_tag(ptr-self.header_size());
         let mut final_size = curr_h.block_size;
-        let mut final_prev_alloc = 0;
-        let mut final_header_index = ptr - HEADER_SIZE;
+        // Unless it merges with the block below, the block below stays what it is.
+        let mut final_prev_alloc = curr_h.pblock_alloc;
+        let mut final_header_index = ptr - self.header_size();
 
         // Initially we assume neither way is possible.
         let mut up_flag   = 0;
         let mut down_flag = 0;
         
         // If it is not the bottom most block, we know that a below block exists.
-        if ptr != BOTTOM_OF_HEAP + HEADER_SIZE { down_flag = 1 }
+        if ptr != BOTTOM_OF_HEAP + self.header_size() { down_flag = 1 }
 
         // To check if up is possible we need the header that ptr is pointing to.
         if curr_h.block_size + ptr < self.size { up_flag = 1 }
@@ -302,7 +485,7 @@ impl Heap {
         // We set the final previous alloc to the same as the below block.
         // And the header index (for _write_header_footer) is the same as the header index for below block.
         if down_flag == 1 {
-            let below_f = _Footer::_from_byte(&self.heap[ptr-HEADER_FOOTER_SIZE]);
+            let below_f = self._read_tag(ptr-self.header_footer_size());
             if below_f.block_alloc == 0 {
                 final_size += below_f.block_size;
                 final_prev_alloc = below_f.pblock_alloc;
@@ -312,20 +495,17 @@ impl Heap {
 
         // IMPORTANT: We DO NOT update the pblock_alloc value of above block before overwriting.
         // Now we check the above block.
-        println!("CALSCS!!: {}", &self.heap[ptr+curr_h.block_size-HEADER_SIZE]);
-        self._print_heap();
         if up_flag == 1 {
-            let above_h = _Header::_from_byte(&self.heap[ptr+curr_h.block_size-HEADER_SIZE]);
+            let above_h = self._read_tag(ptr+curr_h.block_size-self.header_size());
             if above_h.block_alloc == 0 {
                 final_size += above_h.block_size;
             }   
         }
-        
-        self._print_heap();
+
         // Write to the above allocated block, if it exists, that prev is now free.
         // If it was not the final block we update.
         if final_header_index + final_size < self.size {
-            let mut above_above_h = _Header::_from_byte(&self.heap[final_header_index + final_size]);
+            let mut above_above_h = self._read_tag(final_header_index + final_size);
             above_above_h.pblock_alloc = 0;
             self._write_header_footer(&above_above_h, final_header_index + final_size);
         }
@@ -335,53 +515,64 @@ impl Heap {
         let final_header = _Header::_new(final_size, DEFAULT_ALLOC, final_prev_alloc);
         self._write_header_footer(&final_header, final_header_index);
 
-        // Does not return anything. PRAY!
+        // Does not return anything, Heap::check can tell whether it went right.
 
     }
 
     
-    // TODO: REMove assets
-    #[allow(dead_code)]
-    pub fn free(&mut self, ptr: usize) -> Result<(), &'static str> {
+    // Returns the header of the allocated block that 'ptr' points to, checking that it really is one.
+    fn _allocated_header(&self, ptr: usize) -> Result<_Header, &'static str> {
 
         if ptr >= self.size {
             return Err("Error: Pointer is greater then the size of the heap.");
         }
 
-        if ptr < HEADER_SIZE {
+        if ptr < self.header_size() {
             return Err("Error trying to free pointer which is at bottom of heap <0>, this is not allowed");
         }
 
         // Check that the pointer is valid.
-        // Has to be a multiple of 4 + HEADER_FOOTER_SIZE
-        if ptr % 4 != HEADER_SIZE {
+        // Has to be a multiple of 4 + the header size.
+        if ptr % 4 != self.header_size() {
             return Err("Error: Invalid pointer given.");
         }
 
-        // Gets the header of the block.
-        let mut header = _Header::_from_byte(&self.heap[ptr-HEADER_SIZE]);
-        let bsize = header.block_size;
-        let footer = _Footer::_from_byte(&self.heap[ptr+bsize-HEADER_FOOTER_SIZE]);
-        
-        // The block must be allocated.
-        if header.block_alloc != B_ALLOCED {
-            return Err("Error: Trying to free a block that is not allocated.");
+        // Gets the header of the block. Checked as raw tags, ptr may well point into the middle of a block.
+        let raw = self._raw_tag(ptr-self.header_size());
+        let bsize = raw & !(BLOCK_ALLOC_VALS_MASK as usize);
+        if bsize < MINIMUM_BLOCK_SIZE {
+            return Err("Error: Invalid pointer given.");
         }
 
         // Check if size is within bounds.
-        if ptr-HEADER_SIZE+bsize > self.size {
+        if ptr-self.header_size()+bsize > self.size {
             return Err("Error: Block goes out of bounds, possible wrong header.");
         }
 
+        let footer = self._raw_tag(ptr+bsize-self.header_footer_size());
+        
+        // The block must be allocated.
+        if raw & 1 != B_ALLOCED as usize {
+            return Err("Error: Block is not allocated.");
+        }
+
         // check if footer is identical.
-        if header != footer {
+        if raw != footer {
             return Err("Error: Header and footer does not match.");
         }
+        Ok(_Header::_from_raw(raw))
+    }
+
+    // TODO: REMove assets
+    #[allow(dead_code)]
+    pub fn free(&mut self, ptr: usize) -> Result<(), &'static str> {
+
+        let mut header = self._allocated_header(ptr)?;
 
         // Set block to not allocated. 
         // Write it back to the heap.
         header.block_alloc = 0;
-        self._write_header_footer(&header, ptr-HEADER_SIZE);
+        self._write_header_footer(&header, ptr-self.header_size());
 
 
         // Coallesse the heap, hehe hope it works!
@@ -389,40 +580,156 @@ impl Heap {
         Ok(())
     }
 
+    // Resizes the allocated block 'ptr' points to so it holds at least 'new_size' bytes, keeping its contents.
+    // Returns the pointer to the block, which only moves if it has to, or None if there is no room.
+    // The old block is left as it was in that case. Sizes below the minimum are rounded up to it, like allocate's callers do.
+    //  -- Shrinking splits the tail off as a free block, if it is large enough to be one.
+    //  -- Growing takes from the block above if it is free and large enough, splitting off what is left.
+    //  -- Otherwise a new block is allocated, the contents copied over and the old block freed.
+    pub fn realloc(&mut self, ptr: usize, new_size: usize) -> Result<Option<usize>, &'static str> {
+        let new_size = new_size.max(MINIMUM_ALLOCATED_SIZE);
+        let header = self._allocated_header(ptr)?;
+        let i = ptr - self.header_size();
+        let needed = (new_size + self.header_footer_size() + 3) & !3;
+
+        // Shrink, or the block is already large enough.
+        if needed <= header.block_size {
+            if header.block_size - needed >= self._min_block_size() {
+                // The tail is written as an allocated block and then freed, so it coalesces with the block above.
+                let tail = _Header::_new(header.block_size - needed, B_ALLOCED, 1);
+                self._write_header_footer(&_Header::_new(needed, B_ALLOCED, header.pblock_alloc), i);
+                self._write_header_footer(&tail, i + needed);
+                self.free(i + needed + self.header_size())?;
+            }
+            return Ok(Some(ptr));
+        }
+
+        // Grow in place, into the free block above.
+        let above = i + header.block_size;
+        if above < self.size {
+            let above_h = self._read_tag(above);
+            let combined = header.block_size + above_h.block_size;
+            if above_h.block_alloc == 0 && combined >= needed {
+                if combined - needed >= self._min_block_size() {
+                    // The rest stays free, the block above it already knows its previous block is free.
+                    self._write_header_footer(&_Header::_new(needed, B_ALLOCED, header.pblock_alloc), i);
+                    self._write_header_footer(&_Header::_new(combined - needed, DEFAULT_ALLOC, 1), i + needed);
+                } else {
+                    self._write_header_footer(&_Header::_new(combined, B_ALLOCED, header.pblock_alloc), i);
+                    if i + combined < self.size {
+                        let mut next_h = self._read_tag(i + combined);
+                        next_h.pblock_alloc = 1;
+                        self._write_header_footer(&next_h, i + combined);
+                    }
+                }
+                self._update_peak();
+                return Ok(Some(ptr));
+            }
+        }
+
+        // Move it.
+        let new_ptr = match self.allocate(new_size) {
+            Some(p) => p,
+            None    => return Ok(None),
+        };
+        let n = (header.block_size - self.header_footer_size()).min(new_size);
+        self.heap.copy_within(ptr..ptr + n, new_ptr);
+        self.free(ptr)?;
+        Ok(Some(new_ptr))
+    }
+
     // HELPER
-    // Takes a ptr to the first element in a block and returns the available space for data (blocksize - HEADER_FOOTER_SIZE),
-    // This makes no assumptions about the state of the block.
-    fn _check_bsize(&self, ptr: &usize) -> usize {
+    // Takes a ptr to the first element in a block and returns the available space for data (blocksize - header and footer).
+    // Fails if ptr does not point to an allocated block.
+    fn _check_bsize(&self, ptr: &usize) -> Result<usize, &'static str> {
 
-        let h = _Header::_from_byte(&self.heap[ptr-HEADER_SIZE]);
-        h.block_size - HEADER_FOOTER_SIZE
+        let h = self._allocated_header(*ptr)?;
+        Ok(h.block_size - self.header_footer_size())
 
     }
 
     // Writes 'n' bytes from src to self starting at ptr + offset.
-    
+    // All of ptr + offset .. ptr + offset + n has to be inside the payload of the block, and n can't be 0.
     #[allow(dead_code)]
     pub fn write_bytes(&mut self, ptr: &usize, src: &impl BytesConverter, n: usize, offset: usize) -> Result<(), &'static str> {
         // Gets the number of spaces available for writing.
-        let bbytes = self._check_bsize(ptr);
+        let bbytes = self._check_bsize(ptr)?;
 
-        // If we try to write too many bytes or too little, return err.
-        if bbytes < n {
-            return Err("Error: Trying to write more bytes that the size of the block.");
-        }
+        // If we try to write too many bytes or too little, return err. The offset can be anything, so it may not even add up.
+        let end = offset.checked_add(n).filter(|end| *end <= bbytes)
+            .ok_or("Error: Trying to write more bytes that the size of the block.")?;
 
         if n == 0 {
             return Err("Error: Trying to write 0 bytes.");
         }
 
-        // We know block is allocated, and that we have enough space to write, so we write.
-        // We copy the src into out heap and return.
-        unsafe {
-            std::ptr::copy_nonoverlapping(src.to_bytes().as_ptr(), &mut self.heap[*ptr+offset] as *mut u8, n);
+        let bytes = src.to_bytes();
+        if bytes.len() < n {
+            return Err("Error: Trying to write more bytes than src has.");
         }
 
+        // We know block is allocated, and that we have enough space to write, so we write.
+        self.heap[*ptr + offset..*ptr + end].copy_from_slice(&bytes[..n]);
         Ok(())
     }
+
+    // Reads 'n' bytes starting at ptr + offset, checked the same way as write_bytes.
+    #[allow(dead_code)]
+    pub fn read_bytes(&self, ptr: &usize, n: usize, offset: usize) -> Result<Vec<u8>, &'static str> {
+        let bbytes = self._check_bsize(ptr)?;
+
+        let end = offset.checked_add(n).filter(|end| *end <= bbytes)
+            .ok_or("Error: Trying to read past the end of the block.")?;
+
+        if n == 0 {
+            return Err("Error: Trying to read 0 bytes.");
+        }
+
+        Ok(self.heap[*ptr + offset..*ptr + end].to_vec())
+    }
+
+    #[allow(dead_code)]
+    pub fn write_u8(&mut self, ptr: &usize, offset: usize, val: u8) -> Result<(), &'static str> {
+        self.write_bytes(ptr, &val, 1, offset)
+    }
+
+    #[allow(dead_code)]
+    pub fn read_u8(&self, ptr: &usize, offset: usize) -> Result<u8, &'static str> {
+        Ok(self.read_bytes(ptr, 1, offset)?[0])
+    }
+
+    // u16s are little endian, like the wide headers.
+    #[allow(dead_code)]
+    pub fn write_u16(&mut self, ptr: &usize, offset: usize, val: u16) -> Result<(), &'static str> {
+        self.write_bytes(ptr, &val, 2, offset)
+    }
+
+    #[allow(dead_code)]
+    pub fn read_u16(&self, ptr: &usize, offset: usize) -> Result<u16, &'static str> {
+        let b = self.read_bytes(ptr, 2, offset)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    // Strings are stored with their length in the first byte, so they can be at most 255 bytes.
+    #[allow(dead_code)]
+    pub fn write_str(&mut self, ptr: &usize, offset: usize, s: &str) -> Result<(), &'static str> {
+        if s.len() > u8::MAX as usize {
+            return Err("Error: String is longer than 255 bytes.");
+        }
+        let mut bytes = vec![s.len() as u8];
+        bytes.extend_from_slice(s.as_bytes());
+        self.write_bytes(ptr, &bytes, bytes.len(), offset)
+    }
+
+    #[allow(dead_code)]
+    pub fn read_str(&self, ptr: &usize, offset: usize) -> Result<String, &'static str> {
+        let len = self.read_u8(ptr, offset)? as usize;
+        if len == 0 {
+            return Ok(String::new());
+        }
+        let bytes = self.read_bytes(ptr, len, offset + 1)?;
+        String::from_utf8(bytes).map_err(|_| "Error: String is not valid UTF-8.")
+    }
 }
 
 #[cfg(test)]
@@ -472,6 +779,31 @@ mod tests {
         let _ = Heap::new_heap(3);
     }
 
+    #[test]
+    #[should_panic]
+    fn test_narrow_heap_size_limit() {
+        let _ = Heap::new_heap(256);
+    }
+
+    #[test]
+    fn test_wide_heap() {
+        let mut heap = Heap::with_header(1024, HeaderWidth::Wide);
+        assert_eq!(&heap.heap[1022..], &[0x00, 0x04]); // Footer of the one free block, 1024 little endian.
+
+        let ptr1 = heap.allocate(600).unwrap();
+        assert_eq!(ptr1, 2);
+        assert_eq!(&heap.heap[..2], &[0x5D, 0x02]); // 604 + 1 for allocation.
+        let ptr2 = heap.allocate(300).unwrap();
+        assert_eq!(ptr2, 606);
+        assert!(heap.allocate(200).is_none());
+
+        assert!(heap.write_bytes(&ptr2, &vec![7; 300], 300, 0).is_ok());
+
+        heap.free(ptr1).unwrap();
+        heap.free(ptr2).unwrap();
+        assert_eq!(heap.block_list(), vec![BlockInfo { offset: 0, size: 1024, allocated: false }]);
+    }
+
     #[test]
     fn test_right_init_header_footer() {
         let heap = Heap::new_heap(32);
@@ -615,7 +947,6 @@ mod tests {
         let mut heap = Heap::new_heap(heap_size);
         let ptr = heap.allocate(6).unwrap();
         let wres = heap.write_bytes(&ptr, &data, data_size, 0);
-        heap._print_heap();
         assert!(wres.is_ok());
         assert_eq!(heap.heap[ptr], data[0]);
         assert_eq!(heap.heap[ptr+1], data[1]);
@@ -683,7 +1014,6 @@ mod tests {
         let ptrr = ptr;
 
         heap.free(ptr).expect("Failed to free block");
-        heap._print_heap();
         // After freeing, check if the block is correctly marked as free
         let header = _Header::_from_byte(&heap.heap[ptrr - HEADER_SIZE]);
         assert_eq!(header.block_alloc, 0, "Block should be marked as free");
@@ -751,4 +1081,173 @@ mod tests {
         assert_eq!(header.block_size, 64, "All blocks should be coalesced into a single large free block");
         assert_eq!(header.block_alloc, 0, "Coalesced block should be marked as free");
     }
-}
\ No newline at end of file
+
+    fn layout(heap: &Heap) -> Vec<(usize, usize, bool)> {
+        heap.block_list().iter().map(|b| (b.offset, b.size, b.allocated)).collect()
+    }
+
+    #[test]
+    fn test_realloc_in_place() {
+        let mut heap = Heap::new_heap(64);
+        let _ptr1 = heap.allocate(4).unwrap();
+        let ptr2 = heap.allocate(4).unwrap();
+
+        // Grows into the free block above and splits off the rest.
+        assert_eq!(heap.realloc(ptr2, 20), Ok(Some(ptr2)));
+        assert_eq!(layout(&heap), vec![(0, 8, true), (8, 24, true), (32, 32, false)]);
+
+        // The tail coalesces with the free block above.
+        assert_eq!(heap.realloc(ptr2, 4), Ok(Some(ptr2)));
+        assert_eq!(layout(&heap), vec![(0, 8, true), (8, 8, true), (16, 48, false)]);
+    }
+
+    #[test]
+    fn test_realloc_takes_whole_block() {
+        let mut heap = Heap::new_heap(32);
+        let ptr1 = heap.allocate(4).unwrap();
+        let ptr2 = heap.allocate(4).unwrap();
+        let ptr3 = heap.allocate(4).unwrap();
+        heap.free(ptr2).unwrap();
+
+        // 12 bytes need all 16 of both blocks, the block above has to learn that its previous block is allocated.
+        assert_eq!(heap.realloc(ptr1, 12), Ok(Some(ptr1)));
+        assert_eq!(layout(&heap), vec![(0, 16, true), (16, 8, true), (24, 8, false)]);
+        assert_eq!(heap._read_tag(16).pblock_alloc, 1);
+
+        heap.free(ptr3).unwrap();
+        heap.free(ptr1).unwrap();
+        assert_eq!(layout(&heap), vec![(0, 32, false)]);
+    }
+
+    #[test]
+    fn test_realloc_moves() {
+        let mut heap = Heap::new_heap(64);
+        let ptr1 = heap.allocate(4).unwrap();
+        let _ptr2 = heap.allocate(4).unwrap();
+        heap.heap[ptr1..ptr1 + 6].copy_from_slice(&[1, 2, 3, 4, 5, 6]);
+
+        let moved = heap.realloc(ptr1, 10).unwrap().unwrap();
+        assert_eq!(moved, 17);
+        assert_eq!(&heap.heap[moved..moved + 6], &[1, 2, 3, 4, 5, 6]);
+        assert_eq!(layout(&heap), vec![(0, 8, false), (8, 8, true), (16, 12, true), (28, 36, false)]);
+
+        // No room anywhere, nothing changes.
+        assert_eq!(heap.realloc(moved, 100), Ok(None));
+        assert_eq!(layout(&heap)[2], (16, 12, true));
+        assert!(heap.realloc(3, 8).is_err());
+        assert!(heap.realloc(ptr1, 8).is_err());
+    }
+
+    #[test]
+    fn test_realloc_small_size() {
+        let mut heap = Heap::new_heap(32);
+        let ptr = heap.allocate(12).unwrap();
+        assert_eq!(heap.realloc(ptr, 1), Ok(Some(ptr)));
+        assert_eq!(layout(&heap), vec![(0, 4, true), (4, 28, false)]);
+        assert_eq!(heap.realloc(ptr, 0), Ok(Some(ptr)));
+    }
+
+    // Two allocated blocks of 8 and a free block of 16.
+    fn two_blocks() -> Heap {
+        let mut heap = Heap::new_heap(32);
+        heap.allocate(4).unwrap();
+        heap.allocate(4).unwrap();
+        assert_eq!(heap.check(), vec![]);
+        heap
+    }
+
+    fn problems(heap: &Heap) -> Vec<(usize, Problem)> {
+        heap.check().into_iter().map(|v| (v.offset, v.problem)).collect()
+    }
+
+    #[test]
+    fn test_check() {
+        let mut heap = two_blocks();
+        heap.heap[7] = 0;
+        assert_eq!(problems(&heap), vec![(0, Problem::FooterMismatch { header: 9, footer: 0 })]);
+        assert_eq!(heap.check()[0].to_string(), "Block at 0: header 0x9 and footer 0x0 don't match");
+
+        let mut heap = two_blocks();
+        heap.heap[8] = 9;
+        heap.heap[15] = 9;
+        assert_eq!(problems(&heap), vec![(8, Problem::WrongPrevAlloc { expected: true })]);
+
+        // The middle block freed without coalescing.
+        let mut heap = two_blocks();
+        heap.heap[8] = 10;
+        heap.heap[15] = 10;
+        heap.heap[16] = 16;
+        heap.heap[31] = 16;
+        assert_eq!(problems(&heap), vec![(16, Problem::NotCoalesced)]);
+
+        let mut heap = two_blocks();
+        heap.heap[16] = 34;
+        assert_eq!(problems(&heap), vec![(16, Problem::PastEnd(48))]);
+
+        let mut heap = two_blocks();
+        heap.heap[16] = 2;
+        assert_eq!(problems(&heap), vec![(16, Problem::BadSize(0))]);
+    }
+
+    #[test]
+    fn test_check_after_realloc_and_free() {
+        let mut heap = Heap::with_header(128, HeaderWidth::Wide);
+        let ptrs: Vec<usize> = (0..5).map(|_| heap.allocate(6).unwrap()).collect();
+        heap.free(ptrs[1]).unwrap();
+        heap.free(ptrs[3]).unwrap();
+        assert_eq!(heap.check(), vec![]);
+        heap.realloc(ptrs[0], 14).unwrap();
+        heap.realloc(ptrs[2], 30).unwrap();
+        heap.free(ptrs[4]).unwrap();
+        assert_eq!(heap.check(), vec![]);
+    }
+
+    #[test]
+    fn test_write_checks_offset() {
+        let mut heap = Heap::new_heap(32);
+        let ptr = heap.allocate(6).unwrap();
+        assert!(heap.write_bytes(&ptr, &vec![1, 2, 3], 3, 4).is_err());
+        assert!(heap.write_bytes(&ptr, &vec![1, 2], 3, 0).is_err());
+        assert!(heap.write_bytes(&(ptr + 4), &vec![1], 1, 0).is_err());
+
+        assert!(heap.write_bytes(&ptr, &vec![1], 1, usize::MAX).is_err());
+
+        heap.free(ptr).unwrap();
+        assert!(heap.write_bytes(&ptr, &vec![1], 1, 0).is_err());
+    }
+
+    #[test]
+    fn test_read_bytes() {
+        let mut heap = Heap::new_heap(32);
+        let ptr = heap.allocate(6).unwrap();
+        heap.write_bytes(&ptr, &vec![1, 2, 3, 4, 5, 6], 6, 0).unwrap();
+        assert_eq!(heap.read_bytes(&ptr, 3, 2), Ok(vec![3, 4, 5]));
+        assert!(heap.read_bytes(&ptr, 0, 6).is_err());
+        assert!(heap.read_bytes(&ptr, 3, 4).is_err());
+        assert!(heap.read_bytes(&(ptr + 1), 1, 0).is_err());
+        assert!(heap.read_bytes(&ptr, 2, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn test_typed_accessors() {
+        let mut heap = Heap::with_header(64, HeaderWidth::Wide);
+        let ptr = heap.allocate(12).unwrap();
+        heap.write_u8(&ptr, 0, 200).unwrap();
+        heap.write_u16(&ptr, 1, 0x1234).unwrap();
+        assert_eq!(heap.heap[ptr + 1..ptr + 3], [0x34, 0x12]);
+        heap.write_str(&ptr, 3, "heap").unwrap();
+
+        assert_eq!(heap.read_u8(&ptr, 0), Ok(200));
+        assert_eq!(heap.read_u16(&ptr, 1), Ok(0x1234));
+        assert_eq!(heap.read_str(&ptr, 3), Ok(String::from("heap")));
+
+        // 1 length byte and 9 bytes don't fit in the 9 bytes after offset 3.
+        assert!(heap.write_str(&ptr, 3, "too long!").is_err());
+        assert!(heap.write_u16(&ptr, 11, 1).is_err());
+        heap.write_u8(&ptr, 3, 20).unwrap();
+        assert!(heap.read_str(&ptr, 3).is_err());
+
+        heap.write_str(&ptr, 11, "").unwrap();
+        assert_eq!(heap.read_str(&ptr, 11), Ok(String::new()));
+    }
+}